@@ -3,18 +3,35 @@
 
 use coop_protocol::*;
 
+use std::cell::{Cell, RefCell};
 use std::env;
-use tokio_unix_ipc::{Receiver, Sender};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio_unix_ipc::Receiver;
+
+pub mod result;
+
+use result::*;
 
 /// Use this to generate a window with the co window manager and interact with it.
 pub struct Window {
-    width: f32,
-    height: f32,
-    _title: String,
-    sender: Sender<ClientMessage>,
-    receiver: Receiver<ClientMessage>,
+    width: Cell<f32>,
+    height: Cell<f32>,
+    title: RefCell<String>,
+    sender: Box<dyn MessageSender>,
+    receiver: mpsc::Receiver<ClientMessage>,
     key: String,
     is_async: bool,
+    connected: Cell<bool>,
+    capabilities: Vec<Capability>,
+    decoration_mode: DecorationMode,
+    shared_memory: RefCell<Option<SharedMemory>>,
+    clipboard: RefCell<Option<String>>,
+    windows: RefCell<Vec<String>>,
+    window_count: Cell<u32>,
+    pixel_format: Cell<PixelFormat>,
+    pixel_formats: Vec<PixelFormat>,
     runtime: tokio::runtime::Runtime,
 }
 
@@ -26,105 +43,478 @@ impl Default for Window {
 
 impl Window {
     /// Creates a new window with default settings.
+    ///
+    /// Panics if no connection to the server can be established, use `try_new` to handle the error.
     pub fn new(width: f32, height: f32, title: String, is_async: bool) -> Self {
-        let runtime = tokio::runtime::Runtime::new().unwrap();
-        let creation_result = runtime.block_on(async {
-            let server_key = env::var(coop_protocol::SERVER_KEY).expect("Cannot read server key");
-            let client_key = env::var(coop_protocol::CLIENT_KEY).expect("Cannot read client key");
-            let client_path =
-                env::var(coop_protocol::CLIENT_PATH).expect("Cannot read client path");
-
-            let receiver = Receiver::<ClientMessage>::connect(server_key.clone())
-                .await
-                .unwrap();
-            let message = receiver.recv().await.unwrap();
+        Self::try_new(width, height, title, is_async)
+            .expect("Cannot establish connection to backend.")
+    }
+
+    /// Creates a new window and connects it to the server.
+    ///
+    /// Connects over the socket of `SERVER_KEY` if the app is started by a `coop_server`, otherwise over the
+    /// address of `SERVER_ADDRESS`. Returns an error if neither is set or the connection cannot be established.
+    pub fn try_new(width: f32, height: f32, title: String, is_async: bool) -> ClientResult<Self> {
+        Self::create(width, height, title, is_async, DecorationMode::Server, None)
+    }
+
+    /// Creates a new window like `try_new` that prefers the given decoration mode, e.g. `DecorationMode::Client`
+    /// for an app with its own header bar.
+    ///
+    /// The server may not support the mode, check the negotiated mode with `decoration_mode`.
+    pub fn try_new_with_decoration_mode(
+        width: f32,
+        height: f32,
+        title: String,
+        is_async: bool,
+        decoration_mode: DecorationMode,
+    ) -> ClientResult<Self> {
+        Self::create(width, height, title, is_async, decoration_mode, None)
+    }
+
+    /// Creates a new window that attaches to a server which listens on the given address.
+    ///
+    /// Use it to connect an app that is not started by the server, e.g. by a debugger. An address that contains
+    /// a `/` is the path of a Unix socket, otherwise it is a TCP address. The token of the `CLIENT_TOKEN` env
+    /// variable is sent to servers that listen with a token.
+    pub fn connect(
+        address: impl Into<String>,
+        width: f32,
+        height: f32,
+        title: String,
+        is_async: bool,
+    ) -> ClientResult<Self> {
+        Self::create(
+            width,
+            height,
+            title,
+            is_async,
+            DecorationMode::Server,
+            Some(address.into()),
+        )
+    }
+
+    fn create(
+        width: f32,
+        height: f32,
+        title: String,
+        is_async: bool,
+        decoration_mode: DecorationMode,
+        address: Option<String>,
+    ) -> ClientResult<Self> {
+        let runtime = tokio::runtime::Runtime::new()
+            .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+
+        let (sender, receiver, key, capabilities, decoration_mode, shared_memory, pixel_formats) =
+            runtime.block_on(async {
+                let (sender, receiver, request) = match &address {
+                    Some(address) => connect_address(address).await?,
+                    None => connect_env().await?,
+                };
+
+                // remote clients are not started by the server and fall back to their own executable.
+                let client_path = env::var(coop_protocol::CLIENT_PATH)
+                    .ok()
+                    .or_else(|| {
+                        env::current_exe()
+                            .ok()
+                            .map(|path| path.display().to_string())
+                    })
+                    .unwrap_or_default();
+
+                let mut capabilities: Vec<Capability> = Capability::all()
+                    .into_iter()
+                    .filter(|c| request.capabilities.contains(c))
+                    .collect();
+
+                // falls back to send the frame buffer with the draw message if no shared memory is available.
+                let shared_memory = if capabilities.contains(&Capability::SharedMemory) {
+                    env::var(coop_protocol::SHARED_MEMORY_PATH)
+                        .ok()
+                        .and_then(|path| SharedMemory::open(path).ok())
+                } else {
+                    None
+                };
+
+                if shared_memory.is_none() {
+                    capabilities.retain(|c| *c != Capability::SharedMemory);
+                }
+
+                // falls back to the mode preferred by the server if it does not support the requested mode.
+                let decoration_mode = if request.decoration_modes.contains(&decoration_mode) {
+                    decoration_mode
+                } else {
+                    request
+                        .decoration_modes
+                        .first()
+                        .copied()
+                        .unwrap_or_default()
+                };
 
-            if let ClientMessage::Create(sender) = message {
                 sender
                     .send(ClientMessage::Response(ClientResponse {
-                        key: client_key.clone(),
+                        key: request.key.clone(),
                         message: ClientResponseMessage::Created(CreationResponse {
                             title: title.clone(),
                             path: client_path,
                             width,
                             height,
+                            version: coop_protocol::VERSION,
+                            capabilities: capabilities.clone(),
+                            decoration_mode,
                         }),
                     }))
                     .await
-                    .expect("Cannot send creation message to backend.");
+                    .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
 
-                return Some((sender, receiver, client_key));
-            }
-            None
-        });
+                Ok::<_, ClientError>((
+                    sender,
+                    receiver,
+                    request.key,
+                    capabilities,
+                    decoration_mode,
+                    shared_memory,
+                    request.pixel_formats,
+                ))
+            })?;
 
-        let (sender, receiver, key) =
-            creation_result.expect("Cannot establish connection to backend.");
+        // frames are drawn in `PixelFormat::Rgba8888` by default, otherwise in the format preferred by the server.
+        let pixel_format = if pixel_formats.contains(&PixelFormat::Rgba8888) {
+            PixelFormat::Rgba8888
+        } else {
+            pixel_formats.first().copied().unwrap_or_default()
+        };
 
-        Self {
-            width,
-            height,
-            _title: title,
+        // messages are read on their own thread, so waiting for them can time out without losing a partly read
+        // message.
+        let (message_sender, message_receiver) = mpsc::channel();
+        let handle = runtime.handle().clone();
+
+        thread::spawn(move || {
+            handle.block_on(async move {
+                while let Ok(message) = receiver.recv().await {
+                    if message_sender.send(message).is_err() {
+                        break;
+                    }
+                }
+            })
+        });
+
+        Ok(Self {
+            width: Cell::new(width),
+            height: Cell::new(height),
+            title: RefCell::new(title),
             sender,
-            receiver,
+            receiver: message_receiver,
             key,
             is_async,
+            connected: Cell::new(true),
+            capabilities,
+            decoration_mode,
+            shared_memory: RefCell::new(shared_memory),
+            clipboard: RefCell::new(None),
+            windows: RefCell::new(vec![]),
+            window_count: Cell::new(0),
+            pixel_format: Cell::new(pixel_format),
+            pixel_formats,
             runtime,
-        }
+        })
     }
 
     /// Draws the frame buffer to the window.
-    pub fn draw(&self, buffer: &[u8], width: f32, height: f32) {
+    ///
+    /// The pixels of `buffer` must have the format of `pixel_format`, the length of a row is taken from the length
+    /// of `buffer` and `height`. The frame buffer is written to the shared memory if available, otherwise it is copied into the message.
+    pub fn draw(&self, buffer: &[u8], width: f32, height: f32) -> ClientResult<()> {
+        self.draw_damaged(buffer, width, height, &[])
+    }
+
+    /// Draws the frame buffer to the window but only updates the given damaged regions on the server.
+    ///
+    /// An empty list of regions means the whole frame buffer has changed.
+    pub fn draw_damaged(
+        &self,
+        buffer: &[u8],
+        width: f32,
+        height: f32,
+        damage: &[DamageRegion],
+    ) -> ClientResult<()> {
+        let damage = if self.has_capability(Capability::Damage) {
+            damage
+        } else {
+            &[]
+        };
+
+        let format = self.pixel_format.get();
+        let stride = stride(buffer, height);
+
+        if let Some(shared_memory) = self.shared_memory.borrow_mut().as_mut() {
+            if shared_memory.write(buffer).is_ok() {
+                return self.send(ClientMessage::Response(ClientResponse {
+                    key: self.key.clone(),
+                    message: ClientResponseMessage::FrameReady(FrameReadyResponse {
+                        len: buffer.len(),
+                        width,
+                        height,
+                        damage: damage.to_vec(),
+                        format,
+                        stride,
+                    }),
+                }));
+            }
+        }
+
+        let buffer = if damage.is_empty() {
+            buffer.to_vec()
+        } else {
+            pack_regions(buffer, stride, format, damage)
+        };
+
         self.send(ClientMessage::Response(ClientResponse {
             key: self.key.clone(),
             message: ClientResponseMessage::Draw(DrawResponse {
-                buffer: buffer.to_vec(),
+                buffer,
                 width,
                 height,
+                damage: damage.to_vec(),
+                format,
+                stride,
             }),
-        }));
+        }))
+    }
+
+    /// Opens an additional window, e.g. a dialog or a popup, and returns its key.
+    ///
+    /// Returns an error if the server does not support additional windows.
+    pub fn open_window(
+        &self,
+        title: impl Into<String>,
+        width: f32,
+        height: f32,
+        kind: WindowKind,
+    ) -> ClientResult<String> {
+        if !self.has_capability(Capability::Windows) {
+            return Err(ClientError::MissingCapability(Capability::Windows));
+        }
+
+        let key = format!("{}:{}", self.key, self.window_count.get());
+        self.window_count.set(self.window_count.get() + 1);
+
+        self.respond_for(
+            key.clone(),
+            ClientResponseMessage::WindowCreated(WindowCreationResponse {
+                title: title.into(),
+                width,
+                height,
+                kind,
+                decoration_mode: self.decoration_mode,
+            }),
+        )?;
+        self.windows.borrow_mut().push(key.clone());
+
+        Ok(key)
+    }
+
+    /// Draws the frame buffer to the additional window with the given key.
+    ///
+    /// Only the main window uses the shared memory, the frame buffer of an additional window is always copied
+    /// into the message.
+    pub fn draw_window(
+        &self,
+        key: &str,
+        buffer: &[u8],
+        width: f32,
+        height: f32,
+        damage: &[DamageRegion],
+    ) -> ClientResult<()> {
+        let damage = if self.has_capability(Capability::Damage) {
+            damage
+        } else {
+            &[]
+        };
+
+        let format = self.pixel_format.get();
+        let stride = stride(buffer, height);
+
+        let buffer = if damage.is_empty() {
+            buffer.to_vec()
+        } else {
+            pack_regions(buffer, stride, format, damage)
+        };
+
+        self.respond_for(
+            key.into(),
+            ClientResponseMessage::Draw(DrawResponse {
+                buffer,
+                width,
+                height,
+                damage: damage.to_vec(),
+                format,
+                stride,
+            }),
+        )
+    }
+
+    /// Closes the additional window with the given key, its child windows are closed by the server.
+    pub fn close_window(&self, key: &str) -> ClientResult<()> {
+        self.windows.borrow_mut().retain(|k| k != key);
+        self.respond_for(key.into(), ClientResponseMessage::WindowClosed)
+    }
+
+    /// Returns the keys of the open additional windows.
+    pub fn windows(&self) -> Vec<String> {
+        self.windows.borrow().clone()
+    }
+
+    /// Returns the key of the main window.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns `false` if the connection to the server is lost.
+    pub fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    /// Returns the decoration mode that is negotiated with the server, it is also used for additional top level
+    /// windows.
+    pub fn decoration_mode(&self) -> DecorationMode {
+        self.decoration_mode
+    }
+
+    /// Returns the capabilities that are negotiated with the server.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Returns `true` if the given capability is negotiated with the server.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Returns the format of the pixels of the frame buffers that are drawn.
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format.get()
+    }
+
+    /// Returns the pixel formats of frame buffers that are supported by the server, the first one is preferred by
+    /// the server.
+    pub fn pixel_formats(&self) -> &[PixelFormat] {
+        &self.pixel_formats
+    }
+
+    /// Sets the format of the pixels of the frame buffers that are drawn, the default is `PixelFormat::Rgba8888`.
+    ///
+    /// Returns an error if the server does not support the format, see `pixel_formats`.
+    pub fn set_pixel_format(&self, format: PixelFormat) -> ClientResult<()> {
+        if !self.pixel_formats.contains(&format) {
+            return Err(ClientError::UnsupportedPixelFormat(format));
+        }
+
+        self.pixel_format.set(format);
+
+        Ok(())
     }
 
     /// Returns the current width of the client window.
     pub fn width(&self) -> f32 {
-        self.width
+        self.width.get()
     }
 
     /// Returns the current height of the client window.
     pub fn height(&self) -> f32 {
-        self.height
+        self.height.get()
     }
 
     /// Returns the current size of the client window.
     pub fn size(&self) -> (f32, f32) {
-        (self.width, self.height)
+        (self.width.get(), self.height.get())
     }
 
-    /// Request list of events.
-    pub fn events(&self) -> Vec<ClientEvent> {
-        let mut events = vec![];
+    /// Returns the title of the client window.
+    pub fn title(&self) -> String {
+        self.title.borrow().clone()
+    }
 
-        'blocking: loop {
-            let event = self.runtime.block_on(async {
-                if let Ok(message) = self.receiver.recv().await {
-                    match message {
-                        ClientMessage::Create(_) => unreachable!("Client is already created."),
-                        ClientMessage::Response(_) => {
-                            unreachable!("Only the client send client responses")
-                        }
-                        ClientMessage::Event(e) => return Some(e),
-                        ClientMessage::Closed(_) => {
-                            unreachable!("Only the client send closed responses")
-                        }
-                    }
-                }
+    /// Changes the title of the client window.
+    pub fn set_title(&self, title: impl Into<String>) -> ClientResult<()> {
+        let title = title.into();
+        *self.title.borrow_mut() = title.clone();
 
-                None
-            });
+        self.respond(ClientResponseMessage::SetTitle(title))
+    }
 
-            if let Some(event) = event {
-                events.push(event);
-            }
+    /// Sets the icon of the client window from a rgba8 buffer.
+    pub fn set_icon(&self, buffer: &[u8], width: u32, height: u32) -> ClientResult<()> {
+        self.respond(ClientResponseMessage::SetIcon(WindowIcon {
+            buffer: buffer.to_vec(),
+            width,
+            height,
+        }))
+    }
+
+    /// Requests the server to change the state of the client window, e.g. to maximize it.
+    pub fn set_state(&self, state: WindowState) -> ClientResult<()> {
+        self.respond(ClientResponseMessage::SetState(state))
+    }
+
+    /// Requests the server to move the client window with the pressed pointer, e.g. on a press on a header bar that
+    /// is drawn by the client.
+    pub fn start_move(&self) -> ClientResult<()> {
+        self.respond(ClientResponseMessage::StartMove)
+    }
+
+    /// Requests the server to resize the client window at the given edge with the pressed pointer.
+    pub fn start_resize(&self, edge: ResizeEdge) -> ClientResult<()> {
+        self.respond(ClientResponseMessage::StartResize(edge))
+    }
+
+    /// Copies the given text to the clipboard that is shared by the server with all clients.
+    pub fn set_clipboard_text(&self, text: impl Into<String>) -> ClientResult<()> {
+        let text = text.into();
+        *self.clipboard.borrow_mut() = Some(text.clone());
+
+        self.respond(ClientResponseMessage::SetClipboardText(text))
+    }
+
+    /// Requests the server to open the client app with the given path.
+    ///
+    /// The server answers with `ClientEvent::PermissionDenied` if the client is not allowed to open clients and with
+    /// `ClientEvent::OpenClientFailed` if the client cannot be started.
+    pub fn open_client(&self, path: impl Into<String>) -> ClientResult<()> {
+        self.respond(ClientResponseMessage::OpenClient(path.into()))
+    }
+
+    /// Returns the current text of the shared clipboard.
+    pub fn clipboard_text(&self) -> Option<String> {
+        self.clipboard.borrow().clone()
+    }
+
+    /// Request list of events of the main window.
+    ///
+    /// A `ClientEvent::Resize` updates the size of the window before it is returned.
+    /// A `ClientEvent::ClipboardChanged` only updates the clipboard and is not returned.
+    /// Events of additional windows are dropped, use `window_events` if the client opens additional windows.
+    pub fn events(&self) -> ClientResult<Vec<ClientEvent>> {
+        Ok(self
+            .window_events()?
+            .into_iter()
+            .filter(|(key, _)| key == &self.key)
+            .map(|(_, event)| event)
+            .collect())
+    }
+
+    /// Request list of events of all windows with the key of the window the event belongs to.
+    ///
+    /// A `ClientEvent::Close` for an additional window means the server has closed the window.
+    pub fn window_events(&self) -> ClientResult<Vec<(String, ClientEvent)>> {
+        self.check_connection()?;
+
+        let mut events = vec![];
+
+        'blocking: loop {
+            let message = self.receiver.recv().map_err(|_| self.disconnect())?;
+            self.handle_message(message, &mut events)?;
 
             if !self.is_async && events.is_empty() {
                 std::thread::yield_now();
@@ -133,20 +523,199 @@ impl Window {
             }
         }
 
-        events
+        Ok(events)
+    }
+
+    /// Waits until a message is received from the server or the timeout has elapsed, `None` waits without timeout.
+    ///
+    /// Returns the events of all windows like `window_events`, the list is empty if the timeout has elapsed. Use it
+    /// to block an event loop until the next event or the next timer of the app.
+    pub fn wait_window_events(
+        &self,
+        timeout: Option<Duration>,
+    ) -> ClientResult<Vec<(String, ClientEvent)>> {
+        self.check_connection()?;
+
+        let message = match timeout {
+            Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(vec![]),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(self.disconnect()),
+            },
+            None => self.receiver.recv().map_err(|_| self.disconnect())?,
+        };
+
+        let mut events = vec![];
+        self.handle_message(message, &mut events)?;
+
+        // reads the messages that are already received without waiting.
+        while let Ok(message) = self.receiver.try_recv() {
+            self.handle_message(message, &mut events)?;
+        }
+
+        Ok(events)
     }
 
     /// Clean close the client window.
-    pub fn close(&self) {
-        self.send(ClientMessage::Closed(self.key.clone()));
+    pub fn close(&self) -> ClientResult<()> {
+        self.send(ClientMessage::Closed(self.key.clone()))
+    }
+
+    // updates the state of the window by the given message and adds its event to `events`.
+    fn handle_message(
+        &self,
+        message: ClientMessage,
+        events: &mut Vec<(String, ClientEvent)>,
+    ) -> ClientResult<()> {
+        match message {
+            // the server closes the connection after it has rejected the token.
+            ClientMessage::Event(ClientEvent::AuthenticationFailed) => {
+                self.connected.set(false);
+                return Err(ClientError::AuthenticationFailed);
+            }
+            ClientMessage::Event(ClientEvent::ClipboardChanged(text)) => {
+                *self.clipboard.borrow_mut() = Some(text);
+            }
+            ClientMessage::Event(event) => {
+                if let ClientEvent::Resize { width, height } = event {
+                    self.width.set(width);
+                    self.height.set(height);
+                }
+
+                events.push((self.key.clone(), event));
+            }
+            ClientMessage::WindowEvent { key, event } => {
+                if let ClientEvent::Close = event {
+                    self.windows.borrow_mut().retain(|k| k != &key);
+                }
+
+                events.push((key, event));
+            }
+            message => {
+                return Err(ClientError::UnexpectedMessage(format!("{:?}", message)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn respond(&self, message: ClientResponseMessage) -> ClientResult<()> {
+        self.respond_for(self.key.clone(), message)
+    }
+
+    fn respond_for(&self, key: String, message: ClientResponseMessage) -> ClientResult<()> {
+        self.send(ClientMessage::Response(ClientResponse { key, message }))
+    }
+
+    fn send(&self, message: ClientMessage) -> ClientResult<()> {
+        self.check_connection()?;
+
+        self.runtime
+            .block_on(async { self.sender.send(message).await })
+            .map_err(|_| self.disconnect())
     }
 
-    fn send(&self, message: ClientMessage) {
-        self.runtime.block_on(async {
-            self.sender
-                .send(message)
+    fn check_connection(&self) -> ClientResult<()> {
+        if !self.connected.get() {
+            return Err(ClientError::Disconnected);
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&self) -> ClientError {
+        self.connected.set(false);
+        ClientError::Disconnected
+    }
+}
+
+/// Connection to the server with the creation request of the server.
+type Connection = (
+    Box<dyn MessageSender>,
+    Box<dyn MessageReceiver>,
+    CreationRequest,
+);
+
+/// Connects to the server that is given by the env variables and returns the creation request of the server.
+async fn connect_env() -> ClientResult<Connection> {
+    if let Ok(server_key) = env::var(coop_protocol::SERVER_KEY) {
+        // checks the version before the first message is read, messages of other versions cannot be read.
+        let server_version = env::var(coop_protocol::PROTOCOL_VERSION)
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok());
+
+        if server_version != Some(coop_protocol::VERSION) {
+            return Err(ClientError::VersionMismatch {
+                server: server_version,
+                client: coop_protocol::VERSION,
+            });
+        }
+
+        let receiver = Receiver::<ClientMessage>::connect(server_key)
+            .await
+            .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+        let mut request = read_creation_request(&receiver).await?;
+        let sender = request.sender.take().ok_or_else(|| {
+            ClientError::UnexpectedMessage("Creation request without sender".into())
+        })?;
+
+        // the server drops clients that do not authenticate with the token it has passed to them.
+        if let Ok(token) = env::var(coop_protocol::CLIENT_TOKEN) {
+            sender
+                .send(ClientMessage::Authenticate(token))
                 .await
-                .expect("Cannot send message to server.");
+                .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+        }
+
+        return Ok((Box::new(sender), Box::new(receiver), request));
+    }
+
+    let address = env::var(coop_protocol::SERVER_ADDRESS)
+        .map_err(|_| ClientError::MissingEnv(coop_protocol::SERVER_ADDRESS.into()))?;
+
+    connect_address(&address).await
+}
+
+/// Connects to a server that listens on the given address and returns the creation request of the server.
+async fn connect_address(address: &str) -> ClientResult<Connection> {
+    let (server_version, sender, receiver) = coop_protocol::connect(address)
+        .await
+        .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+
+    // a listening server cannot pass its version by env, it writes it before the first message.
+    if server_version != coop_protocol::VERSION {
+        return Err(ClientError::VersionMismatch {
+            server: Some(server_version),
+            client: coop_protocol::VERSION,
         });
     }
+
+    let request = read_creation_request(receiver.as_ref()).await?;
+
+    // a server that listens with a token only grants permissions to clients that know it.
+    if let Ok(token) = env::var(coop_protocol::CLIENT_TOKEN) {
+        sender
+            .send(ClientMessage::Authenticate(token))
+            .await
+            .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+    }
+
+    Ok((sender, receiver, request))
+}
+
+async fn read_creation_request(receiver: &dyn MessageReceiver) -> ClientResult<CreationRequest> {
+    let message = receiver
+        .recv()
+        .await
+        .map_err(|e| ClientError::CannotConnect(format!("{:?}", e)))?;
+
+    match message {
+        ClientMessage::Create(request) => Ok(request),
+        message => Err(ClientError::UnexpectedMessage(format!("{:?}", message))),
+    }
+}
+
+// length of a row of the given frame buffer in bytes.
+fn stride(buffer: &[u8], height: f32) -> usize {
+    buffer.len() / (height as usize).max(1)
 }
@@ -0,0 +1,32 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+/// Represents a result from the client.
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientError {
+    /// The env variable with the given key is not set, the app is probably not started by a `coop_server`.
+    MissingEnv(String),
+
+    /// Client and server use different protocol versions.
+    VersionMismatch { server: Option<u32>, client: u32 },
+
+    /// Cannot connect to the server.
+    CannotConnect(String),
+
+    /// The server does not support the given capability.
+    MissingCapability(coop_protocol::Capability),
+
+    /// The server does not support frame buffers with the given pixel format.
+    UnsupportedPixelFormat(coop_protocol::PixelFormat),
+
+    /// The server has sent an unexpected message.
+    UnexpectedMessage(String),
+
+    /// The server has rejected the token of the client.
+    AuthenticationFailed,
+
+    /// The connection to the server is lost.
+    Disconnected,
+}
@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::PixelFormat;
+
+/// Describes a rectangle of a frame buffer that has changed since the last frame.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+pub struct DamageRegion {
+    /// X position of the region in pixels.
+    pub x: u32,
+
+    /// Y position of the region in pixels.
+    pub y: u32,
+
+    /// Width of the region in pixels.
+    pub width: u32,
+
+    /// Height of the region in pixels.
+    pub height: u32,
+}
+
+impl DamageRegion {
+    /// Creates a new damage region.
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Returns `true` if the region has no pixels.
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns the region clipped to a frame buffer of the given size.
+    pub fn clip(&self, width: u32, height: u32) -> Self {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+
+        Self {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+/// Copies the pixels of the given regions row by row out of `buffer` into one packed buffer without padding.
+///
+/// `stride` is the length of a row of `buffer` in bytes and `format` the format of its pixels.
+pub fn pack_regions(
+    buffer: &[u8],
+    stride: usize,
+    format: PixelFormat,
+    regions: &[DamageRegion],
+) -> Vec<u8> {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let (width, height) = frame_size(buffer.len(), stride, bytes_per_pixel);
+    let mut packed = vec![];
+
+    for region in regions.iter().map(|r| r.clip(width, height)) {
+        for row in region.y..region.y + region.height {
+            let start = row as usize * stride + region.x as usize * bytes_per_pixel;
+            let end = start + region.width as usize * bytes_per_pixel;
+            packed.extend_from_slice(&buffer[start..end]);
+        }
+    }
+
+    packed
+}
+
+/// Writes the pixels of a buffer that is created by `pack_regions` back to the given regions of `buffer`.
+///
+/// `stride` is the length of a row of `buffer` in bytes and `format` the format of the pixels of both buffers.
+pub fn unpack_regions(
+    buffer: &mut [u8],
+    stride: usize,
+    format: PixelFormat,
+    regions: &[DamageRegion],
+    packed: &[u8],
+) {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let (width, height) = frame_size(buffer.len(), stride, bytes_per_pixel);
+    let mut offset = 0;
+
+    for region in regions.iter().map(|r| r.clip(width, height)) {
+        let row_len = region.width as usize * bytes_per_pixel;
+
+        for row in region.y..region.y + region.height {
+            if offset + row_len > packed.len() {
+                return;
+            }
+
+            let start = row as usize * stride + region.x as usize * bytes_per_pixel;
+            buffer[start..start + row_len].copy_from_slice(&packed[offset..offset + row_len]);
+            offset += row_len;
+        }
+    }
+}
+
+// returns the number of pixels that fit into a row and the number of complete rows of a buffer.
+fn frame_size(len: usize, stride: usize, bytes_per_pixel: usize) -> (u32, u32) {
+    (
+        (stride / bytes_per_pixel) as u32,
+        crate::buffer_height(len, stride),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height * 4).map(|i| (i / 4) as u8).collect()
+    }
+
+    #[test]
+    fn test_clip() {
+        assert_eq!(
+            DamageRegion::new(2, 2, 10, 10).clip(4, 6),
+            DamageRegion::new(2, 2, 2, 4)
+        );
+        assert!(DamageRegion::new(8, 0, 2, 2).clip(4, 4).is_empty());
+    }
+
+    #[test]
+    fn test_pack_regions() {
+        let regions = [DamageRegion::new(1, 1, 2, 2)];
+        let packed = pack_regions(&buffer(4, 4), 16, PixelFormat::Rgba8888, &regions);
+
+        assert_eq!(
+            packed,
+            vec![5, 5, 5, 5, 6, 6, 6, 6, 9, 9, 9, 9, 10, 10, 10, 10]
+        );
+    }
+
+    #[test]
+    fn test_unpack_regions() {
+        let source = buffer(4, 4);
+        let regions = [DamageRegion::new(0, 0, 1, 1), DamageRegion::new(2, 3, 2, 1)];
+        let packed = pack_regions(&source, 16, PixelFormat::Rgba8888, &regions);
+
+        let mut target = vec![0; source.len()];
+        unpack_regions(&mut target, 16, PixelFormat::Rgba8888, &regions, &packed);
+
+        assert_eq!(&target[0..4], &source[0..4]);
+        assert_eq!(&target[56..64], &source[56..64]);
+        assert_eq!(&target[4..56], &[0; 52]);
+    }
+
+    #[test]
+    fn test_pack_regions_with_stride() {
+        // 2x2 Rgb565 buffer with a stride of 6 bytes, the last two bytes of each row are padding.
+        let source = [1, 1, 2, 2, 0, 0, 3, 3, 4, 4, 0, 0];
+        let regions = [DamageRegion::new(1, 0, 1, 2)];
+        let packed = pack_regions(&source, 6, PixelFormat::Rgb565, &regions);
+
+        assert_eq!(packed, vec![2, 2, 4, 4]);
+
+        let mut target = vec![0; source.len()];
+        unpack_regions(&mut target, 6, PixelFormat::Rgb565, &regions, &packed);
+
+        assert_eq!(target, vec![0, 0, 2, 2, 0, 0, 0, 0, 4, 4, 0, 0]);
+    }
+}
@@ -0,0 +1,188 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+macro_rules! special_keys {
+    ($($char:literal => $name:ident,)*) => {
+        /// Key of a key event.
+        ///
+        /// Named keys use the same character codes as the special keys of Slint (`slint::platform::Key`).
+        #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+        pub enum Key {
+            /// Key that produces the given text.
+            Character(String),
+            $($name,)*
+        }
+
+        impl Key {
+            /// Returns the key that is represented by the given text.
+            ///
+            /// A single special character is converted to its named key, every other text to `Key::Character`.
+            pub fn from_text(text: &str) -> Self {
+                let mut chars = text.chars();
+
+                match (chars.next(), chars.next()) {
+                    $((Some($char), None) => Key::$name,)*
+                    _ => Key::Character(text.into()),
+                }
+            }
+
+            /// Returns the text representation of the key. For named keys this is the special character.
+            pub fn text(&self) -> String {
+                match self {
+                    Key::Character(text) => text.clone(),
+                    $(Key::$name => $char.into(),)*
+                }
+            }
+        }
+    };
+}
+
+special_keys! {
+    '\u{0008}' => Backspace,
+    '\u{0009}' => Tab,
+    '\u{000a}' => Return,
+    '\u{001b}' => Escape,
+    '\u{0019}' => Backtab,
+    '\u{007f}' => Delete,
+    '\u{0010}' => Shift,
+    '\u{0011}' => Control,
+    '\u{0012}' => Alt,
+    '\u{0013}' => AltGr,
+    '\u{0014}' => CapsLock,
+    '\u{0015}' => ShiftR,
+    '\u{0016}' => ControlR,
+    '\u{0017}' => Meta,
+    '\u{0018}' => MetaR,
+    '\u{0020}' => Space,
+    '\u{F700}' => UpArrow,
+    '\u{F701}' => DownArrow,
+    '\u{F702}' => LeftArrow,
+    '\u{F703}' => RightArrow,
+    '\u{F704}' => F1,
+    '\u{F705}' => F2,
+    '\u{F706}' => F3,
+    '\u{F707}' => F4,
+    '\u{F708}' => F5,
+    '\u{F709}' => F6,
+    '\u{F70A}' => F7,
+    '\u{F70B}' => F8,
+    '\u{F70C}' => F9,
+    '\u{F70D}' => F10,
+    '\u{F70E}' => F11,
+    '\u{F70F}' => F12,
+    '\u{F710}' => F13,
+    '\u{F711}' => F14,
+    '\u{F712}' => F15,
+    '\u{F713}' => F16,
+    '\u{F714}' => F17,
+    '\u{F715}' => F18,
+    '\u{F716}' => F19,
+    '\u{F717}' => F20,
+    '\u{F718}' => F21,
+    '\u{F719}' => F22,
+    '\u{F71A}' => F23,
+    '\u{F71B}' => F24,
+    '\u{F727}' => Insert,
+    '\u{F729}' => Home,
+    '\u{F72B}' => End,
+    '\u{F72C}' => PageUp,
+    '\u{F72D}' => PageDown,
+    '\u{F72F}' => ScrollLock,
+    '\u{F730}' => Pause,
+    '\u{F731}' => SysReq,
+    '\u{F734}' => Stop,
+    '\u{F735}' => Menu,
+}
+
+/// State of the modifier keys of a key event.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+pub struct KeyboardModifiers {
+    /// Alt key is pressed.
+    pub alt: bool,
+
+    /// Control key is pressed.
+    pub control: bool,
+
+    /// Shift key is pressed.
+    pub shift: bool,
+
+    /// Meta key is pressed.
+    pub meta: bool,
+}
+
+/// Describes a key that is pressed or released.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct KeyEvent {
+    /// The key of the event.
+    pub key: Key,
+
+    /// Text that is composed by the key press, e.g. `A` if shift is pressed. Empty for named keys.
+    pub text: String,
+
+    /// Modifier keys that are pressed while the event occurs.
+    ///
+    /// Modifier keys are also sent as key events of their own.
+    pub modifiers: KeyboardModifiers,
+
+    /// `true` if the event is repeated because the key is held down.
+    pub repeat: bool,
+}
+
+impl KeyEvent {
+    /// Creates a key event from the text representation of the key.
+    pub fn from_text(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let key = Key::from_text(&text);
+
+        Self {
+            text: if let Key::Character(_) = key {
+                text
+            } else {
+                String::default()
+            },
+            key,
+            modifiers: KeyboardModifiers::default(),
+            repeat: false,
+        }
+    }
+
+    /// Returns the text that is used to dispatch the event, the composed text or the special character of a named key.
+    pub fn dispatch_text(&self) -> String {
+        if self.text.is_empty() {
+            self.key.text()
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_text() {
+        assert_eq!(Key::from_text("\u{F700}"), Key::UpArrow);
+        assert_eq!(Key::from_text("\t"), Key::Tab);
+        assert_eq!(Key::from_text("a"), Key::Character("a".into()));
+        assert_eq!(Key::from_text(""), Key::Character("".into()));
+        assert_eq!(Key::from_text("\tx"), Key::Character("\tx".into()));
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        for key in [Key::Backspace, Key::Control, Key::F24, Key::Menu] {
+            assert_eq!(Key::from_text(&key.text()), key);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_text() {
+        assert_eq!(KeyEvent::from_text("\u{F702}").dispatch_text(), "\u{F702}");
+        assert_eq!(KeyEvent::from_text("é").dispatch_text(), "é");
+        assert!(KeyEvent::from_text("\u{F702}").text.is_empty());
+    }
+}
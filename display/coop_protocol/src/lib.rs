@@ -4,19 +4,204 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+mod damage;
+mod key;
+mod pixel;
+mod record;
+mod shared_memory;
+mod transport;
+
+pub use damage::*;
+pub use key::*;
+pub use pixel::*;
+pub use record::*;
+pub use shared_memory::*;
+pub use transport::*;
+
 /// Env key to identify the server.
 pub const SERVER_KEY: &str = "SERVER_KEY";
 
+/// Env key of the address a server listens on, it is used if `SERVER_KEY` is not set (see `connect`).
+pub const SERVER_ADDRESS: &str = "SERVER_ADDRESS";
+
 /// Env key to identify a client.
 pub const CLIENT_KEY: &str = "CLIENT_KEY";
 
 /// Origin client path.
 pub const CLIENT_PATH: &str = "CLIENT_PATH";
 
+/// Env key of the secret a client authenticates with, see `ClientMessage::Authenticate`. It is set by the server
+/// for the clients it starts, attached clients set it to the token of the listener.
+pub const CLIENT_TOKEN: &str = "CLIENT_TOKEN";
+
+/// Env key of the shared memory file that is used to exchange frame buffers.
+pub const SHARED_MEMORY_PATH: &str = "SHARED_MEMORY_PATH";
+
+/// Env key of the protocol version of the server.
+pub const PROTOCOL_VERSION: &str = "PROTOCOL_VERSION";
+
+/// Version of the protocol. Client and server can only communicate if both use the same version.
+pub const VERSION: u32 = 14;
+
+/// Optional features of the protocol that are negotiated between client and server.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, JsonSchema)]
+pub enum Capability {
+    /// The client can be resized by `ClientEvent::Resize`.
+    Resize,
+
+    /// The client can send damaged regions instead of the whole frame buffer.
+    Damage,
+
+    /// The client can send frame buffers by the shared memory.
+    SharedMemory,
+
+    /// The client can open windows in addition to its main window.
+    Windows,
+
+    /// The client gets a `ClientEvent::FrameDone` when the server is ready for its next frame.
+    FrameCallbacks,
+}
+
+impl Capability {
+    /// Returns all capabilities that are supported by this version of the protocol.
+    pub fn all() -> Vec<Capability> {
+        vec![
+            Capability::Resize,
+            Capability::Damage,
+            Capability::SharedMemory,
+            Capability::Windows,
+            Capability::FrameCallbacks,
+        ]
+    }
+}
+
+/// Decides who draws the decorations of a top level window like title bar and border.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, JsonSchema)]
+pub enum DecorationMode {
+    /// The server draws the decorations around the frame buffer of the window.
+    #[default]
+    Server,
+
+    /// The client draws its own decorations, e.g. a header bar, into its frame buffer.
+    ///
+    /// Use `ClientResponseMessage::StartMove` and `ClientResponseMessage::StartResize` to let the user move and
+    /// resize the window with them.
+    Client,
+}
+
+impl DecorationMode {
+    /// Returns all decoration modes.
+    pub fn all() -> Vec<DecorationMode> {
+        vec![DecorationMode::Server, DecorationMode::Client]
+    }
+}
+
+/// Edge or corner of a window that is dragged by an interactive resize.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum ResizeEdge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl ResizeEdge {
+    /// Returns `true` if the left side of the window is moved by the resize.
+    pub fn is_left(&self) -> bool {
+        matches!(
+            self,
+            ResizeEdge::Left | ResizeEdge::TopLeft | ResizeEdge::BottomLeft
+        )
+    }
+
+    /// Returns `true` if the right side of the window is moved by the resize.
+    pub fn is_right(&self) -> bool {
+        matches!(
+            self,
+            ResizeEdge::Right | ResizeEdge::TopRight | ResizeEdge::BottomRight
+        )
+    }
+
+    /// Returns `true` if the top side of the window is moved by the resize.
+    pub fn is_top(&self) -> bool {
+        matches!(
+            self,
+            ResizeEdge::Top | ResizeEdge::TopLeft | ResizeEdge::TopRight
+        )
+    }
+
+    /// Returns `true` if the bottom side of the window is moved by the resize.
+    pub fn is_bottom(&self) -> bool {
+        matches!(
+            self,
+            ResizeEdge::Bottom | ResizeEdge::BottomLeft | ResizeEdge::BottomRight
+        )
+    }
+}
+
+/// Actions a client is only allowed to perform if the server grants the permission.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, JsonSchema)]
+pub enum Permission {
+    /// The client can read and write the shared clipboard.
+    Clipboard,
+
+    /// The client can open other clients by `ClientResponseMessage::OpenClient`.
+    SpawnClients,
+
+    /// The client can switch to `WindowState::Fullscreen`.
+    Fullscreen,
+}
+
+impl Permission {
+    /// Returns all permissions.
+    pub fn all() -> Vec<Permission> {
+        vec![
+            Permission::Clipboard,
+            Permission::SpawnClients,
+            Permission::Fullscreen,
+        ]
+    }
+
+    /// Returns the permissions of clients that are opened without explicit permissions, all except
+    /// `Permission::SpawnClients`.
+    pub fn defaults() -> Vec<Permission> {
+        vec![Permission::Clipboard, Permission::Fullscreen]
+    }
+}
+
+/// Request from the server to create a client.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CreationRequest {
+    /// Sender that is used by the client to send messages to the server.
+    ///
+    /// It is `None` if the connection is already bidirectional, e.g. over TCP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<tokio_unix_ipc::Sender<ClientMessage>>,
+
+    /// Key of the client.
+    pub key: String,
+
+    /// Protocol version of the server.
+    pub version: u32,
+
+    /// Capabilities supported by the server.
+    pub capabilities: Vec<Capability>,
+
+    /// Decoration modes supported by the server, the first one is preferred by the server.
+    pub decoration_modes: Vec<DecorationMode>,
+
+    /// Pixel formats of frame buffers supported by the server, the first one is preferred by the server.
+    pub pixel_formats: Vec<PixelFormat>,
+}
+
 /// Response from the client with the frame buffer.
 #[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
 pub struct DrawResponse {
-    /// Frame buffer to draw.
+    /// Frame buffer to draw. If `damage` is not empty it contains only the pixels of the damaged regions (see `pack_regions`).
     pub buffer: Vec<u8>,
 
     /// Current width to draw.
@@ -24,6 +209,37 @@ pub struct DrawResponse {
 
     /// Current height to draw.
     pub height: f32,
+
+    /// Regions that have changed since the last frame. If empty the whole frame buffer is sent.
+    pub damage: Vec<DamageRegion>,
+
+    /// Format of the pixels of `buffer`.
+    pub format: PixelFormat,
+
+    /// Length of a row of the whole frame buffer in bytes. The rows of packed damaged regions have no padding.
+    pub stride: usize,
+}
+
+/// Response from the client that a frame is written to the shared memory.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct FrameReadyResponse {
+    /// Length of the frame buffer in bytes.
+    pub len: usize,
+
+    /// Current width to draw.
+    pub width: f32,
+
+    /// Current height to draw.
+    pub height: f32,
+
+    /// Regions that have changed since the last frame. If empty the whole frame buffer has changed.
+    pub damage: Vec<DamageRegion>,
+
+    /// Format of the pixels of the frame buffer.
+    pub format: PixelFormat,
+
+    /// Length of a row of the frame buffer in bytes.
+    pub stride: usize,
 }
 
 /// Response from the client that the client is created.
@@ -40,6 +256,95 @@ pub struct CreationResponse {
 
     /// Initial frame buffer height of the client.
     pub height: f32,
+
+    /// Protocol version of the client.
+    pub version: u32,
+
+    /// Capabilities that are supported by both, client and server.
+    pub capabilities: Vec<Capability>,
+
+    /// Decoration mode of the main window that is chosen by the client from the modes supported by the server.
+    pub decoration_mode: DecorationMode,
+}
+
+/// Display state of a client window.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+pub enum WindowState {
+    /// The window is shown with its own size.
+    #[default]
+    Normal,
+
+    /// The window is hidden until it is brought to front.
+    Minimized,
+
+    /// The window fills the display area.
+    Maximized,
+
+    /// The window fills the whole display.
+    Fullscreen,
+}
+
+/// Icon of a client window.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct WindowIcon {
+    /// Rgba8 pixels of the icon.
+    pub buffer: Vec<u8>,
+
+    /// Width of the icon in pixels.
+    pub width: u32,
+
+    /// Height of the icon in pixels.
+    pub height: u32,
+}
+
+impl WindowIcon {
+    /// Returns `true` if the buffer holds exactly the pixels of the size of the icon.
+    ///
+    /// The icon is sent by the client, the server drops an icon that is not valid.
+    pub fn is_valid(&self) -> bool {
+        (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|len| len.checked_mul(4))
+            == Some(self.buffer.len())
+    }
+}
+
+/// Kind of a window that a client opens in addition to its main window.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub enum WindowKind {
+    /// Independent window.
+    TopLevel,
+
+    /// Transient window like a context menu or a dialog.
+    Popup {
+        /// Key of the parent window.
+        parent: String,
+
+        /// X position relative to the parent window.
+        x: f32,
+
+        /// Y position relative to the parent window.
+        y: f32,
+    },
+}
+
+/// Response from the client that it has opened an additional window.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct WindowCreationResponse {
+    /// Title of the window.
+    pub title: String,
+
+    /// Initial frame buffer width of the window.
+    pub width: f32,
+
+    /// Initial frame buffer height of the window.
+    pub height: f32,
+
+    /// Kind of the window.
+    pub kind: WindowKind,
+
+    /// Decoration mode of the window, it is ignored for popups.
+    pub decoration_mode: DecorationMode,
 }
 
 /// Response message send from the client.
@@ -51,9 +356,61 @@ pub enum ClientResponseMessage {
     /// Client buffer to draw.
     Draw(DrawResponse),
 
+    /// Client buffer is ready to draw from the shared memory.
+    FrameReady(FrameReadyResponse),
+
     /// The client is closed.
     Closed,
 
+    /// The client has terminated without closing, e.g. it crashed or the connection was lost.
+    Crashed {
+        /// Exit code of the client process if it has exited normally.
+        exit_code: Option<i32>,
+
+        /// Signal that has terminated the client process.
+        signal: Option<i32>,
+    },
+
+    /// The client has opened an additional window, the key of the response is the key of the new window.
+    WindowCreated(WindowCreationResponse),
+
+    /// An additional window of the client is closed.
+    WindowClosed,
+
+    /// The title of the client has changed.
+    SetTitle(String),
+
+    /// The icon of the client has changed.
+    SetIcon(WindowIcon),
+
+    /// The client requests to change its window state. The server decides how to handle it.
+    SetState(WindowState),
+
+    /// The client requests to move its window with the pressed pointer until it is released, e.g. after a press on
+    /// its header bar. The server decides how to handle it.
+    StartMove,
+
+    /// The client requests to resize its window at the given edge with the pressed pointer until it is released.
+    /// The server decides how to handle it.
+    StartResize(ResizeEdge),
+
+    /// Text is copied to the clipboard of the client. The server shares it with all other clients.
+    SetClipboardText(String),
+
+    /// The client requests to open the client with the given path.
+    OpenClient(String),
+
+    /// Message from server if a client cannot be started, does not connect or is rejected, e.g. because of a
+    /// different protocol version. The key of the response is the key of the client, it is empty if the client
+    /// could not be started at all.
+    OpenClientFailed {
+        /// Path of the client.
+        path: String,
+
+        /// Description of the error.
+        error: String,
+    },
+
     /// Message from server if all clients are closed
     AllClosed,
 }
@@ -78,11 +435,20 @@ pub enum PointerEventButton {
 }
 
 // Event that is send to the client.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub enum ClientEvent {
     /// The client should be closed.
     Close,
 
+    /// The client window is resized.
+    Resize {
+        /// New width of the client window.
+        width: f32,
+
+        /// New height of the client window.
+        height: f32,
+    },
+
     /// Pointer is pressed.
     PointerPressed {
         /// Current position of the button.
@@ -101,36 +467,105 @@ pub enum ClientEvent {
         button: PointerEventButton,
     },
 
+    /// Pointer is moved.
+    PointerMoved {
+        /// Current position of the pointer.
+        position: (f32, f32),
+    },
+
+    /// Wheel or touchpad is scrolled.
+    PointerScrolled {
+        /// Current position of the pointer.
+        position: (f32, f32),
+
+        /// Scrolled distance on the x axis.
+        delta_x: f32,
+
+        /// Scrolled distance on the y axis.
+        delta_y: f32,
+    },
+
     /// The pointer exited the window.
     PointerExited,
 
     /// Pointer event is canceled.
     PointerCanceled,
-    /// A key was pressed.
-    KeyPressed {
-        /// The unicode representation of the key pressed.
-        text: String,
-    },
+
+    /// The text of the shared clipboard has changed.
+    ClipboardChanged(String),
 
     /// A key was pressed.
-    KeyReleased {
-        /// The unicode representation of the key released.
-        text: String,
-    },
+    KeyPressed(KeyEvent),
+
+    /// A key was released.
+    KeyReleased(KeyEvent),
+
+    /// The window has got the keyboard focus.
+    FocusIn,
+
+    /// The window has lost the keyboard focus.
+    FocusOut,
+
+    /// The server has rejected a message of the client because the client does not have the given permission.
+    PermissionDenied(Permission),
+
+    /// The server cannot open the client with the given path that is requested by
+    /// `ClientResponseMessage::OpenClient`.
+    OpenClientFailed(String),
+
+    /// The server has rejected the token of the client, the connection is closed.
+    AuthenticationFailed,
+
+    /// The server is ready for the next frame of the window, it is sent at the refresh rate of the server after
+    /// the window has drawn.
+    FrameDone,
 }
 
 /// Messages between client and server.
 #[derive(Deserialize, Serialize, Debug)]
 pub enum ClientMessage {
     /// Request to open an create a client.
-    Create(tokio_unix_ipc::Sender<ClientMessage>),
+    Create(CreationRequest),
+
+    /// First message of a client that is started by the server or attaches to a listener with a token, contains
+    /// the secret of `CLIENT_TOKEN`.
+    Authenticate(String),
 
     /// Send an event to the client.
     Event(ClientEvent),
 
+    /// Send an event to an additional window of the client.
+    WindowEvent {
+        /// Key of the window.
+        key: String,
+
+        /// The event.
+        event: ClientEvent,
+    },
+
     /// Responses from client to server.
     Response(ClientResponse),
 
     /// Response that the client is closed
     Closed(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_icon_is_valid() {
+        let icon = |len: usize, width: u32, height: u32| WindowIcon {
+            buffer: vec![0; len],
+            width,
+            height,
+        };
+
+        assert!(icon(8, 2, 1).is_valid());
+        assert!(icon(0, 0, 0).is_valid());
+        assert!(!icon(7, 2, 1).is_valid());
+        assert!(!icon(12, 2, 1).is_valid());
+        assert!(!icon(0, u32::MAX, u32::MAX).is_valid());
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Memory layout of the pixels of a frame buffer.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, JsonSchema)]
+pub enum PixelFormat {
+    /// Four bytes per pixel in the order red, green, blue, alpha.
+    #[default]
+    Rgba8888,
+
+    /// Four bytes per pixel in the order blue, green, red, alpha.
+    Bgra8888,
+
+    /// Two bytes per pixel, little endian with 5 bits red, 6 bits green and 5 bits blue from the high to the low bits.
+    Rgb565,
+}
+
+impl PixelFormat {
+    /// Returns all pixel formats.
+    pub fn all() -> Vec<PixelFormat> {
+        vec![
+            PixelFormat::Rgba8888,
+            PixelFormat::Bgra8888,
+            PixelFormat::Rgb565,
+        ]
+    }
+
+    /// Returns the number of bytes of one pixel.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    /// Returns the number of bytes of a row of the given width without padding.
+    pub fn stride(&self, width: u32) -> usize {
+        width as usize * self.bytes_per_pixel()
+    }
+
+    /// Converts one pixel into red, green, blue and alpha. `pixel` must contain at least `bytes_per_pixel` bytes.
+    pub fn to_rgba(&self, pixel: &[u8]) -> [u8; 4] {
+        match self {
+            PixelFormat::Rgba8888 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            PixelFormat::Bgra8888 => [pixel[2], pixel[1], pixel[0], pixel[3]],
+            PixelFormat::Rgb565 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let r = ((value >> 11) & 0x1F) as u8;
+                let g = ((value >> 5) & 0x3F) as u8;
+                let b = (value & 0x1F) as u8;
+
+                // expands the channels to 8 bits, so that the maximum of a channel stays the maximum.
+                [
+                    (r << 3) | (r >> 2),
+                    (g << 2) | (g >> 4),
+                    (b << 3) | (b >> 2),
+                    255,
+                ]
+            }
+        }
+    }
+
+    /// Converts pixels without padding into pixels of the format `Rgba8888`. Incomplete trailing bytes are ignored.
+    pub fn to_rgba8(&self, pixels: &[u8]) -> Vec<u8> {
+        if *self == PixelFormat::Rgba8888 {
+            return pixels[..pixels.len() - pixels.len() % 4].to_vec();
+        }
+
+        pixels
+            .chunks_exact(self.bytes_per_pixel())
+            .flat_map(|pixel| self.to_rgba(pixel))
+            .collect()
+    }
+}
+
+/// Converts a frame buffer with the given width, stride in bytes and format into a frame buffer of the format
+/// `Rgba8888` without padding.
+///
+/// The height is taken from the buffer, so the result contains `buffer.len() / stride` complete rows.
+pub fn convert_to_rgba8(buffer: &[u8], width: u32, stride: usize, format: PixelFormat) -> Vec<u8> {
+    let row_len = format.stride(width);
+
+    if stride == 0 || row_len > stride {
+        return vec![];
+    }
+
+    if stride == row_len {
+        return format.to_rgba8(&buffer[..buffer.len() - buffer.len() % stride]);
+    }
+
+    buffer
+        .chunks_exact(stride)
+        .flat_map(|row| format.to_rgba8(&row[..row_len]))
+        .collect()
+}
+
+/// Returns the number of complete rows of a frame buffer with the given length and stride in bytes.
+pub fn buffer_height(len: usize, stride: usize) -> u32 {
+    len.checked_div(stride).unwrap_or_default() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_rgba() {
+        assert_eq!(PixelFormat::Rgba8888.to_rgba(&[1, 2, 3, 4]), [1, 2, 3, 4]);
+        assert_eq!(PixelFormat::Bgra8888.to_rgba(&[1, 2, 3, 4]), [3, 2, 1, 4]);
+        assert_eq!(
+            PixelFormat::Rgb565.to_rgba(&0xF800_u16.to_le_bytes()),
+            [255, 0, 0, 255]
+        );
+        assert_eq!(
+            PixelFormat::Rgb565.to_rgba(&0x07E0_u16.to_le_bytes()),
+            [0, 255, 0, 255]
+        );
+        assert_eq!(
+            PixelFormat::Rgb565.to_rgba(&0x001F_u16.to_le_bytes()),
+            [0, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_to_rgba8() {
+        assert_eq!(
+            PixelFormat::Bgra8888.to_rgba8(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            vec![3, 2, 1, 4, 7, 6, 5, 8]
+        );
+        assert_eq!(
+            PixelFormat::Rgb565.to_rgba8(&[0xFF, 0xFF, 0, 0, 1]),
+            vec![255, 255, 255, 255, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn test_convert_to_rgba8() {
+        // two rows of one Bgra8888 pixel with two bytes padding each.
+        let buffer = [1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 0, 0];
+
+        assert_eq!(
+            convert_to_rgba8(&buffer, 1, 6, PixelFormat::Bgra8888),
+            vec![3, 2, 1, 4, 7, 6, 5, 8]
+        );
+        assert_eq!(buffer_height(buffer.len(), 6), 2);
+
+        // an incomplete row is dropped.
+        assert_eq!(
+            convert_to_rgba8(&buffer[..10], 1, 6, PixelFormat::Bgra8888),
+            vec![3, 2, 1, 4]
+        );
+
+        // a stride smaller than a row is invalid.
+        assert!(convert_to_rgba8(&buffer, 2, 6, PixelFormat::Rgba8888).is_empty());
+        assert_eq!(buffer_height(buffer.len(), 0), 0);
+    }
+}
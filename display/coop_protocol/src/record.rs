@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::io::{self, BufRead, Write};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientEvent, ClientResponse};
+
+/// Message that is exchanged between server and client during a recorded session.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub enum RecordMessage {
+    /// Event that is sent to the window with the given key.
+    Event { key: String, event: ClientEvent },
+
+    /// Response that is received from a client.
+    Response(ClientResponse),
+}
+
+/// Entry of a recorded session. A recording contains one JSON serialized record per line.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct Record {
+    /// Milliseconds since the recording has started.
+    pub time: u64,
+
+    /// Recorded message.
+    pub message: RecordMessage,
+}
+
+/// Writes the given record as one line to `writer`.
+pub fn write_record(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+/// Reads all records of a recording. Empty lines are skipped.
+pub fn read_records(reader: impl BufRead) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        records.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientResponseMessage;
+
+    #[test]
+    fn test_write_read_records() {
+        let mut recording = vec![];
+
+        write_record(
+            &mut recording,
+            &Record {
+                time: 0,
+                message: RecordMessage::Response(ClientResponse {
+                    key: "client".into(),
+                    message: ClientResponseMessage::SetTitle("title".into()),
+                }),
+            },
+        )
+        .unwrap();
+        write_record(
+            &mut recording,
+            &Record {
+                time: 16,
+                message: RecordMessage::Event {
+                    key: "client:0".into(),
+                    event: ClientEvent::PointerMoved { position: (1., 2.) },
+                },
+            },
+        )
+        .unwrap();
+
+        let records = read_records(recording.as_slice()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(
+            &records[0],
+            Record {
+                time: 0,
+                message: RecordMessage::Response(ClientResponse {
+                    message: ClientResponseMessage::SetTitle(title),
+                    ..
+                }),
+            } if title == "title"
+        ));
+        assert!(matches!(
+            &records[1],
+            Record {
+                time: 16,
+                message: RecordMessage::Event {
+                    key,
+                    event: ClientEvent::PointerMoved { position: (x, y) },
+                },
+            } if key == "client:0" && *x == 1. && *y == 2.
+        ));
+
+        assert!(read_records("no json\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use memmap2::MmapMut;
+
+/// File backed shared memory that is used to exchange frame buffers between client and server.
+///
+/// The client writes to a memory mapping of the file. The server does not trust the client and reads the file into
+/// its own buffer, a client that truncates or writes the file meanwhile only spoils its own frame.
+#[derive(Debug)]
+pub struct SharedMemory {
+    path: PathBuf,
+    file: File,
+    map: Option<MmapMut>,
+}
+
+impl SharedMemory {
+    /// Creates a new empty shared memory file on the given path that is only accessible by the current user.
+    ///
+    /// Fails if the path already exists, a file or symlink that is placed there by someone else is never used.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path.as_ref())?;
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            map: None,
+        })
+    }
+
+    /// Opens an existing shared memory file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())?;
+
+        let mut shared_memory = Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            map: None,
+        };
+        shared_memory.remap()?;
+
+        Ok(shared_memory)
+    }
+
+    /// Gets the path of the shared memory file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the size of the current mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.map.as_ref().map(|m| m.len()).unwrap_or_default()
+    }
+
+    /// Returns `true` if nothing is mapped.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the given buffer to the beginning of the shared memory. Grows the file if needed.
+    pub fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        if self.len() < buffer.len() {
+            self.file.set_len(buffer.len() as u64)?;
+            self.remap()?;
+        }
+
+        if let Some(map) = &mut self.map {
+            map[..buffer.len()].copy_from_slice(buffer);
+        }
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes from the beginning of the shared memory file.
+    ///
+    /// The file is read without a memory mapping, so a file that is truncated by the other side results in an
+    /// error instead of a bus error.
+    pub fn read(&self, len: usize) -> io::Result<Vec<u8>> {
+        if self.file.metadata()?.len() < len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Shared memory is smaller than the requested frame.",
+            ));
+        }
+
+        let mut buffer = vec![0; len];
+        self.file.read_exact_at(&mut buffer, 0)?;
+
+        Ok(buffer)
+    }
+
+    /// Removes the shared memory file.
+    pub fn remove(self) -> io::Result<()> {
+        std::fs::remove_file(&self.path)
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+
+        if self.file.metadata()?.len() == 0 {
+            return Ok(());
+        }
+
+        // Safety: only the client maps the file and writes to it. The server that has created the file never
+        // truncates it and reads it with `read`, so no slice of the mapping is invalidated by the other side.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_write_read() {
+        let path = std::env::temp_dir().join(format!("coop_shm_test_{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+
+        let server = SharedMemory::create(&path).unwrap();
+        assert!(server.is_empty());
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            SharedMemory::create(&path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        let mut client = SharedMemory::open(&path).unwrap();
+        client.write(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(server.read(4).unwrap(), vec![1, 2, 3, 4]);
+
+        client.write(&[5, 6, 7, 8, 9, 10, 11, 12]).unwrap();
+        assert_eq!(server.read(8).unwrap(), vec![5, 6, 7, 8, 9, 10, 11, 12]);
+        assert!(server.read(16).is_err());
+
+        // a truncated file cannot be read but does not crash the reader.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(2)
+            .unwrap();
+        assert_eq!(
+            server.read(8).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        server.remove().unwrap();
+    }
+}
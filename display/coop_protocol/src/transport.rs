@@ -0,0 +1,415 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{tcp, unix, TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::AbortHandle;
+
+use crate::{ClientMessage, VERSION};
+
+/// Future that is returned by `MessageSender::send`.
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + 'a>>;
+
+/// Future that is returned by `MessageReceiver::recv`.
+pub type RecvFuture<'a> = Pin<Box<dyn Future<Output = io::Result<ClientMessage>> + 'a>>;
+
+/// Largest message in bytes that is accepted by a `StreamReceiver`.
+pub const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Largest message in bytes that is accepted from a peer that has not completed the handshake yet, see
+/// `MessageReceiver::set_max_frame_len`.
+pub const MAX_HANDSHAKE_FRAME_LEN: usize = 16 * 1024;
+
+/// Sends messages over a connection between client and server.
+pub trait MessageSender: Debug + Send {
+    /// Sends a message to the other side of the connection.
+    fn send(&self, message: ClientMessage) -> SendFuture<'_>;
+}
+
+/// Receives messages over a connection between client and server.
+pub trait MessageReceiver: Debug + Send {
+    /// Waits for the next message from the other side of the connection.
+    fn recv(&self) -> RecvFuture<'_>;
+
+    /// Sets the largest message in bytes that is accepted from now on, e.g. `MAX_HANDSHAKE_FRAME_LEN` until the
+    /// other side has completed the handshake. Receivers that do not read frames of their own ignore it.
+    fn set_max_frame_len(&self, _len: usize) {}
+}
+
+impl MessageSender for tokio_unix_ipc::Sender<ClientMessage> {
+    fn send(&self, message: ClientMessage) -> SendFuture<'_> {
+        Box::pin(tokio_unix_ipc::Sender::send(self, message))
+    }
+}
+
+impl MessageSender for tokio_unix_ipc::Bootstrapper {
+    fn send(&self, message: ClientMessage) -> SendFuture<'_> {
+        Box::pin(tokio_unix_ipc::Bootstrapper::send(self, message))
+    }
+}
+
+impl MessageReceiver for tokio_unix_ipc::Receiver<ClientMessage> {
+    fn recv(&self) -> RecvFuture<'_> {
+        Box::pin(tokio_unix_ipc::Receiver::recv(self))
+    }
+}
+
+/// Sends messages over a TCP stream.
+pub type TcpSender = StreamSender<tcp::OwnedWriteHalf>;
+
+/// Receives messages over a TCP stream.
+pub type TcpReceiver = StreamReceiver<tcp::OwnedReadHalf>;
+
+/// Sends messages over a Unix socket stream.
+pub type UnixSender = StreamSender<unix::OwnedWriteHalf>;
+
+/// Receives messages over a Unix socket stream.
+pub type UnixReceiver = StreamReceiver<unix::OwnedReadHalf>;
+
+/// Connects to a server that listens on the given address and returns the protocol version of the server, see
+/// `write_version`.
+///
+/// An address that contains a `/` is the path of a Unix socket, otherwise it is a TCP address.
+pub async fn connect(
+    address: &str,
+) -> io::Result<(u32, Box<dyn MessageSender>, Box<dyn MessageReceiver>)> {
+    if address.contains('/') {
+        let mut stream = UnixStream::connect(address).await?;
+        let version = read_version(&mut stream).await?;
+        let (sender, receiver) = split_unix(stream);
+        return Ok((version, Box::new(sender), Box::new(receiver)));
+    }
+
+    let mut stream = TcpStream::connect(address).await?;
+    let version = read_version(&mut stream).await?;
+    let (sender, receiver) = split_tcp(stream);
+    Ok((version, Box::new(sender), Box::new(receiver)))
+}
+
+/// Writes the protocol version to a stream before the first message, see `read_version`.
+///
+/// The version is not part of a message, so a client can check it even if it cannot read the messages of the server.
+pub async fn write_version(writer: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+    writer.write_u32(VERSION).await
+}
+
+/// Reads the protocol version that is written by `write_version`.
+pub async fn read_version(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<u32> {
+    reader.read_u32().await
+}
+
+/// Connects to a server that listens on the given TCP address.
+pub async fn connect_tcp(address: impl ToSocketAddrs) -> io::Result<(TcpSender, TcpReceiver)> {
+    Ok(split_tcp(TcpStream::connect(address).await?))
+}
+
+/// Splits a TCP stream into a sender and a receiver of messages.
+pub fn split_tcp(stream: TcpStream) -> (TcpSender, TcpReceiver) {
+    let (reader, writer) = stream.into_split();
+    (StreamSender::new(writer), StreamReceiver::new(reader))
+}
+
+/// Splits a Unix socket stream into a sender and a receiver of messages.
+pub fn split_unix(stream: UnixStream) -> (UnixSender, UnixReceiver) {
+    let (reader, writer) = stream.into_split();
+    (StreamSender::new(writer), StreamReceiver::new(reader))
+}
+
+/// Sends messages as JSON frames with a length prefix over a stream.
+#[derive(Debug)]
+pub struct StreamSender<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> StreamSender<W> {
+    /// Creates a sender that writes to the given stream.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W> StreamSender<W>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn write(&self, message: &ClientMessage) -> io::Result<()> {
+        let frame = serde_json::to_vec(message)?;
+        let mut writer = self.writer.lock().await;
+
+        writer.write_u32(frame.len() as u32).await?;
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+}
+
+impl<W> MessageSender for StreamSender<W>
+where
+    W: AsyncWrite + Debug + Send + Unpin,
+{
+    fn send(&self, message: ClientMessage) -> SendFuture<'_> {
+        Box::pin(async move { self.write(&message).await })
+    }
+}
+
+/// Sends messages over a stream from a writer task, so a peer that does not read its messages cannot block the
+/// sender.
+///
+/// Up to `capacity` messages are queued. If the queue is full the connection is closed, the writer task ends and
+/// drops the stream.
+#[derive(Debug)]
+pub struct QueuedSender {
+    queue: mpsc::Sender<ClientMessage>,
+    writer: AbortHandle,
+}
+
+impl QueuedSender {
+    /// Spawns the writer task of the given stream on the current tokio runtime.
+    pub fn spawn<W>(writer: W, capacity: usize) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (queue, mut receiver) = mpsc::channel::<ClientMessage>(capacity.max(1));
+        let sender = StreamSender::new(writer);
+
+        let writer = tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if sender.write(&message).await.is_err() {
+                    break;
+                }
+            }
+        })
+        .abort_handle();
+
+        Self { queue, writer }
+    }
+}
+
+impl MessageSender for QueuedSender {
+    fn send(&self, message: ClientMessage) -> SendFuture<'_> {
+        let result = match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.writer.abort();
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Peer does not read its messages",
+                ))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        };
+
+        Box::pin(std::future::ready(result))
+    }
+}
+
+/// Receives messages that are sent by a `StreamSender`.
+#[derive(Debug)]
+pub struct StreamReceiver<R> {
+    reader: Mutex<R>,
+    max_frame_len: AtomicUsize,
+}
+
+impl<R> StreamReceiver<R> {
+    /// Creates a receiver that reads from the given stream and accepts messages up to `MAX_FRAME_LEN`.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+            max_frame_len: AtomicUsize::new(MAX_FRAME_LEN),
+        }
+    }
+}
+
+impl<R> MessageReceiver for StreamReceiver<R>
+where
+    R: AsyncRead + Debug + Send + Unpin,
+{
+    fn recv(&self) -> RecvFuture<'_> {
+        Box::pin(async move {
+            let mut reader = self.reader.lock().await;
+            let len = reader.read_u32().await? as usize;
+
+            if len > self.max_frame_len.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Frame of {len} bytes exceeds the maximum frame length"),
+                ));
+            }
+
+            // the frame grows with the received bytes, the announced length alone allocates nothing.
+            let mut frame = vec![];
+            (&mut *reader)
+                .take(len as u64)
+                .read_to_end(&mut frame)
+                .await?;
+
+            if frame.len() < len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            Ok(serde_json::from_slice(&frame)?)
+        })
+    }
+
+    fn set_max_frame_len(&self, len: usize) {
+        self.max_frame_len.store(len, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_tcp() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let (client, server) = tokio::join!(connect_tcp(address), listener.accept());
+            let (client_sender, client_receiver) = client.unwrap();
+            let (server_sender, server_receiver) = split_tcp(server.unwrap().0);
+
+            server_sender
+                .send(ClientMessage::Create(CreationRequest {
+                    sender: None,
+                    key: "client".into(),
+                    version: VERSION,
+                    capabilities: vec![Capability::Resize],
+                    decoration_modes: DecorationMode::all(),
+                    pixel_formats: PixelFormat::all(),
+                }))
+                .await
+                .unwrap();
+
+            match client_receiver.recv().await.unwrap() {
+                ClientMessage::Create(request) => {
+                    assert!(request.sender.is_none());
+                    assert_eq!(request.key, "client");
+                    assert_eq!(request.capabilities, vec![Capability::Resize]);
+                    assert_eq!(request.pixel_formats, PixelFormat::all());
+                }
+                m => panic!("Unexpected message {:?}", m),
+            }
+
+            client_sender
+                .send(ClientMessage::Closed("client".into()))
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                server_receiver.recv().await.unwrap(),
+                ClientMessage::Closed(key) if key == "client"
+            ));
+
+            drop(client_sender);
+            assert!(server_receiver.recv().await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_unix() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let path = std::env::temp_dir().join(format!("coop_test_{}.sock", std::process::id()));
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+            let server = async {
+                let (mut stream, _) = listener.accept().await?;
+                write_version(&mut stream).await?;
+                io::Result::Ok(stream)
+            };
+
+            let (client, server) = tokio::join!(connect(path.to_str().unwrap()), server);
+            let (version, client_sender, _) = client.unwrap();
+            let (_, server_receiver) = split_unix(server.unwrap());
+
+            // the version is read before the first message.
+            assert_eq!(version, VERSION);
+
+            client_sender
+                .send(ClientMessage::Closed("client".into()))
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                server_receiver.recv().await.unwrap(),
+                ClientMessage::Closed(key) if key == "client"
+            ));
+
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (client, server) = UnixStream::pair().unwrap();
+            let (client_sender, _) = split_unix(client);
+            let (_, server_receiver) = split_unix(server);
+            server_receiver.set_max_frame_len(MAX_HANDSHAKE_FRAME_LEN);
+
+            client_sender
+                .send(ClientMessage::Closed("client".into()))
+                .await
+                .unwrap();
+            assert!(server_receiver.recv().await.is_ok());
+
+            client_sender
+                .send(ClientMessage::Closed("c".repeat(MAX_HANDSHAKE_FRAME_LEN)))
+                .await
+                .unwrap();
+            assert_eq!(
+                server_receiver.recv().await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        });
+    }
+
+    #[test]
+    fn test_queued_sender() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (client, server) = UnixStream::pair().unwrap();
+            let (_, client_receiver) = split_unix(client);
+            let sender = QueuedSender::spawn(server.into_split().1, 2);
+
+            sender
+                .send(ClientMessage::Closed("client".into()))
+                .await
+                .unwrap();
+            assert!(matches!(
+                client_receiver.recv().await.unwrap(),
+                ClientMessage::Closed(key) if key == "client"
+            ));
+
+            // a peer that does not read loses its connection instead of blocking the sender.
+            let mut result = Ok(());
+
+            for _ in 0..64 {
+                result = sender
+                    .send(ClientMessage::Closed("c".repeat(1024 * 1024)))
+                    .await;
+
+                if result.is_err() {
+                    break;
+                }
+
+                tokio::task::yield_now().await;
+            }
+
+            assert!(result.is_err());
+            assert!(sender
+                .send(ClientMessage::Closed("client".into()))
+                .await
+                .is_err());
+        });
+    }
+}
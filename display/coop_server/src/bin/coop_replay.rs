@@ -0,0 +1,77 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+//! Replays a recorded session into a client.
+//!
+//! Usage: `coop_replay <recording> <client> [recorded client key]`
+//!
+//! Opens the client, sends it the recorded events of the recorded client with their recorded timing and closes
+//! it afterwards. Without a key the first client of the recording is replayed.
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+use coop_protocol::*;
+use coop_server::recorder::replay;
+use coop_server::Server;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (Some(recording), Some(client)) = (args.first(), args.get(1)) else {
+        eprintln!("Usage: coop_replay <recording> <client> [recorded client key]");
+        process::exit(1);
+    };
+
+    let records = File::open(recording)
+        .and_then(|file| read_records(BufReader::new(file)))
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot read recording {recording}: {e}");
+            process::exit(1);
+        });
+
+    let Some(from) = args.get(2).cloned().or_else(|| first_client(&records)) else {
+        eprintln!("The recording {recording} contains no client.");
+        process::exit(1);
+    };
+
+    let mut server = Server::new();
+    let proxy = server.proxy();
+    proxy.open_client(client.as_str());
+
+    // the events are replayed after the client is created, like they are recorded.
+    let to = loop {
+        match server.client_response() {
+            Some(ClientResponse {
+                key,
+                message: ClientResponseMessage::Created(_),
+            }) => break key,
+            Some(ClientResponse {
+                message: ClientResponseMessage::Crashed { .. } | ClientResponseMessage::AllClosed,
+                ..
+            })
+            | None => {
+                eprintln!("Cannot open client {client}.");
+                process::exit(1);
+            }
+            Some(_) => {}
+        }
+    };
+
+    replay(&proxy, &records, &from, &to);
+
+    server.join().expect("Cannot join server.");
+}
+
+// returns the key of the first client that is created in the recording.
+fn first_client(records: &[Record]) -> Option<String> {
+    records.iter().find_map(|record| match &record.message {
+        RecordMessage::Response(ClientResponse {
+            key,
+            message: ClientResponseMessage::Created(_),
+        }) => Some(key.clone()),
+        _ => None,
+    })
+}
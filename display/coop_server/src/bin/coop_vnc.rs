@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+//! Runs clients without a window system and shows them to VNC viewers.
+//!
+//! Usage: `coop_vnc <address> <width>x<height> <client>...`
+//!
+//! Opens the clients and serves the composed display on the given TCP address, e.g. `0.0.0.0:5900`. It exits
+//! when all clients are closed.
+
+use std::env;
+use std::process;
+
+use coop_server::vnc::VncServer;
+use coop_server::Server;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (Some(address), Some((width, height)), Some(clients)) = (
+        args.first(),
+        args.get(1).and_then(|size| parse_size(size)),
+        args.get(2..).filter(|clients| !clients.is_empty()),
+    ) else {
+        eprintln!("Usage: coop_vnc <address> <width>x<height> <client>...");
+        process::exit(1);
+    };
+
+    let mut server = Server::new();
+
+    let vnc =
+        VncServer::bind(address.as_str(), width, height, server.proxy()).unwrap_or_else(|e| {
+            eprintln!("Cannot listen on {address}: {e}");
+            process::exit(1);
+        });
+
+    for client in clients {
+        server.proxy().open_client(client.as_str());
+    }
+
+    vnc.run(&mut server);
+
+    server.join().expect("Cannot join server.");
+}
+
+// parses a size like `800x600`.
+fn parse_size(size: &str) -> Option<(u16, u16)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
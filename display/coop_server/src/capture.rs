@@ -0,0 +1,409 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use coop_protocol::*;
+
+use crate::window_manager::Geometry;
+
+/// Captured frame with pixels of the format `PixelFormat::Rgba8888` without padding.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    /// Width in pixels.
+    pub width: u32,
+
+    /// Height in pixels.
+    pub height: u32,
+
+    /// Pixels row by row.
+    pub buffer: Vec<u8>,
+}
+
+impl Image {
+    /// Creates a new transparent image.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            buffer: vec![0; PixelFormat::Rgba8888.stride(width) * height as usize],
+        }
+    }
+
+    /// Returns the pixel at the given position or `None` if the position is outside of the image.
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let start = (y as usize * self.width as usize + x as usize) * 4;
+        Some(PixelFormat::Rgba8888.to_rgba(&self.buffer[start..start + 4]))
+    }
+
+    /// Draws the given image with its top left corner at the given position over this image.
+    pub fn draw_over(&mut self, image: &Image, x: i64, y: i64) {
+        for row in 0..image.height as i64 {
+            let target_y = y + row;
+
+            if target_y < 0 || target_y >= self.height as i64 {
+                continue;
+            }
+
+            for column in 0..image.width as i64 {
+                let target_x = x + column;
+
+                if target_x < 0 || target_x >= self.width as i64 {
+                    continue;
+                }
+
+                let source = (row * image.width as i64 + column) as usize * 4;
+                let target = (target_y * self.width as i64 + target_x) as usize * 4;
+
+                blend(
+                    &mut self.buffer[target..target + 4],
+                    &image.buffer[source..source + 4],
+                );
+            }
+        }
+    }
+
+    /// Draws a rectangle of the given color over this image, parts outside of the image are cut off.
+    pub fn fill_rect(&mut self, x: i64, y: i64, width: u32, height: u32, color: [u8; 4]) {
+        let (left, top) = (x.max(0), y.max(0));
+        let right = (x + width as i64).min(self.width as i64);
+        let bottom = (y + height as i64).min(self.height as i64);
+
+        for row in top..bottom {
+            for column in left..right {
+                let target = (row * self.width as i64 + column) as usize * 4;
+                blend(&mut self.buffer[target..target + 4], &color);
+            }
+        }
+    }
+
+    /// Encodes the image as PNG.
+    pub fn encode_png(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.buffer))
+            .map_err(io::Error::other)
+    }
+
+    /// Saves the image as PNG file on the given path.
+    pub fn save_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.encode_png(BufWriter::new(File::create(path)?))
+    }
+}
+
+/// Composes the given images from bottom to top into an image of the given size, e.g. the windows of a display.
+///
+/// Each image is drawn at the position of its geometry, parts outside of the composed image are cut off.
+pub fn compose<'a>(
+    width: u32,
+    height: u32,
+    layers: impl IntoIterator<Item = (&'a Image, Geometry)>,
+) -> Image {
+    let mut image = Image::new(width, height);
+
+    for (layer, geometry) in layers {
+        image.draw_over(layer, geometry.x.round() as i64, geometry.y.round() as i64);
+    }
+
+    image
+}
+
+// draws the non premultiplied source pixel over the target pixel.
+fn blend(target: &mut [u8], source: &[u8]) {
+    let alpha = source[3] as u32;
+
+    if alpha == 255 {
+        target.copy_from_slice(source);
+        return;
+    }
+
+    let target_alpha = target[3] as u32 * (255 - alpha) / 255;
+    let out_alpha = alpha + target_alpha;
+
+    if out_alpha == 0 {
+        target.copy_from_slice(&[0; 4]);
+        return;
+    }
+
+    for channel in 0..3 {
+        target[channel] = ((source[channel] as u32 * alpha + target[channel] as u32 * target_alpha)
+            / out_alpha) as u8;
+    }
+
+    target[3] = out_alpha as u8;
+}
+
+/// Keeps the latest frame of each window, so it can be captured at any time.
+///
+/// The store is shared by the server and all client loops, damaged regions are applied to the stored frame. Frames
+/// are converted to `Image` on the first capture after an update, later captures share the converted image.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStore {
+    frames: Arc<Mutex<HashMap<String, Frame>>>,
+}
+
+// frame buffer in the format of the client.
+#[derive(Debug)]
+struct Frame {
+    buffer: Vec<u8>,
+    width: u32,
+    stride: usize,
+    format: PixelFormat,
+    image: Option<Arc<Image>>,
+}
+
+impl FrameStore {
+    /// Creates a new empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the frame of the window with the given key by a draw response.
+    ///
+    /// Damaged regions are dropped if there is no previous frame with the same layout to apply them to.
+    pub fn update(&self, key: &str, draw: &DrawResponse) {
+        let mut frames = self.lock();
+        let width = draw.width as u32;
+
+        if draw.damage.is_empty() {
+            // the buffer of the previous frame is reused, the draw response is forwarded to the server app.
+            let frame = frames.entry(key.into()).or_insert_with(|| Frame {
+                buffer: vec![],
+                width,
+                stride: draw.stride,
+                format: draw.format,
+                image: None,
+            });
+
+            frame.buffer.clear();
+            frame.buffer.extend_from_slice(&draw.buffer);
+            frame.width = width;
+            frame.stride = draw.stride;
+            frame.format = draw.format;
+            frame.image = None;
+            return;
+        }
+
+        if let Some(frame) = frames
+            .get_mut(key)
+            .filter(|f| f.width == width && f.stride == draw.stride && f.format == draw.format)
+        {
+            frame.image = None;
+            unpack_regions(
+                &mut frame.buffer,
+                frame.stride,
+                frame.format,
+                &draw.damage,
+                &draw.buffer,
+            );
+        }
+    }
+
+    /// Removes the frame of the window with the given key.
+    pub fn remove(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    /// Removes the frames of the main window and of the additional windows of the client with the given key.
+    pub fn remove_client(&self, client_key: &str) {
+        self.lock()
+            .retain(|key, _| !crate::is_window_of(client_key, key));
+    }
+
+    /// Returns the latest frame of the window with the given key.
+    pub fn capture(&self, key: &str) -> Option<Arc<Image>> {
+        self.lock().get_mut(key).map(|frame| {
+            frame
+                .image
+                .get_or_insert_with(|| {
+                    let buffer =
+                        convert_to_rgba8(&frame.buffer, frame.width, frame.stride, frame.format);
+
+                    Arc::new(Image {
+                        width: frame.width,
+                        height: buffer_height(
+                            buffer.len(),
+                            PixelFormat::Rgba8888.stride(frame.width),
+                        ),
+                        buffer,
+                    })
+                })
+                .clone()
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Frame>> {
+        // the frames are still usable if another thread has panicked while holding the lock.
+        self.frames.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(buffer: Vec<u8>, width: f32, height: f32, damage: Vec<DamageRegion>) -> DrawResponse {
+        DrawResponse {
+            buffer,
+            width,
+            height,
+            damage,
+            format: PixelFormat::Bgra8888,
+            stride: PixelFormat::Bgra8888.stride(width as u32),
+        }
+    }
+
+    #[test]
+    fn test_frame_store() {
+        let frames = FrameStore::new();
+        assert!(frames.capture("window").is_none());
+
+        frames.update(
+            "window",
+            &draw(vec![1, 2, 3, 255, 4, 5, 6, 255], 2., 1., vec![]),
+        );
+        frames.update(
+            "window",
+            &draw(
+                vec![7, 8, 9, 255],
+                2.,
+                1.,
+                vec![DamageRegion::new(1, 0, 1, 1)],
+            ),
+        );
+
+        let image = frames.capture("window").unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.buffer, vec![3, 2, 1, 255, 9, 8, 7, 255]);
+
+        // the converted frame is shared until the next update.
+        assert!(Arc::ptr_eq(&image, &frames.capture("window").unwrap()));
+        frames.update(
+            "window",
+            &draw(
+                vec![0, 0, 0, 255],
+                2.,
+                1.,
+                vec![DamageRegion::new(0, 0, 1, 1)],
+            ),
+        );
+        assert_eq!(
+            frames.capture("window").unwrap().buffer,
+            vec![0, 0, 0, 255, 9, 8, 7, 255]
+        );
+
+        frames.remove("window");
+        assert!(frames.capture("window").is_none());
+
+        frames.update("client", &draw(vec![0; 4], 1., 1., vec![]));
+        frames.update("client:0", &draw(vec![0; 4], 1., 1., vec![]));
+        frames.update("client_1", &draw(vec![0; 4], 1., 1., vec![]));
+        frames.remove_client("client");
+
+        assert!(frames.capture("client").is_none());
+        assert!(frames.capture("client:0").is_none());
+        assert!(frames.capture("client_1").is_some());
+    }
+
+    #[test]
+    fn test_compose() {
+        let red = Image {
+            width: 2,
+            height: 2,
+            buffer: [255, 0, 0, 255].repeat(4),
+        };
+        let blue = Image {
+            width: 2,
+            height: 2,
+            buffer: [0, 0, 255, 255].repeat(4),
+        };
+
+        // the blue image is on top and partly outside of the composed image.
+        let image = compose(
+            3,
+            2,
+            [
+                (&red, Geometry::new(0., 0., 2., 2.)),
+                (&blue, Geometry::new(2., 1., 2., 2.)),
+            ],
+        );
+
+        assert_eq!(image.pixel(0, 0), Some([255, 0, 0, 255]));
+        assert_eq!(image.pixel(2, 0), Some([0, 0, 0, 0]));
+        assert_eq!(image.pixel(2, 1), Some([0, 0, 255, 255]));
+        assert_eq!(image.pixel(3, 1), None);
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut target = [0, 0, 255, 255];
+        blend(&mut target, &[255, 0, 0, 128]);
+        assert_eq!(target, [128, 0, 127, 255]);
+
+        let mut target = [0; 4];
+        blend(&mut target, &[255, 0, 0, 0]);
+        assert_eq!(target, [0; 4]);
+    }
+
+    #[test]
+    fn test_encode_png() {
+        let mut png = vec![];
+        Image::new(2, 1).encode_png(&mut png).unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_capture() {
+        use crate::test_support::*;
+        use crate::Server;
+
+        let mut server = Server::new();
+        let (env, client) = spawn_client(&server, "fake_client");
+        wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let image = runtime
+            .block_on(server.proxy().capture(env.client_key.clone()))
+            .unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.buffer, vec![255; 8]);
+
+        assert!(runtime.block_on(server.proxy().capture("unknown")).is_err());
+
+        let display = runtime
+            .block_on(server.proxy().capture_display(
+                3,
+                1,
+                vec![
+                    (env.client_key.clone(), Geometry::new(1., 0., 2., 1.)),
+                    ("unknown".into(), Geometry::new(0., 0., 1., 1.)),
+                ],
+            ))
+            .unwrap();
+        assert_eq!(display.pixel(0, 0), Some([0; 4]));
+        assert_eq!(display.pixel(1, 0), Some([255; 4]));
+
+        // the frame is dropped when the client is closed.
+        server.proxy().close_client(env.client_key.clone());
+        client.join().unwrap();
+        assert!(runtime
+            .block_on(server.proxy().capture(env.client_key.clone()))
+            .is_err());
+    }
+}
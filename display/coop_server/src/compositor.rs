@@ -0,0 +1,429 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+
+use crate::capture::{FrameStore, Image};
+use crate::window_manager::{Geometry, Insets, WindowManager};
+
+/// Appearance of the decorations that the `Compositor` draws around top level windows.
+///
+/// The decorations are drawn outside of the geometry of a window: the title bar above it and the border around
+/// both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoration {
+    /// Height of the title bar in pixels.
+    pub title_bar_height: u32,
+
+    /// Width of the border in pixels.
+    pub border_width: u32,
+
+    /// Color of the title bar of windows without keyboard focus.
+    pub title_bar_color: [u8; 4],
+
+    /// Color of the title bar of the window with the keyboard focus.
+    pub focused_title_bar_color: [u8; 4],
+
+    /// Color of the border.
+    pub border_color: [u8; 4],
+}
+
+impl Default for Decoration {
+    fn default() -> Self {
+        Self {
+            title_bar_height: 24,
+            border_width: 1,
+            title_bar_color: [0x3c, 0x3c, 0x3c, 0xff],
+            focused_title_bar_color: [0x1e, 0x5a, 0x96, 0xff],
+            border_color: [0x28, 0x28, 0x28, 0xff],
+        }
+    }
+}
+
+impl Decoration {
+    /// Returns the space of the decorations around a window, see `WindowManager::set_frame_insets`.
+    pub fn insets(&self) -> Insets {
+        let border = self.border_width as f32;
+
+        Insets {
+            top: self.title_bar_height as f32 + border,
+            bottom: border,
+            left: border,
+            right: border,
+        }
+    }
+}
+
+/// Window that is composed by the `Compositor`.
+#[derive(Clone, Copy, Debug)]
+pub struct Layer<'a> {
+    /// Latest frame of the window.
+    pub image: &'a Image,
+
+    /// Position and size of the window on the output.
+    pub geometry: Geometry,
+
+    /// `true` if the window gets decorations, e.g. top level windows but not popups or windows that draw their own
+    /// decorations.
+    pub decorated: bool,
+
+    /// `true` if the window has the keyboard focus.
+    pub focused: bool,
+}
+
+/// Pointer image that is drawn on top of all windows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cursor {
+    /// Image of the cursor.
+    pub image: Image,
+
+    /// Position in the image that points to the cursor position.
+    pub hotspot: (u32, u32),
+}
+
+/// Composes the frames of the windows by their stacking order into one output buffer.
+///
+/// The output is an `Image` with pixels of the format `PixelFormat::Rgba8888`, so it can be copied to a raw
+/// frame buffer, sent to a remote display or compared pixel by pixel in tests without any window system.
+#[derive(Clone, Debug)]
+pub struct Compositor {
+    output: Image,
+    background: [u8; 4],
+    decoration: Decoration,
+    cursor: Option<Cursor>,
+    cursor_position: (f32, f32),
+}
+
+impl Compositor {
+    /// Creates a compositor with an output of the given size, an opaque black background and default decorations.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            output: Image::new(width, height),
+            background: [0, 0, 0, 0xff],
+            decoration: Decoration::default(),
+            cursor: None,
+            cursor_position: (0., 0.),
+        }
+    }
+
+    /// Resizes the output, it is cleared until the next `compose`.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.output = Image::new(width, height);
+    }
+
+    /// Sets the color that is drawn behind all windows.
+    pub fn set_background(&mut self, color: [u8; 4]) {
+        self.background = color;
+    }
+
+    /// Sets the appearance of the window decorations.
+    pub fn set_decoration(&mut self, decoration: Decoration) {
+        self.decoration = decoration;
+    }
+
+    /// Returns the appearance of the window decorations.
+    pub fn decoration(&self) -> Decoration {
+        self.decoration
+    }
+
+    /// Sets the cursor image, `None` hides the cursor.
+    pub fn set_cursor(&mut self, cursor: Option<Cursor>) {
+        self.cursor = cursor;
+    }
+
+    /// Moves the cursor to the given output position.
+    pub fn move_cursor(&mut self, x: f32, y: f32) {
+        self.cursor_position = (x, y);
+    }
+
+    /// Returns the composed output of the last `compose`.
+    pub fn output(&self) -> &Image {
+        &self.output
+    }
+
+    /// Composes the given windows from bottom to top with their decorations and the cursor on top.
+    pub fn compose<'a>(&mut self, layers: impl IntoIterator<Item = Layer<'a>>) -> &Image {
+        let (width, height) = (self.output.width, self.output.height);
+        self.output.buffer.clear();
+        self.output.buffer.extend(
+            self.background
+                .iter()
+                .copied()
+                .cycle()
+                .take(width as usize * height as usize * 4),
+        );
+
+        for layer in layers {
+            let (x, y) = (
+                layer.geometry.x.round() as i64,
+                layer.geometry.y.round() as i64,
+            );
+
+            if layer.decorated {
+                self.draw_decoration(x, y, layer.image, layer.focused);
+            }
+
+            self.output.draw_over(layer.image, x, y);
+        }
+
+        if let Some(cursor) = &self.cursor {
+            let x = self.cursor_position.0.round() as i64 - cursor.hotspot.0 as i64;
+            let y = self.cursor_position.1.round() as i64 - cursor.hotspot.1 as i64;
+            self.output.draw_over(&cursor.image, x, y);
+        }
+
+        &self.output
+    }
+
+    /// Composes the latest frames of the visible windows of the window manager. Top level windows with
+    /// `DecorationMode::Server` are decorated, windows without a frame are left out.
+    ///
+    /// Frames that are not updated since the last `compose_windows` are not converted again, see `FrameStore`.
+    pub fn compose_windows(
+        &mut self,
+        frames: &FrameStore,
+        window_manager: &WindowManager,
+        focused: Option<&str>,
+    ) -> &Image {
+        let windows: Vec<(Arc<Image>, Geometry, bool, bool)> = window_manager
+            .visible_windows()
+            .into_iter()
+            .filter_map(|(key, geometry)| {
+                let decorated = window_manager
+                    .window(&key)
+                    .is_some_and(|w| w.is_decorated());
+
+                frames
+                    .capture(&key)
+                    .map(|image| (image, geometry, decorated, focused == Some(key.as_str())))
+            })
+            .collect();
+
+        self.compose(
+            windows
+                .iter()
+                .map(|(image, geometry, decorated, focused)| Layer {
+                    image,
+                    geometry: *geometry,
+                    decorated: *decorated,
+                    focused: *focused,
+                }),
+        )
+    }
+
+    // draws the border and the title bar around the window content at the given position.
+    //
+    // the sizes saturate, a decoration that does not fit is clipped by the output like a window outside of it.
+    fn draw_decoration(&mut self, x: i64, y: i64, image: &Image, focused: bool) {
+        let decoration = self.decoration;
+        let border = decoration.border_width;
+        let title_bar = decoration.title_bar_height;
+
+        self.output.fill_rect(
+            x - border as i64,
+            y - title_bar as i64 - border as i64,
+            image.width.saturating_add(border.saturating_mul(2)),
+            image
+                .height
+                .saturating_add(title_bar)
+                .saturating_add(border.saturating_mul(2)),
+            decoration.border_color,
+        );
+
+        let title_bar_color = if focused {
+            decoration.focused_title_bar_color
+        } else {
+            decoration.title_bar_color
+        };
+
+        self.output.fill_rect(
+            x,
+            y - title_bar as i64,
+            image.width,
+            title_bar,
+            title_bar_color,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window_manager::ManagedWindow;
+    use coop_protocol::{DecorationMode, DrawResponse, PixelFormat};
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    fn image(width: u32, height: u32, color: [u8; 4]) -> Image {
+        Image {
+            width,
+            height,
+            buffer: color.repeat((width * height) as usize),
+        }
+    }
+
+    fn decoration() -> Decoration {
+        Decoration {
+            title_bar_height: 2,
+            border_width: 1,
+            title_bar_color: [10, 10, 10, 255],
+            focused_title_bar_color: [20, 20, 20, 255],
+            border_color: [30, 30, 30, 255],
+        }
+    }
+
+    #[test]
+    fn test_compose_stacking_order() {
+        let mut compositor = Compositor::new(4, 4);
+        let (red, blue) = (image(2, 2, RED), image(2, 2, BLUE));
+
+        let output = compositor.compose([
+            Layer {
+                image: &red,
+                geometry: Geometry::new(0., 0., 2., 2.),
+                decorated: false,
+                focused: false,
+            },
+            Layer {
+                image: &blue,
+                geometry: Geometry::new(1., 1., 2., 2.),
+                decorated: false,
+                focused: false,
+            },
+        ]);
+
+        assert_eq!(output.pixel(0, 0), Some(RED));
+        assert_eq!(output.pixel(1, 1), Some(BLUE));
+        assert_eq!(output.pixel(3, 3), Some(BLACK));
+    }
+
+    #[test]
+    fn test_compose_alpha() {
+        let mut compositor = Compositor::new(1, 1);
+        compositor.set_background([0, 0, 255, 255]);
+        let red = image(1, 1, [255, 0, 0, 128]);
+
+        let output = compositor.compose([Layer {
+            image: &red,
+            geometry: Geometry::new(0., 0., 1., 1.),
+            decorated: false,
+            focused: false,
+        }]);
+
+        assert_eq!(output.pixel(0, 0), Some([128, 0, 127, 255]));
+    }
+
+    #[test]
+    fn test_compose_decoration() {
+        let mut compositor = Compositor::new(6, 7);
+        compositor.set_decoration(decoration());
+        let red = image(2, 2, RED);
+
+        let mut layer = Layer {
+            image: &red,
+            geometry: Geometry::new(2., 3., 2., 2.),
+            decorated: true,
+            focused: false,
+        };
+
+        let output = compositor.compose([layer]);
+        assert_eq!(output.pixel(1, 0), Some([30, 30, 30, 255]));
+        assert_eq!(output.pixel(2, 1), Some([10, 10, 10, 255]));
+        assert_eq!(output.pixel(3, 2), Some([10, 10, 10, 255]));
+        assert_eq!(output.pixel(2, 3), Some(RED));
+        assert_eq!(output.pixel(4, 5), Some([30, 30, 30, 255]));
+        assert_eq!(output.pixel(5, 6), Some(BLACK));
+
+        layer.focused = true;
+        let output = compositor.compose([layer]);
+        assert_eq!(output.pixel(2, 1), Some([20, 20, 20, 255]));
+    }
+
+    #[test]
+    fn test_compose_huge_decoration() {
+        let mut compositor = Compositor::new(2, 2);
+        compositor.set_decoration(Decoration {
+            title_bar_height: u32::MAX,
+            border_width: u32::MAX,
+            ..decoration()
+        });
+        let red = image(1, 1, RED);
+
+        // the sizes of the decoration saturate instead of overflowing.
+        let output = compositor.compose([Layer {
+            image: &red,
+            geometry: Geometry::new(1., 1., 1., 1.),
+            decorated: true,
+            focused: false,
+        }]);
+        assert_eq!(output.pixel(1, 1), Some(RED));
+    }
+
+    #[test]
+    fn test_compose_cursor() {
+        let mut compositor = Compositor::new(3, 3);
+        compositor.set_cursor(Some(Cursor {
+            image: image(1, 1, BLUE),
+            hotspot: (0, 0),
+        }));
+        compositor.move_cursor(2., 1.);
+
+        let red = image(3, 3, RED);
+        let output = compositor.compose([Layer {
+            image: &red,
+            geometry: Geometry::new(0., 0., 3., 3.),
+            decorated: false,
+            focused: false,
+        }]);
+
+        assert_eq!(output.pixel(2, 1), Some(BLUE));
+        assert_eq!(output.pixel(1, 1), Some(RED));
+    }
+
+    #[test]
+    fn test_compose_windows() {
+        let frames = FrameStore::new();
+        for (key, color) in [("one", RED), ("one:0", BLUE), ("three", RED)] {
+            frames.update(
+                key,
+                &DrawResponse {
+                    buffer: color.to_vec(),
+                    width: 1.,
+                    height: 1.,
+                    damage: vec![],
+                    format: PixelFormat::Rgba8888,
+                    stride: 4,
+                },
+            );
+        }
+
+        let mut window_manager = WindowManager::new();
+        window_manager.add_window(ManagedWindow::new("one", Geometry::new(1., 3., 1., 1.)));
+        window_manager.add_window(ManagedWindow {
+            parent: Some("one".into()),
+            ..ManagedWindow::new("one:0", Geometry::new(3., 3., 1., 1.))
+        });
+        window_manager.add_window(ManagedWindow::new("two", Geometry::new(0., 0., 1., 1.)));
+        window_manager.add_window(ManagedWindow {
+            decoration_mode: DecorationMode::Client,
+            ..ManagedWindow::new("three", Geometry::new(3., 0., 1., 1.))
+        });
+
+        let mut compositor = Compositor::new(5, 5);
+        compositor.set_decoration(decoration());
+        let output = compositor.compose_windows(&frames, &window_manager, Some("one"));
+
+        // the top level window is decorated, the popup is not and the window without frame is left out.
+        assert_eq!(output.pixel(1, 3), Some(RED));
+        assert_eq!(output.pixel(1, 1), Some([20, 20, 20, 255]));
+        assert_eq!(output.pixel(3, 3), Some(BLUE));
+        assert_eq!(output.pixel(3, 2), Some(BLACK));
+        assert_eq!(output.pixel(0, 0), Some([30, 30, 30, 255]));
+
+        // the window with client decorations is not decorated by the compositor.
+        assert_eq!(output.pixel(3, 0), Some(RED));
+        assert_eq!(output.pixel(4, 0), Some(BLACK));
+    }
+}
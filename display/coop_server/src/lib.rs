@@ -2,16 +2,42 @@
 // SPDX-License-Identifier: MIT
 
 use coop_protocol::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinSet, LocalSet};
 
+pub mod capture;
+pub mod compositor;
 pub mod proxy;
+pub mod recorder;
 pub mod result;
+pub mod vnc;
+pub mod window_manager;
+
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 use result::*;
 
+/// Refresh rate in frames per second that is used by `Server::new`.
+pub const DEFAULT_REFRESH_RATE: u32 = 60;
+
+/// Time a client has to close itself after the server has requested it before it is ended.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time a client process has to exit after its connection is lost before it is killed.
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Messages that are queued for an attached client, its connection is closed if it does not read them.
+pub const ATTACHED_QUEUE_LEN: usize = 1024;
+
 /// `Server` is used to work with `co_clients` and handle the communication between window manager and the clients.
 pub struct Server {
     proxy: proxy::ServerProxy,
@@ -23,14 +49,31 @@ pub struct Server {
 impl Server {
     /// Creates a new server with default settings.
     pub fn new() -> Self {
+        Self::with_refresh_rate(DEFAULT_REFRESH_RATE)
+    }
+
+    /// Creates a new server that paces the frames of its clients to the given frames per second.
+    pub fn with_refresh_rate(refresh_rate: u32) -> Self {
+        let frame_interval = Duration::from_secs(1) / refresh_rate.max(1);
         let (proxy_sender, proxy_receiver) = mpsc::unbounded_channel();
         let (client_response_sender, client_response_receiver) = mpsc::unbounded_channel();
 
-        let server_loop = thread::spawn(move || {
-            tokio::runtime::Runtime::new()
-                .unwrap()
-                .block_on(server_loop(proxy_receiver, client_response_sender))
-                .expect("Crash on server loop.")
+        let server_loop = thread::spawn({
+            let proxy_sender = proxy_sender.clone();
+            move || {
+                // the handshakes with new clients run as local tasks of the server loop.
+                LocalSet::new()
+                    .block_on(
+                        &tokio::runtime::Runtime::new().unwrap(),
+                        server_loop(
+                            proxy_receiver,
+                            proxy_sender,
+                            client_response_sender,
+                            frame_interval,
+                        ),
+                    )
+                    .expect("Crash on server loop.")
+            }
         });
 
         Self {
@@ -64,87 +107,1658 @@ impl Server {
 
 async fn server_loop(
     mut proxy_receiver: mpsc::UnboundedReceiver<proxy::ServerProxyMessage>,
+    proxy_sender: mpsc::UnboundedSender<proxy::ServerProxyMessage>,
     client_response_sender: mpsc::UnboundedSender<ClientResponse>,
+    frame_interval: Duration,
 ) -> ServerResult<()> {
     let mut client_proxy_pool = proxy::ClientProxyPool::new();
-    let mut client_loops: HashMap<String, thread::JoinHandle<()>> = HashMap::new();
-
-    'server_loop: loop {
-        if let Some(message) = proxy_receiver.recv().await {
-            match message {
-                proxy::ServerProxyMessage::SendEvent { key, event } => {
-                    client_proxy_pool.send_event(&key, event).await
-                }
-                proxy::ServerProxyMessage::OpenClient(path) => {
-                    let (key, client_receiver) = client_proxy_pool.open(path).await?;
-
-                    let client_loop = thread::spawn({
-                        let client_response_sender = client_response_sender.clone();
-                        move || {
-                            tokio::runtime::Runtime::new()
-                                .unwrap()
-                                .block_on(client_loop(client_response_sender, client_receiver));
+    let mut client_loops: HashMap<String, ClientLoop> = HashMap::new();
+    let mut restart_policies: HashMap<
+        String,
+        (proxy::ClientCommand, proxy::RestartPolicy, Vec<Permission>),
+    > = HashMap::new();
+
+    // clipboard that is shared by all clients.
+    let mut clipboard: Option<String> = None;
+
+    // key of the window that gets the key events.
+    let mut focused: Option<String> = None;
+
+    // interval to check if clients have terminated and to write the buffered records of a recording.
+    let mut supervision = tokio::time::interval(Duration::from_millis(100));
+
+    // windows that have drawn since the last frame, they get a frame callback on the next frame.
+    let mut drawn: HashSet<String> = HashSet::new();
+    let mut frames = tokio::time::interval(frame_interval);
+    frames.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    // clients that connect to a listener of the server, see `ServerProxy::listen`.
+    let (attach_sender, mut attach_receiver) = mpsc::unbounded_channel::<AttachedClient>();
+
+    // handshakes with started clients, they run off the server loop so a slow client cannot stall it.
+    let mut handshakes: JoinSet<(ServerResult<proxy::OpenedClient>, OpeningClient)> =
+        JoinSet::new();
+
+    // Unix sockets of the listeners, they are removed when the server is closed.
+    let mut socket_paths: Vec<String> = vec![];
+
+    // `true` if the server is requested to close.
+    let mut closing = false;
+
+    loop {
+        if closing && handshakes.is_empty() {
+            client_proxy_pool.request_close_all().await;
+            break;
+        }
+
+        tokio::select! {
+            Some(message) = proxy_receiver.recv() => {
+                match message {
+                    proxy::ServerProxyMessage::SendEvent { key, event } => {
+                        client_proxy_pool.send_event(&key, event).await
+                    }
+                    proxy::ServerProxyMessage::SendFocusedEvent(event) => {
+                        if let Some(key) = &focused {
+                            client_proxy_pool.send_event(key, event).await;
+                        }
+                    }
+                    proxy::ServerProxyMessage::SetFocus(key) => {
+                        if key != focused {
+                            if let Some(key) = &focused {
+                                client_proxy_pool.send_event(key, ClientEvent::FocusOut).await;
+                            }
+
+                            if let Some(key) = &key {
+                                client_proxy_pool.send_event(key, ClientEvent::FocusIn).await;
+                            }
+
+                            focused = key;
                         }
-                    });
+                    }
+                    proxy::ServerProxyMessage::OpenClient {
+                        command,
+                        restart_policy,
+                        permissions,
+                        requester,
+                    } => {
+                        let result = client_proxy_pool.open(&command, permissions.clone());
+                        let opening = OpeningClient {
+                            key: result.as_ref().map(|c| c.key().into()).unwrap_or_default(),
+                            path: command.path.clone(),
+                            restart_policy: Some((command, restart_policy, permissions)),
+                            requester,
+                        };
 
-                    client_loops.insert(key, client_loop);
+                        match result {
+                            Ok(client) => {
+                                handshakes.spawn_local(async move { (client.connect().await, opening) });
+                            }
+                            Err(e) => {
+                                client_opening_failed(
+                                    &mut client_proxy_pool,
+                                    &client_response_sender,
+                                    opening,
+                                    e,
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    proxy::ServerProxyMessage::OpenInProcessClient {
+                        path,
+                        permissions,
+                        env_sender,
+                    } => {
+                        // the caller gets an error when `env_sender` is dropped.
+                        let result = client_proxy_pool.open_with(&path, permissions, move |env| {
+                            env_sender.send(env.clone()).map_err(|_| {
+                                ServerError::CannotOpenClient("Cannot send client env".into())
+                            })?;
+
+                            Ok(None)
+                        });
+                        let opening = OpeningClient {
+                            key: result.as_ref().map(|c| c.key().into()).unwrap_or_default(),
+                            path,
+                            restart_policy: None,
+                            requester: None,
+                        };
+
+                        match result {
+                            Ok(client) => {
+                                handshakes.spawn_local(async move { (client.connect().await, opening) });
+                            }
+                            Err(e) => {
+                                client_opening_failed(
+                                    &mut client_proxy_pool,
+                                    &client_response_sender,
+                                    opening,
+                                    e,
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    proxy::ServerProxyMessage::Listen {
+                        address,
+                        token,
+                        permissions,
+                        address_sender,
+                    } => {
+                        let result = listen(address, token, permissions, attach_sender.clone())
+                            .await
+                            .map_err(|e| ServerError::CannotListen(e.to_string()));
+
+                        match &result {
+                            Ok(address) if address.contains('/') => {
+                                socket_paths.push(address.clone())
+                            }
+                            _ => {}
+                        }
+
+                        address_sender.send(result).ok();
+                    }
+                    proxy::ServerProxyMessage::Record {
+                        path,
+                        result_sender,
+                    } => {
+                        let recorder = client_proxy_pool.recorder();
+                        let result = match path {
+                            Some(path) => recorder.start(path),
+                            None => recorder.stop(),
+                        };
+
+                        result_sender
+                            .send(result.map_err(|e| ServerError::CannotRecord(e.to_string())))
+                            .ok();
+                    }
+                    proxy::ServerProxyMessage::Capture { key, image_sender } => {
+                        let image = client_proxy_pool
+                            .frames()
+                            .capture(&key)
+                            .map(Arc::unwrap_or_clone)
+                            .ok_or_else(|| {
+                                ServerError::CannotCapture(format!("No frame of window {key}"))
+                            });
+
+                        image_sender.try_send(image).ok();
+                    }
+                    proxy::ServerProxyMessage::CaptureDisplay {
+                        width,
+                        height,
+                        windows,
+                        image_sender,
+                    } => {
+                        // windows without a frame are not drawn yet, they are left out.
+                        let frames = client_proxy_pool.frames();
+                        let images: Vec<(Arc<capture::Image>, window_manager::Geometry)> =
+                            windows
+                            .into_iter()
+                            .filter_map(|(key, geometry)| {
+                                frames.capture(&key).map(|image| (image, geometry))
+                            })
+                            .collect();
+
+                        image_sender
+                            .try_send(Ok(capture::compose(
+                                width,
+                                height,
+                                images.iter().map(|(image, geometry)| (&**image, *geometry)),
+                            )))
+                            .ok();
+                    }
+                    proxy::ServerProxyMessage::FrameDrawn(key) => {
+                        drawn.insert(key);
+                    }
+                    proxy::ServerProxyMessage::AddWindow { client, key, parent } => {
+                        client_proxy_pool.add_window(&client, key, parent);
+                    }
+                    proxy::ServerProxyMessage::RemoveWindow { client, key } => {
+                        let children = client_proxy_pool.remove_window(&client, &key);
+
+                        // child windows like popups are closed with their parent.
+                        for child in &children {
+                            client_proxy_pool
+                                .send_window_event(&client, child, ClientEvent::Close)
+                                .await;
+                        }
+
+                        if focused
+                            .as_ref()
+                            .is_some_and(|f| *f == key || children.contains(f))
+                        {
+                            focused = None;
+                        }
+
+                        send_windows_closed(
+                            &client_response_sender,
+                            std::iter::once(key).chain(children),
+                        );
+                    }
+                    proxy::ServerProxyMessage::SetClipboardText { text, source } => {
+                        client_proxy_pool
+                            .broadcast_event(
+                                ClientEvent::ClipboardChanged(text.clone()),
+                                source.as_ref(),
+                            )
+                            .await;
+                        clipboard = Some(text);
+                    }
+                    proxy::ServerProxyMessage::CloseClient(key) => {
+                        if focused.as_ref().is_some_and(|f| is_window_of(&key, f)) {
+                            focused = None;
+                        }
+
+                        client_proxy_pool.request_close(&key).await;
+                        send_windows_closed(
+                            &client_response_sender,
+                            client_proxy_pool.windows(&key),
+                        );
+
+                        // the client loop is already gone if the client has crashed.
+                        tokio::spawn(end_client(
+                            client_loops.remove(&key),
+                            client_proxy_pool.take_client(&key),
+                        ));
+                        restart_policies.remove(&key);
+                    }
+                    // clients that are still connecting are closed after their handshake.
+                    proxy::ServerProxyMessage::Close => closing = true,
                 }
-                proxy::ServerProxyMessage::CloseClient(key) => {
-                    client_proxy_pool.request_close(&key).await;
-                    client_loops
-                        .remove(&key)
-                        .expect("Cannot reach client loop")
-                        .join()
-                        .expect("Cannot join client loop.");
-                    client_proxy_pool.kill_client(&key);
+            }
+            Some(handshake) = handshakes.join_next(), if !handshakes.is_empty() => {
+                let Ok((result, opening)) = handshake else {
+                    continue;
+                };
+
+                let client = match result {
+                    Ok(client) => client,
+                    Err(e) => {
+                        client_opening_failed(
+                            &mut client_proxy_pool,
+                            &client_response_sender,
+                            opening,
+                            e,
+                        )
+                        .await;
+                        continue;
+                    }
+                };
+
+                let connection = client_proxy_pool.add(client).await;
+                let key = connection.key.clone();
+
+                client_loops.insert(
+                    key.clone(),
+                    spawn_client_loop(
+                        connection,
+                        proxy_sender.clone(),
+                        client_response_sender.clone(),
+                    ),
+                );
+
+                if let Some(text) = &clipboard {
+                    client_proxy_pool
+                        .send_event(&key, ClientEvent::ClipboardChanged(text.clone()))
+                        .await;
+                }
+
+                if let Some(restart_policy) = opening.restart_policy {
+                    restart_policies.insert(key, restart_policy);
                 }
-                proxy::ServerProxyMessage::Close => {
-                    client_proxy_pool.request_close_all().await;
-                    break 'server_loop;
+            }
+            Some(client) = attach_receiver.recv() => {
+                // a failed handshake only drops the connection.
+                let Ok(connection) = client_proxy_pool
+                    .attach(
+                        client.name,
+                        client.sender,
+                        client.receiver,
+                        client.token,
+                        client.permissions,
+                    )
+                    .await
+                else {
+                    continue;
+                };
+                let key = connection.key.clone();
+
+                if let Some(text) = &clipboard {
+                    client_proxy_pool
+                        .send_event(&key, ClientEvent::ClipboardChanged(text.clone()))
+                        .await;
+                }
+
+                client_loops.insert(
+                    key,
+                    spawn_client_loop(
+                        connection,
+                        proxy_sender.clone(),
+                        client_response_sender.clone(),
+                    ),
+                );
+            }
+            _ = frames.tick(), if !drawn.is_empty() => {
+                for key in drawn.drain() {
+                    client_proxy_pool.send_event(&key, ClientEvent::FrameDone).await;
+                }
+            }
+            _ = supervision.tick() => {
+                // records are written even if no further record arrives.
+                client_proxy_pool.recorder().flush();
+
+                let terminated: Vec<String> = client_loops
+                    .iter()
+                    .filter(|(_, client_loop)| client_loop.thread.is_finished())
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                for key in terminated {
+                    if focused.as_ref().is_some_and(|f| is_window_of(&key, f)) {
+                        focused = None;
+                    }
+
+                    // the loop has finished, it is joined without blocking.
+                    let closed = client_loops
+                        .remove(&key)
+                        .map(|client_loop| client_loop.thread.join().unwrap_or_default())
+                        .unwrap_or_default();
+                    send_windows_closed(
+                        &client_response_sender,
+                        client_proxy_pool.windows(&key),
+                    );
+
+                    tokio::spawn(reap_client(
+                        key.clone(),
+                        client_proxy_pool.take_client(&key),
+                        closed,
+                        restart_policies.remove(&key),
+                        proxy_sender.clone(),
+                        client_response_sender.clone(),
+                    ));
                 }
             }
         }
     }
 
-    for (_, client_loop) in client_loops {
-        client_loop.join().expect("Cannot join client loop close");
+    let ends: Vec<_> = client_loops
+        .into_values()
+        .map(|client_loop| tokio::spawn(client_loop.end(CLOSE_TIMEOUT)))
+        .collect();
+
+    for end in ends {
+        end.await.ok();
     }
 
     client_proxy_pool.kill_all();
+    client_proxy_pool.recorder().stop().ok();
+
+    for path in socket_paths {
+        std::fs::remove_file(path).ok();
+    }
 
     client_response_sender
         .send(ClientResponse {
             key: String::default(),
             message: ClientResponseMessage::AllClosed,
         })
-        .expect("Cannot send all closed message");
+        .ok();
 
     Ok(())
 }
 
-async fn client_loop(
+/// Client that is started by the server loop and connects off the loop, see `proxy::PendingClient::connect`.
+struct OpeningClient {
+    key: String,
+    path: String,
+    restart_policy: Option<(proxy::ClientCommand, proxy::RestartPolicy, Vec<Permission>)>,
+
+    // client that has requested to open the client, it is notified if the client cannot be opened.
+    requester: Option<String>,
+}
+
+/// Reports a client that cannot be opened or does not connect.
+async fn client_opening_failed(
+    client_proxy_pool: &mut proxy::ClientProxyPool,
+    client_response_sender: &mpsc::UnboundedSender<ClientResponse>,
+    opening: OpeningClient,
+    error: ServerError,
+) {
+    client_proxy_pool.remove_pending(&opening.key);
+    client_response_sender
+        .send(ClientResponse {
+            key: opening.key,
+            message: ClientResponseMessage::OpenClientFailed {
+                path: opening.path.clone(),
+                error: format!("{error:?}"),
+            },
+        })
+        .ok();
+
+    if let Some(requester) = &opening.requester {
+        client_proxy_pool
+            .send_event(requester, ClientEvent::OpenClientFailed(opening.path))
+            .await;
+    }
+}
+
+/// Client that has connected to a listener of the server.
+struct AttachedClient {
+    name: String,
+    sender: Box<dyn MessageSender>,
+    receiver: Box<dyn MessageReceiver>,
+    token: Option<String>,
+    permissions: Vec<Permission>,
+}
+
+impl AttachedClient {
+    // peers that have not completed the handshake can only send small messages.
+    fn new(
+        name: String,
+        sender: QueuedSender,
+        receiver: impl MessageReceiver + 'static,
+        token: Option<String>,
+        permissions: Vec<Permission>,
+    ) -> Self {
+        receiver.set_max_frame_len(MAX_HANDSHAKE_FRAME_LEN);
+
+        Self {
+            name,
+            sender: Box::new(sender),
+            receiver: Box::new(receiver),
+            token,
+            permissions,
+        }
+    }
+}
+
+/// Binds the given address and forwards the clients that connect to it. Returns the bound address.
+async fn listen(
+    address: String,
+    token: Option<String>,
+    permissions: Vec<Permission>,
+    attach_sender: mpsc::UnboundedSender<AttachedClient>,
+) -> io::Result<String> {
+    if address.contains('/') {
+        let listener = UnixListener::bind(&address)?;
+
+        tokio::spawn({
+            let address = address.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    if write_version(&mut stream).await.is_err() {
+                        continue;
+                    }
+
+                    let (reader, writer) = stream.into_split();
+                    let client = AttachedClient::new(
+                        address.clone(),
+                        QueuedSender::spawn(writer, ATTACHED_QUEUE_LEN),
+                        UnixReceiver::new(reader),
+                        token.clone(),
+                        permissions.clone(),
+                    );
+
+                    if attach_sender.send(client).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        return Ok(address);
+    }
+
+    let listener = TcpListener::bind(&address).await?;
+    let local_address = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            // the client checks the version before it reads the first message.
+            if write_version(&mut stream).await.is_err() {
+                continue;
+            }
+
+            let (reader, writer) = stream.into_split();
+            let client = AttachedClient::new(
+                peer.to_string(),
+                QueuedSender::spawn(writer, ATTACHED_QUEUE_LEN),
+                TcpReceiver::new(reader),
+                token.clone(),
+                permissions.clone(),
+            );
+
+            if attach_sender.send(client).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(local_address.to_string())
+}
+
+/// Returns `true` if `key` is the key of the main window or of an additional window of the given client.
+pub(crate) fn is_window_of(client_key: &str, key: &str) -> bool {
+    // keys of additional windows of the client start with the client key.
+    key.strip_prefix(client_key)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+}
+
+/// Compares the given tokens in constant time, the time of a failed authentication does not tell how much of the
+/// token is right.
+fn tokens_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Returns the permission a client needs to send the given message.
+fn required_permission(message: &ClientResponseMessage) -> Option<Permission> {
+    match message {
+        ClientResponseMessage::SetClipboardText(_) => Some(Permission::Clipboard),
+        ClientResponseMessage::SetState(WindowState::Fullscreen) => Some(Permission::Fullscreen),
+        ClientResponseMessage::OpenClient(_) => Some(Permission::SpawnClients),
+        _ => None,
+    }
+}
+
+/// Reports the windows with the given keys as closed.
+fn send_windows_closed(
+    client_response_sender: &mpsc::UnboundedSender<ClientResponse>,
+    keys: impl IntoIterator<Item = String>,
+) {
+    for key in keys {
+        client_response_sender
+            .send(ClientResponse {
+                key,
+                message: ClientResponseMessage::WindowClosed,
+            })
+            .ok();
+    }
+}
+
+/// Closes the connection of a client from its client loop and reports the client as closed.
+fn close_connection(
+    client_key: &str,
+    proxy_sender: &mpsc::UnboundedSender<proxy::ServerProxyMessage>,
+    client_response_sender: &mpsc::UnboundedSender<ClientResponse>,
+) {
+    client_response_sender
+        .send(ClientResponse {
+            key: client_key.into(),
+            message: ClientResponseMessage::Closed,
+        })
+        .ok();
+    proxy_sender
+        .send(proxy::ServerProxyMessage::CloseClient(client_key.into()))
+        .ok();
+}
+
+/// Thread that forwards the messages of a client, see `client_loop`.
+struct ClientLoop {
+    thread: thread::JoinHandle<bool>,
+
+    // ends the loop if it is sent or dropped.
+    stop: oneshot::Sender<()>,
+}
+
+impl ClientLoop {
+    /// Waits up to `timeout` for the loop to end by itself, then stops it. Returns `true` if the client is closed.
+    async fn end(self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        while !self.thread.is_finished() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        self.stop.send(()).ok();
+
+        tokio::task::spawn_blocking(move || self.thread.join().unwrap_or_default())
+            .await
+            .unwrap_or_default()
+    }
+}
+
+fn spawn_client_loop(
+    connection: proxy::ClientConnection,
+    proxy_sender: mpsc::UnboundedSender<proxy::ServerProxyMessage>,
+    client_response_sender: mpsc::UnboundedSender<ClientResponse>,
+) -> ClientLoop {
+    let (stop, stop_receiver) = oneshot::channel();
+
+    let thread = thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(client_loop(
+                connection,
+                proxy_sender,
+                client_response_sender,
+                stop_receiver,
+            ))
+    });
+
+    ClientLoop { thread, stop }
+}
+
+/// Ends a client that is requested to close after it has closed itself or `CLOSE_TIMEOUT` has passed.
+///
+/// It runs off the server loop, so a client that does not respond cannot stall the server.
+async fn end_client(client_loop: Option<ClientLoop>, proxy: Option<proxy::ClientProxy>) {
+    if let Some(client_loop) = client_loop {
+        client_loop.end(CLOSE_TIMEOUT).await;
+    }
+
+    if let Some(mut proxy) = proxy {
+        tokio::task::spawn_blocking(move || proxy.kill()).await.ok();
+    }
+}
+
+/// Reaps the process of a client whose loop has ended and reports the client as crashed unless it has closed
+/// itself. A crashed client is restarted as defined by its restart policy.
+///
+/// It runs off the server loop, the process gets `EXIT_TIMEOUT` to exit before it is killed.
+async fn reap_client(
+    key: String,
+    proxy: Option<proxy::ClientProxy>,
+    closed: bool,
+    restart_policy: Option<(proxy::ClientCommand, proxy::RestartPolicy, Vec<Permission>)>,
+    proxy_sender: mpsc::UnboundedSender<proxy::ServerProxyMessage>,
     client_response_sender: mpsc::UnboundedSender<ClientResponse>,
-    client_receiver: tokio_unix_ipc::Receiver<ClientMessage>,
 ) {
-    'client_loop: loop {
-        if let Ok(message) = client_receiver.recv().await {
-            match message {
-                ClientMessage::Create(_) => unreachable!("Client cannot send create messages"),
-                ClientMessage::Event(_) => unreachable!("Client cannot send events"),
-                ClientMessage::Response(response) => client_response_sender
-                    .send(response)
-                    .expect("Cannot send inner message."),
-                ClientMessage::Closed(key) => {
+    let status = match proxy {
+        Some(mut proxy) => tokio::task::spawn_blocking(move || {
+            let status = proxy.wait_exit(EXIT_TIMEOUT);
+            proxy.kill();
+            status
+        })
+        .await
+        .ok()
+        .flatten(),
+        None => None,
+    };
+
+    if closed {
+        return;
+    }
+
+    // a client process that has exited successfully has closed itself without saying so.
+    if status.is_some_and(|s| s.success()) {
+        client_response_sender
+            .send(ClientResponse {
+                key,
+                message: ClientResponseMessage::Closed,
+            })
+            .ok();
+
+        return;
+    }
+
+    // the server app could be already gone.
+    client_response_sender
+        .send(ClientResponse {
+            key,
+            message: ClientResponseMessage::Crashed {
+                exit_code: status.and_then(|s| s.code()),
+                signal: status.and_then(|s| s.signal()),
+            },
+        })
+        .ok();
+
+    if let Some((command, restart_policy, permissions)) = restart_policy {
+        if let Some(restart_policy) = restart_policy.next() {
+            proxy_sender
+                .send(proxy::ServerProxyMessage::OpenClient {
+                    command,
+                    restart_policy,
+                    permissions,
+                    requester: None,
+                })
+                .ok();
+        }
+    }
+}
+
+/// Forwards the messages of a client. Returns `true` if the client is closed and `false` if the connection is lost.
+async fn client_loop(
+    connection: proxy::ClientConnection,
+    proxy_sender: mpsc::UnboundedSender<proxy::ServerProxyMessage>,
+    client_response_sender: mpsc::UnboundedSender<ClientResponse>,
+    mut stop: oneshot::Receiver<()>,
+) -> bool {
+    let proxy::ClientConnection {
+        key: client_key,
+        receiver: client_receiver,
+        shared_memory,
+        token,
+        permissions,
+        recorder,
+        frames,
+    } = connection;
+    let is_own_key = |key: &String| is_window_of(&client_key, key);
+
+    // `true` if the client has negotiated `Capability::FrameCallbacks`.
+    let mut frame_callbacks = false;
+
+    // windows of the client with the keys of their parents, a popup needs a known parent so they cannot form a cycle.
+    let mut windows: HashMap<String, Option<String>> = HashMap::from([(client_key.clone(), None)]);
+
+    // a client that fails to authenticate is closed, it could impersonate the client the server has started.
+    if let Some(token) = token {
+        let message = tokio::select! {
+            message = client_receiver.recv() => message,
+            _ = &mut stop => return false,
+        };
+
+        match message {
+            Ok(ClientMessage::Authenticate(client_token))
+                if tokens_equal(&client_token, &token) => {}
+            Ok(_) => {
+                proxy_sender
+                    .send(proxy::ServerProxyMessage::SendEvent {
+                        key: client_key.clone(),
+                        event: ClientEvent::AuthenticationFailed,
+                    })
+                    .ok();
+                close_connection(&client_key, &proxy_sender, &client_response_sender);
+
+                return true;
+            }
+            Err(_) => return false,
+        }
+    }
+
+    // a failed send to the server loop means that the server is shutting down, the loop ends like it is stopped.
+    loop {
+        // the server stops the loop of a client that does not close itself when it is requested.
+        let message = tokio::select! {
+            message = client_receiver.recv() => message,
+            _ = &mut stop => return true,
+        };
+
+        let Ok(message) = message else {
+            return false;
+        };
+
+        if let ClientMessage::Response(response) = &message {
+            recorder.record_response(response);
+        }
+
+        if let ClientMessage::Response(ClientResponse { message, .. }) = &message {
+            if let Some(permission) =
+                required_permission(message).filter(|p| !permissions.contains(p))
+            {
+                if proxy_sender
+                    .send(proxy::ServerProxyMessage::SendEvent {
+                        key: client_key.clone(),
+                        event: ClientEvent::PermissionDenied(permission),
+                    })
+                    .is_err()
+                {
+                    return true;
+                }
+                continue;
+            }
+        }
+
+        match &message {
+            ClientMessage::Response(ClientResponse {
+                message: ClientResponseMessage::Created(creation),
+                ..
+            }) if creation.version != VERSION => {
+                // rejects clients with a different protocol version before anything of their handshake is applied.
+                client_response_sender
+                    .send(ClientResponse {
+                        key: client_key.clone(),
+                        message: ClientResponseMessage::OpenClientFailed {
+                            path: creation.path.clone(),
+                            error: format!(
+                                "Client has protocol version {}, the server has version {}.",
+                                creation.version, VERSION
+                            ),
+                        },
+                    })
+                    .ok();
+                proxy_sender
+                    .send(proxy::ServerProxyMessage::CloseClient(client_key.clone()))
+                    .ok();
+
+                // the client is closed by the server, it is not reported as crashed.
+                return true;
+            }
+            ClientMessage::Response(ClientResponse {
+                message: ClientResponseMessage::Created(creation),
+                ..
+            }) => {
+                frame_callbacks = creation.capabilities.contains(&Capability::FrameCallbacks);
+
+                // the handshake is completed, the client can send its frames.
+                client_receiver.set_max_frame_len(MAX_FRAME_LEN);
+            }
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::Draw(_) | ClientResponseMessage::FrameReady(_),
+            }) if frame_callbacks && is_own_key(key) => {
+                proxy_sender
+                    .send(proxy::ServerProxyMessage::FrameDrawn(key.clone()))
+                    .ok();
+            }
+            _ => {}
+        }
+
+        match message {
+            // only the server sends these messages, a client that sends them is broken.
+            ClientMessage::Create(_)
+            | ClientMessage::Event(_)
+            | ClientMessage::WindowEvent { .. } => {
+                // the server app is told by the `Closed` response.
+                close_connection(&client_key, &proxy_sender, &client_response_sender);
+
+                return true;
+            }
+            // the client is already authenticated.
+            ClientMessage::Authenticate(_) => {}
+            // only the server reports these responses.
+            ClientMessage::Response(ClientResponse {
+                message:
+                    ClientResponseMessage::OpenClientFailed { .. } | ClientResponseMessage::AllClosed,
+                ..
+            }) => {}
+            // ignores responses for windows of other clients.
+            ClientMessage::Response(ClientResponse { key, .. }) if !is_own_key(&key) => {}
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::WindowCreated(creation),
+            }) => {
+                let parent = match &creation.kind {
+                    WindowKind::TopLevel => None,
+                    WindowKind::Popup { parent, .. } => Some(parent.clone()),
+                };
+
+                if windows.contains_key(&key)
+                    || parent.as_ref().is_some_and(|p| !windows.contains_key(p))
+                {
+                    continue;
+                }
+
+                windows.insert(key.clone(), parent.clone());
+
+                if proxy_sender
+                    .send(proxy::ServerProxyMessage::AddWindow {
+                        client: client_key.clone(),
+                        key: key.clone(),
+                        parent,
+                    })
+                    .is_err()
+                {
+                    return true;
+                }
+
+                client_response_sender
+                    .send(ClientResponse {
+                        key,
+                        message: ClientResponseMessage::WindowCreated(creation),
+                    })
+                    .ok();
+            }
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::WindowClosed,
+            }) if key != client_key => {
+                // child windows are closed with their parent.
+                let mut closed = vec![key.clone()];
+
+                while let Some(closed_key) = closed.pop() {
+                    windows.remove(&closed_key);
+                    closed.extend(
+                        windows
+                            .iter()
+                            .filter(|(_, parent)| parent.as_ref() == Some(&closed_key))
+                            .map(|(child, _)| child.clone()),
+                    );
+                }
+
+                // the server reports the closed child windows.
+                if proxy_sender
+                    .send(proxy::ServerProxyMessage::RemoveWindow {
+                        client: client_key.clone(),
+                        key,
+                    })
+                    .is_err()
+                {
+                    return true;
+                }
+            }
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::FrameReady(frame),
+            }) => {
+                // reads the frame from the shared memory and forwards it as draw response.
+                if let Some(buffer) = shared_memory.as_ref().and_then(|shared_memory| {
+                    shared_memory.read(frame.len).ok().map(|buffer| {
+                        if frame.damage.is_empty() {
+                            buffer
+                        } else {
+                            pack_regions(&buffer, frame.stride, frame.format, &frame.damage)
+                        }
+                    })
+                }) {
+                    let draw = DrawResponse {
+                        buffer,
+                        width: frame.width,
+                        height: frame.height,
+                        damage: frame.damage,
+                        format: frame.format,
+                        stride: frame.stride,
+                    };
+
+                    if is_own_key(&key) {
+                        frames.update(&key, &draw);
+                    }
+
                     client_response_sender
                         .send(ClientResponse {
                             key,
-                            message: ClientResponseMessage::Closed,
+                            message: ClientResponseMessage::Draw(draw),
                         })
-                        .expect("Cannot send inner message.");
+                        .ok();
+                }
+            }
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::SetClipboardText(text),
+            }) => {
+                if proxy_sender
+                    .send(proxy::ServerProxyMessage::SetClipboardText {
+                        text: text.clone(),
+                        source: Some(key.clone()),
+                    })
+                    .is_err()
+                {
+                    return true;
+                }
 
-                    break 'client_loop;
+                // forwards the text also to the server app, e.g. to update the host clipboard.
+                client_response_sender
+                    .send(ClientResponse {
+                        key,
+                        message: ClientResponseMessage::SetClipboardText(text),
+                    })
+                    .ok();
+            }
+            // an icon whose buffer does not match its size cannot be shown.
+            ClientMessage::Response(ClientResponse {
+                message: ClientResponseMessage::SetIcon(icon),
+                ..
+            }) if !icon.is_valid() => {}
+            ClientMessage::Response(ClientResponse {
+                message: ClientResponseMessage::OpenClient(path),
+                ..
+            }) => {
+                // the opened client is granted the permissions of the client that has opened it.
+                if proxy_sender
+                    .send(proxy::ServerProxyMessage::OpenClient {
+                        command: path.into(),
+                        restart_policy: proxy::RestartPolicy::Never,
+                        permissions: permissions.clone(),
+                        requester: Some(client_key.clone()),
+                    })
+                    .is_err()
+                {
+                    return true;
                 }
             }
+            ClientMessage::Response(ClientResponse {
+                key,
+                message: ClientResponseMessage::Draw(draw),
+            }) => {
+                // keeps the frame to capture it, see `ServerProxy::capture`.
+                if is_own_key(&key) {
+                    frames.update(&key, &draw);
+                }
+
+                client_response_sender
+                    .send(ClientResponse {
+                        key,
+                        message: ClientResponseMessage::Draw(draw),
+                    })
+                    .ok();
+            }
+            ClientMessage::Response(response) => {
+                client_response_sender.send(response).ok();
+            }
+            ClientMessage::Closed(key) => {
+                client_response_sender
+                    .send(ClientResponse {
+                        key,
+                        message: ClientResponseMessage::Closed,
+                    })
+                    .ok();
+
+                return true;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ClientEnv;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_create_draw_event_close() {
+        let mut server = Server::new();
+        let (env, client) = spawn_client(&server, "fake_client");
+
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, env.client_key);
+        match response.message {
+            ClientResponseMessage::Created(c) => {
+                assert_eq!(c.title, "fake");
+                assert_eq!(c.path, "fake_client");
+                assert_eq!((c.width, c.height), (2., 1.));
+            }
+            m => panic!("Unexpected response {:?}", m),
+        }
+
+        match server.client_response().unwrap().message {
+            ClientResponseMessage::Draw(d) => {
+                assert_eq!(d.buffer, vec![255; 8]);
+                assert_eq!((d.width, d.height), (2., 1.));
+            }
+            m => panic!("Unexpected response {:?}", m),
+        }
+
+        server.proxy().send_pointer_pressed_event(
+            env.client_key.clone(),
+            1.,
+            0.,
+            PointerEventButton::Left,
+        );
+        server.proxy().close_client(env.client_key.clone());
+
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, env.client_key);
+        assert!(matches!(response.message, ClientResponseMessage::Closed));
+
+        let events = client.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            ClientEvent::PointerPressed {
+                position: (x, y),
+                button: PointerEventButton::Left
+            } if x == 1. && y == 0.
+        ));
+    }
+
+    #[test]
+    fn test_all_closed() {
+        let mut server = Server::new();
+        let (_, client_one) = spawn_client(&server, "fake_client_one");
+        let (_, client_two) = spawn_client(&server, "fake_client_two");
+
+        server.proxy().close();
+
+        let mut closed = 0;
+        wait_for(&mut server, |r| match r.message {
+            ClientResponseMessage::Closed => {
+                closed += 1;
+                false
+            }
+            ClientResponseMessage::AllClosed => true,
+            _ => false,
+        });
+
+        assert_eq!(closed, 2);
+        assert!(client_one.join().unwrap().is_empty());
+        assert!(client_two.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clipboard() {
+        let mut server = Server::new();
+        let (target, target_client) = spawn_client(&server, "fake_target");
+        let (_, source_client) = FakeClient::open(&server, "fake_source").unwrap();
+
+        source_client
+            .respond(ClientResponseMessage::SetClipboardText("copied".into()))
+            .unwrap();
+
+        let response = wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::SetClipboardText(_))
+        });
+        assert!(
+            matches!(response.message, ClientResponseMessage::SetClipboardText(text) if text == "copied")
+        );
+
+        server.proxy().close_client(target.client_key.clone());
+
+        let events = target_client.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ClientEvent::ClipboardChanged(text) if text == "copied"));
+    }
+
+    #[test]
+    fn test_windows() {
+        let mut server = Server::new();
+        let (env, client) = FakeClient::open(&server, "fake_client").unwrap();
+
+        let window = format!("{}:0", client.key());
+        let popup = format!("{}:1", client.key());
+
+        let create = |key: &str, kind: WindowKind| {
+            client
+                .respond_for(
+                    key,
+                    ClientResponseMessage::WindowCreated(WindowCreationResponse {
+                        title: key.into(),
+                        width: 1.,
+                        height: 1.,
+                        kind,
+                        decoration_mode: DecorationMode::Server,
+                    }),
+                )
+                .unwrap();
+        };
+
+        create(&window, WindowKind::TopLevel);
+        create(
+            &popup,
+            WindowKind::Popup {
+                parent: window.clone(),
+                x: 1.,
+                y: 0.,
+            },
+        );
+
+        // windows of other clients are rejected.
+        create("other_client:0", WindowKind::TopLevel);
+
+        // popups without a known parent and known keys are rejected, the windows cannot form a cycle.
+        let popup_kind = |parent: &str| WindowKind::Popup {
+            parent: parent.into(),
+            x: 0.,
+            y: 0.,
+        };
+        let (cycle_one, cycle_two) = (format!("{}:2", client.key()), format!("{}:3", client.key()));
+        create(&cycle_one, popup_kind(&cycle_one));
+        create(&cycle_one, popup_kind(&cycle_two));
+        create(&cycle_two, popup_kind(&cycle_one));
+        create(&window, popup_kind(&popup));
+        create(client.key(), popup_kind(&popup));
+
+        let last = format!("{}:4", client.key());
+        create(&last, WindowKind::TopLevel);
+
+        let mut created = vec![];
+        wait_for(&mut server, |r| {
+            if let ClientResponseMessage::WindowCreated(_) = r.message {
+                created.push(r.key.clone());
+            }
+
+            r.key == last
+        });
+        assert_eq!(created, vec![window.clone(), popup.clone(), last]);
+
+        server
+            .proxy()
+            .send_pointer_moved_event(popup.clone(), 1., 0.);
+        let (key, event) = client.next_window_event().unwrap();
+        assert_eq!(key, popup);
+        assert!(matches!(event, ClientEvent::PointerMoved { .. }));
+
+        // the popup is closed with its parent.
+        client
+            .respond_for(window.clone(), ClientResponseMessage::WindowClosed)
+            .unwrap();
+
+        let (key, event) = client.next_window_event().unwrap();
+        assert_eq!(key, popup);
+        assert!(matches!(event, ClientEvent::Close));
+
+        let mut closed = vec![];
+        wait_for(&mut server, |r| {
+            if let ClientResponseMessage::WindowClosed = r.message {
+                closed.push(r.key.clone());
+            }
+
+            closed.len() == 2
+        });
+        assert_eq!(closed, vec![window, popup]);
+
+        server.proxy().close_client(env.client_key.clone());
+        assert!(client.run_until_close().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_focus() {
+        let mut server = Server::new();
+        let (one, client_one) = spawn_client(&server, "fake_client_one");
+        let (two, client_two) = spawn_client(&server, "fake_client_two");
+
+        let event = KeyEvent::from_text("a");
+
+        // key events without focus are dropped.
+        server.proxy().send_key_pressed_event(event.clone());
+        server.proxy().focus(one.client_key.clone());
+        server.proxy().send_key_pressed_event(event.clone());
+        server.proxy().focus(two.client_key.clone());
+        server.proxy().send_key_released_event(event.clone());
+
+        server.proxy().close();
+        wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::AllClosed)
+        });
+
+        let events = client_one.join().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], ClientEvent::FocusIn));
+        assert!(matches!(&events[1], ClientEvent::KeyPressed(e) if *e == event));
+        assert!(matches!(events[2], ClientEvent::FocusOut));
+
+        let events = client_two.join().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], ClientEvent::FocusIn));
+        assert!(matches!(&events[1], ClientEvent::KeyReleased(e) if *e == event));
+    }
+
+    #[test]
+    fn test_authentication() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+
+        // a client that does not know the token cannot connect as the opened client.
+        let impostor = ClientEnv {
+            token: "wrong".into(),
+            ..env.clone()
+        };
+        let client = FakeClient::connect(&impostor, "impostor", 2., 1.).unwrap();
+
+        // the impostor is told and closed, it is not reported as crashed.
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, env.client_key);
+        assert!(matches!(response.message, ClientResponseMessage::Closed));
+        assert!(matches!(
+            client.next_event(),
+            Some(ClientEvent::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_tokens_equal() {
+        assert!(tokens_equal("0123abcd", "0123abcd"));
+        assert!(!tokens_equal("0123abcd", "0123abce"));
+        assert!(!tokens_equal("0123abcd", "0123abc"));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+        let client = FakeClient::connect_with_response(
+            &env,
+            CreationResponse {
+                title: "fake".into(),
+                path: "fake_client".into(),
+                width: 2.,
+                height: 1.,
+                version: VERSION + 1,
+                capabilities: vec![],
+                decoration_mode: DecorationMode::Server,
+            },
+        )
+        .unwrap();
+
+        // the client is rejected instead of created.
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, env.client_key);
+        assert!(matches!(
+            response.message,
+            ClientResponseMessage::OpenClientFailed { path, .. } if path == "fake_client"
+        ));
+        assert!(matches!(client.next_event(), Some(ClientEvent::Close)));
+    }
+
+    #[test]
+    fn test_open_client_failed() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client_with_permissions("fake_client", Permission::all())
+            .unwrap();
+        let client = FakeClient::connect(&env, "fake", 2., 1.).unwrap();
+
+        // a client that cannot be started does not end the server.
+        client
+            .respond(ClientResponseMessage::OpenClient(
+                "/nonexistent/coop_client".into(),
+            ))
+            .unwrap();
+        assert!(matches!(
+            client.next_event(),
+            Some(ClientEvent::OpenClientFailed(path)) if path == "/nonexistent/coop_client"
+        ));
+
+        // the server app is told about the failure as well.
+        wait_for(&mut server, |r| {
+            matches!(
+                &r.message,
+                ClientResponseMessage::OpenClientFailed { path, .. } if path == "/nonexistent/coop_client"
+            )
+        });
+
+        server.proxy().close_client(env.client_key.clone());
+        assert!(client.run_until_close().unwrap().is_empty());
+
+        // clients are not allowed to open other clients by default.
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+        let client = FakeClient::connect(&env, "fake", 2., 1.).unwrap();
+        client
+            .respond(ClientResponseMessage::OpenClient("other".into()))
+            .unwrap();
+        assert!(matches!(
+            client.next_event(),
+            Some(ClientEvent::PermissionDenied(Permission::SpawnClients))
+        ));
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let mut server = Server::new();
+
+        // a started client that never connects does not stall the other clients.
+        server
+            .proxy()
+            .open_client(proxy::ClientCommand::new("sleep").arg("30"));
+
+        let start = std::time::Instant::now();
+        let (env, client) = spawn_client(&server, "fake_client");
+        wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+        assert!(start.elapsed() < proxy::CONNECT_TIMEOUT / 2);
+
+        server.proxy().close_client(env.client_key.clone());
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client_with_permissions("fake_client", vec![Permission::Fullscreen])
+            .unwrap();
+
+        let client = FakeClient::connect(&env, "fake", 2., 1.).unwrap();
+        client
+            .respond(ClientResponseMessage::SetClipboardText("copied".into()))
+            .unwrap();
+        client
+            .respond(ClientResponseMessage::OpenClient("other".into()))
+            .unwrap();
+        client
+            .respond(ClientResponseMessage::SetState(WindowState::Fullscreen))
+            .unwrap();
+
+        assert!(matches!(
+            client.next_event(),
+            Some(ClientEvent::PermissionDenied(Permission::Clipboard))
+        ));
+        assert!(matches!(
+            client.next_event(),
+            Some(ClientEvent::PermissionDenied(Permission::SpawnClients))
+        ));
+
+        assert!(matches!(
+            server.client_response().unwrap().message,
+            ClientResponseMessage::Created(_)
+        ));
+        assert!(matches!(
+            server.client_response().unwrap().message,
+            ClientResponseMessage::SetState(WindowState::Fullscreen)
+        ));
+
+        // the client does not get the clipboard of other clients.
+        server.proxy().set_clipboard_text("host");
+        server.proxy().close_client(env.client_key.clone());
+
+        assert!(client.run_until_close().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tcp() {
+        let mut server = Server::new();
+        let address = server.proxy().listen("127.0.0.1:0").unwrap();
+
+        let client = thread::spawn(move || {
+            let client = FakeClient::attach(&address, "remote", 2., 1.).unwrap();
+            client.draw(vec![255; 8], 2., 1.).unwrap();
+            client.run_until_close().unwrap()
+        });
+
+        let response = server.client_response().unwrap();
+        let key = response.key.clone();
+        match response.message {
+            ClientResponseMessage::Created(c) => {
+                assert_eq!(c.title, "remote");
+                assert!(!c.capabilities.contains(&Capability::SharedMemory));
+            }
+            m => panic!("Unexpected response {:?}", m),
+        }
+
+        match server.client_response().unwrap().message {
+            ClientResponseMessage::Draw(d) => assert_eq!(d.buffer, vec![255; 8]),
+            m => panic!("Unexpected response {:?}", m),
+        }
+
+        server.proxy().close_client(key.clone());
+
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, key);
+        assert!(matches!(response.message, ClientResponseMessage::Closed));
+        assert!(client.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_server_message_from_client() {
+        let mut server = Server::new();
+        let address = server.proxy().listen("127.0.0.1:0").unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (version, sender, receiver) = runtime.block_on(connect(&address)).unwrap();
+        assert_eq!(version, VERSION);
+        let ClientMessage::Create(request) = runtime.block_on(receiver.recv()).unwrap() else {
+            panic!("Missing creation request");
+        };
+
+        // a client that sends events is closed instead of ending the server.
+        runtime
+            .block_on(sender.send(ClientMessage::Event(ClientEvent::Close)))
+            .unwrap();
+
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, request.key);
+        assert!(matches!(response.message, ClientResponseMessage::Closed));
+
+        let (env, client) = spawn_client(&server, "client");
+        wait_for(&mut server, |r| r.key == env.client_key);
+        server.proxy().close_client(env.client_key.clone());
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_stalled_peer() {
+        let mut server = Server::new();
+        let address = server
+            .proxy()
+            .listen_with_token("127.0.0.1:0", "secret", vec![Permission::Clipboard])
+            .unwrap();
+
+        // the attached client never reads its events.
+        let stalled =
+            FakeClient::attach_with_token(&address, Some("secret"), "stalled", 2., 1.).unwrap();
+        wait_for(&mut server, |r| r.key == stalled.key());
+
+        for _ in 0..256 {
+            server.proxy().send_client_event(
+                stalled.key(),
+                ClientEvent::ClipboardChanged("c".repeat(65536)),
+            );
+        }
+
+        // the server still serves other clients.
+        let (env, client) = spawn_client(&server, "client");
+        wait_for(&mut server, |r| {
+            r.key == env.client_key && matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+
+        server.proxy().close_client(env.client_key.clone());
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_listen_unix() {
+        let mut server = Server::new();
+        let path = std::env::temp_dir().join(format!("coop_server_{}.sock", std::process::id()));
+        let address = server
+            .proxy()
+            .listen_with_token(
+                path.to_string_lossy(),
+                "secret",
+                vec![Permission::Clipboard],
+            )
+            .unwrap();
+
+        // a client with another token is closed.
+        let impostor =
+            FakeClient::attach_with_token(&address, Some("wrong"), "impostor", 2., 1.).unwrap();
+        assert!(matches!(
+            impostor.next_event(),
+            Some(ClientEvent::AuthenticationFailed)
+        ));
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, impostor.key());
+        assert!(matches!(response.message, ClientResponseMessage::Closed));
+
+        let client =
+            FakeClient::attach_with_token(&address, Some("secret"), "attached", 2., 1.).unwrap();
+
+        let response = server.client_response().unwrap();
+        assert_eq!(response.key, client.key());
+        assert!(matches!(
+            response.message,
+            ClientResponseMessage::Created(_)
+        ));
+
+        // the client is granted the permissions of the listener.
+        server.proxy().set_clipboard_text("host");
+        server.proxy().close();
+
+        let events = client.run_until_close().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ClientEvent::ClipboardChanged(text) if text == "host"));
+
+        // the socket is removed when the server is closed.
+        wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::AllClosed)
+        });
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_frame_done() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+
+        let client = FakeClient::connect_with_capabilities(
+            &env,
+            "fake",
+            2.,
+            1.,
+            vec![Capability::FrameCallbacks],
+        )
+        .unwrap();
+        client.draw(vec![255; 8], 2., 1.).unwrap();
+
+        assert!(matches!(client.next_event(), Some(ClientEvent::FrameDone)));
+
+        match server.client_response().unwrap().message {
+            ClientResponseMessage::Created(c) => {
+                assert_eq!(c.capabilities, vec![Capability::FrameCallbacks])
+            }
+            m => panic!("Unexpected response {:?}", m),
+        }
+
+        // the next frame callback is only sent after the next frame is drawn.
+        server.proxy().close_client(env.client_key.clone());
+        assert!(client.run_until_close().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_close_unresponsive_client() {
+        let mut server = Server::new();
+        let (env, client) = FakeClient::open(&server, "fake_unresponsive").unwrap();
+
+        // the client ignores the close request, it does not stall the server.
+        server.proxy().close_client(env.client_key.clone());
+        let (other, other_client) = spawn_client(&server, "fake_other");
+
+        let response = wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+        assert_eq!(response.key, other.client_key);
+
+        // the connection is ended after the close timeout.
+        assert!(matches!(client.next_event(), Some(ClientEvent::Close)));
+        assert!(client.next_event().is_none());
+
+        server.proxy().close_client(other.client_key.clone());
+        assert!(other_client.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crashed() {
+        let mut server = Server::new();
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+
+        // the client drops its connection without sending the closed message.
+        thread::spawn({
+            let env = env.clone();
+            move || drop(FakeClient::connect(&env, "fake", 2., 1.).unwrap())
+        })
+        .join()
+        .unwrap();
+
+        let response = wait_for(&mut server, |r| {
+            !matches!(r.message, ClientResponseMessage::Created(_))
+        });
+        assert_eq!(response.key, env.client_key);
+        assert!(matches!(
+            response.message,
+            ClientResponseMessage::Crashed {
+                exit_code: None,
+                signal: None
+            }
+        ));
+    }
+}
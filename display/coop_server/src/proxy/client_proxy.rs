@@ -1,34 +1,240 @@
 // SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
 // SPDX-License-Identifier: MIT
 
+use std::fs::File;
+use std::io::Read;
 use std::path::*;
-use std::process::Command;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, process::Child};
 
-use coop_protocol::{ClientEvent, ClientMessage};
+use coop_protocol::{
+    Capability, ClientEvent, ClientMessage, CreationRequest, DecorationMode, MessageReceiver,
+    MessageSender, Permission, PixelFormat, SharedMemory,
+};
 use tokio_unix_ipc::Bootstrapper;
 
+use crate::capture::FrameStore;
+use crate::recorder::Recorder;
 use crate::result::*;
 
-/// Used to send messages from `Server` to  client.
+/// Time a started client has to connect to the server before it is given up.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Environment that is passed to a client to connect it to the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientEnv {
+    /// Path of the server socket.
+    pub server_key: String,
+
+    /// Connection key of the client.
+    pub client_key: String,
+
+    /// Origin path of the client.
+    pub client_path: String,
+
+    /// Secret the client authenticates with.
+    pub token: String,
+
+    /// Path of the shared memory file of the client.
+    pub shared_memory_path: Option<String>,
+}
+
+impl ClientEnv {
+    /// Returns the list of env variables that are set on a client process.
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            (coop_protocol::SERVER_KEY, self.server_key.clone()),
+            (coop_protocol::CLIENT_KEY, self.client_key.clone()),
+            (coop_protocol::CLIENT_PATH, self.client_path.clone()),
+            (coop_protocol::CLIENT_TOKEN, self.token.clone()),
+            (
+                coop_protocol::PROTOCOL_VERSION,
+                coop_protocol::VERSION.to_string(),
+            ),
+        ];
+
+        if let Some(shared_memory_path) = &self.shared_memory_path {
+            vars.push((
+                coop_protocol::SHARED_MEMORY_PATH,
+                shared_memory_path.clone(),
+            ));
+        }
+
+        vars
+    }
+}
+
+/// Command that starts a client process.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientCommand {
+    /// Path of the client executable.
+    pub path: String,
+
+    /// Arguments that are passed to the client.
+    pub args: Vec<String>,
+
+    /// Working directory of the client, `None` to use the working directory of the server.
+    pub current_dir: Option<PathBuf>,
+
+    /// Additional env variables of the client.
+    pub envs: Vec<(String, String)>,
+}
+
+impl ClientCommand {
+    /// Creates a command that starts the client with the given path.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds an argument that is passed to the client.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Adds arguments that are passed to the client.
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets the working directory of the client.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Adds an env variable of the client.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+}
+
+impl From<String> for ClientCommand {
+    fn from(path: String) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<&str> for ClientCommand {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
+}
+
+/// Defines if a client is restarted after it has crashed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// The client is not restarted.
+    #[default]
+    Never,
+
+    /// The client is restarted after a crash until it has been restarted `max_restarts` times.
+    OnCrash { max_restarts: u32 },
+}
+
+impl RestartPolicy {
+    /// Returns the policy for the restarted client or `None` if the client should not be restarted.
+    pub fn next(&self) -> Option<RestartPolicy> {
+        match self {
+            RestartPolicy::OnCrash { max_restarts } if *max_restarts > 0 => {
+                Some(RestartPolicy::OnCrash {
+                    max_restarts: max_restarts - 1,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Connection to a client that is opened or attached by the `ClientProxyPool`.
+#[derive(Debug)]
+pub struct ClientConnection {
+    /// Key of the client.
+    pub key: String,
+
+    /// Receives the messages of the client.
+    pub receiver: Box<dyn MessageReceiver>,
+
+    /// Shared memory of the client if available.
+    pub shared_memory: Option<SharedMemory>,
+
+    /// Secret the client has to authenticate with before it can send other messages. Attached clients only
+    /// authenticate if their listener has a token.
+    pub token: Option<String>,
+
+    /// Permissions that are granted to the client.
+    pub permissions: Vec<Permission>,
+
+    /// Records the responses of the client while a recording of the server is running.
+    pub recorder: Recorder,
+
+    /// Keeps the latest frames of the windows of the client.
+    pub frames: FrameStore,
+}
+
+/// Used to send messages from `Server` to  client. The client is killed when its proxy is dropped.
 #[derive(Debug)]
 pub struct ClientProxy {
-    sender: Bootstrapper,
-    client: Child,
+    sender: Box<dyn MessageSender>,
+    client: Option<Child>,
+    shared_memory_path: Option<PathBuf>,
+    permissions: Vec<Permission>,
+
+    // additional windows of the client with the key of their parent window.
+    windows: HashMap<String, Option<String>>,
 }
 
 impl ClientProxy {
-    /// Creates a new client proxy.
-    pub fn new(client: Child, sender: Bootstrapper) -> Self {
-        Self { client, sender }
+    /// Creates a new client proxy. `client` is `None` if the client does not run in a child process.
+    pub fn new(
+        client: Option<Child>,
+        sender: Box<dyn MessageSender>,
+        shared_memory_path: Option<PathBuf>,
+        permissions: Vec<Permission>,
+    ) -> Self {
+        Self {
+            client,
+            sender,
+            shared_memory_path,
+            permissions,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if the given permission is granted to the client.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
     }
 
     /// Sends an event to the client.
+    ///
+    /// Events to a client that is gone are dropped, the server reports it as crashed. Clipboard changes are
+    /// only sent to clients with the `Permission::Clipboard`.
     pub async fn send_event(&self, event: ClientEvent) {
+        if let ClientEvent::ClipboardChanged(_) = event {
+            if !self.has_permission(Permission::Clipboard) {
+                return;
+            }
+        }
+
+        self.sender.send(ClientMessage::Event(event)).await.ok();
+    }
+
+    /// Sends an event to an additional window of the client.
+    pub async fn send_window_event(&self, key: impl Into<String>, event: ClientEvent) {
         self.sender
-            .send(ClientMessage::Event(event))
+            .send(ClientMessage::WindowEvent {
+                key: key.into(),
+                event,
+            })
             .await
-            .expect("Cannot send event to client");
+            .ok();
     }
 
     /// Request to close the client.
@@ -36,10 +242,139 @@ impl ClientProxy {
         self.send_event(ClientEvent::Close).await;
     }
 
+    /// Adds an additional window of the client, `parent` is the key of the parent window of a popup.
+    pub fn add_window(&mut self, key: impl Into<String>, parent: Option<String>) {
+        self.windows.insert(key.into(), parent);
+    }
+
+    /// Removes the window with the given key and all its child windows.
+    ///
+    /// Returns the keys of the removed child windows.
+    pub fn remove_window(&mut self, key: &String) -> Vec<String> {
+        self.windows.remove(key);
+
+        let children: Vec<String> = self
+            .windows
+            .iter()
+            .filter(|(_, parent)| parent.as_ref() == Some(key))
+            .map(|(child, _)| child.clone())
+            .collect();
+
+        let mut removed = vec![];
+
+        for child in children {
+            removed.extend(self.remove_window(&child));
+            removed.push(child);
+        }
+
+        removed
+    }
+
+    /// Returns `true` if the client has an additional window with the given key.
+    pub fn has_window(&self, key: &String) -> bool {
+        self.windows.contains_key(key)
+    }
+
+    /// Returns the keys of all additional windows of the client.
+    pub fn windows(&self) -> Vec<String> {
+        self.windows.keys().cloned().collect()
+    }
+
+    /// Waits up to `timeout` for the client process to exit and returns its exit status, kills the client if it is
+    /// still running after that. Blocks the current thread.
+    ///
+    /// Returns `None` if the client does not run in a child process.
+    pub fn wait_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let client = self.client.as_mut()?;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match client.try_wait() {
+                Ok(Some(status)) => return Some(status),
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => {
+                    client.kill().ok();
+                    return client.wait().ok();
+                }
+            }
+        }
+    }
+
     /// Ends the client.
     pub fn kill(&mut self) {
-        self.client.kill().ok();
-        self.client.wait().ok();
+        if let Some(mut client) = self.client.take() {
+            client.kill().ok();
+            client.wait().ok();
+        }
+
+        if let Some(path) = self.shared_memory_path.take() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl Drop for ClientProxy {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Client that is started by the `ClientProxyPool` and has not completed the handshake yet.
+#[derive(Debug)]
+pub struct PendingClient {
+    proxy: ClientProxy,
+    connection: ClientConnection,
+    request: CreationRequest,
+}
+
+impl PendingClient {
+    /// Returns the key of the client.
+    pub fn key(&self) -> &str {
+        &self.connection.key
+    }
+
+    /// Sends the creation request to the client, a client that does not connect within `CONNECT_TIMEOUT` is
+    /// killed.
+    ///
+    /// It does not need the pool, so a slow client can connect off the server loop. The connected client is added
+    /// to the pool by `ClientProxyPool::add`.
+    pub async fn connect(self) -> ServerResult<OpenedClient> {
+        let Self {
+            proxy,
+            connection,
+            request,
+        } = self;
+
+        // the request is only sent when the client connects.
+        let created = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            proxy.sender.send(ClientMessage::Create(request)),
+        )
+        .await;
+
+        if !matches!(created, Ok(Ok(()))) {
+            return Err(ServerError::CannotOpenClient(
+                "Cannot establish connection with client".into(),
+            ));
+        }
+
+        Ok(OpenedClient { proxy, connection })
+    }
+}
+
+/// Client that has completed the handshake, see `PendingClient::connect`.
+#[derive(Debug)]
+pub struct OpenedClient {
+    proxy: ClientProxy,
+    connection: ClientConnection,
+}
+
+impl OpenedClient {
+    /// Returns the key of the client.
+    pub fn key(&self) -> &str {
+        &self.connection.key
     }
 }
 
@@ -47,7 +382,13 @@ impl ClientProxy {
 #[derive(Debug)]
 pub struct ClientProxyPool {
     proxies: HashMap<String, ClientProxy>,
+
+    // events to clients that have not completed the handshake, they are sent when the client is added.
+    pending: HashMap<String, Vec<ClientEvent>>,
+
     connection_count: i32,
+    recorder: Recorder,
+    frames: FrameStore,
 }
 
 impl ClientProxyPool {
@@ -55,17 +396,63 @@ impl ClientProxyPool {
     pub fn new() -> Self {
         Self {
             proxies: HashMap::new(),
+            pending: HashMap::new(),
             connection_count: 0,
+            recorder: Recorder::new(),
+            frames: FrameStore::new(),
         }
     }
 
-    /// Opens a new client and returns a client message receiver.
-    pub async fn open<P>(
+    /// Returns the recorder of the events that are sent by the pool, it is shared with all client connections.
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+
+    /// Returns the latest frames of the windows of all clients, it is shared with all client connections.
+    pub fn frames(&self) -> &FrameStore {
+        &self.frames
+    }
+
+    /// Starts a new client by the given command, it is connected by `PendingClient::connect`.
+    pub fn open(
+        &mut self,
+        command: &ClientCommand,
+        permissions: Vec<Permission>,
+    ) -> ServerResult<PendingClient> {
+        self.open_with(&command.path, permissions, |env| {
+            let mut process = Command::new(&command.path);
+
+            // the env of the connection cannot be overwritten by the additional env variables.
+            process
+                .args(&command.args)
+                .envs(command.envs.iter().cloned())
+                .envs(env.vars());
+
+            if let Some(current_dir) = &command.current_dir {
+                process.current_dir(current_dir);
+            }
+
+            process
+                .spawn()
+                .map(Some)
+                .map_err(|e| ServerError::CannotOpenClient(format!("Cannot open client {:?}", e)))
+        })
+    }
+
+    /// Opens a new client that is started by the given `launch` function.
+    ///
+    /// `launch` gets the environment the client needs to connect to the server and returns the child process
+    /// of the client or `None` if the client does not run in its own process. The client is connected by
+    /// `PendingClient::connect`.
+    pub fn open_with<P, F>(
         &mut self,
         path: P,
-    ) -> ServerResult<(String, tokio_unix_ipc::Receiver<ClientMessage>)>
+        permissions: Vec<Permission>,
+        launch: F,
+    ) -> ServerResult<PendingClient>
     where
         P: AsRef<Path>,
+        F: FnOnce(&ClientEnv) -> ServerResult<Option<Child>>,
     {
         let sender = Bootstrapper::new().unwrap();
         let client_path = path
@@ -78,54 +465,241 @@ impl ClientProxyPool {
         let connection_key = format!("{client_path}_{}", self.connection_count);
         self.connection_count += 1;
 
-        let client = Command::new(path.as_ref())
-            .env(coop_protocol::SERVER_KEY, sender.path())
-            .env(coop_protocol::CLIENT_KEY, connection_key.clone())
-            .env(coop_protocol::CLIENT_PATH, client_path)
-            .spawn()
-            .map_err(|e| {
-                Err(ServerError::CannotOpenClient(format!(
-                    "Cannot open client {:?}",
-                    e
-                )))
-            })?;
+        // the name is not predictable, a path that exists anyway falls back to sending the frames by copy.
+        let shared_memory = SharedMemory::create(format!(
+            "/dev/shm/coop_{}_{}_{}",
+            std::process::id(),
+            self.connection_count,
+            generate_token()?
+        ))
+        .ok();
+        let shared_memory_path = shared_memory.as_ref().map(|s| s.path().to_path_buf());
+
+        let token = generate_token()?;
+
+        let env = ClientEnv {
+            server_key: sender.path().to_string_lossy().to_string(),
+            client_key: connection_key.clone(),
+            client_path,
+            token: token.clone(),
+            shared_memory_path: shared_memory_path
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string()),
+        };
+
+        let remove_shared_memory = || {
+            if let Some(shared_memory_path) = &shared_memory_path {
+                std::fs::remove_file(shared_memory_path).ok();
+            }
+        };
+
+        let client = match launch(&env) {
+            Ok(client) => client,
+            Err(e) => {
+                remove_shared_memory();
+                return Err(e);
+            }
+        };
 
         let (client_sender, client_receiver) = tokio_unix_ipc::channel().unwrap();
 
+        let mut capabilities = Capability::all();
+
+        if shared_memory.is_none() {
+            capabilities.retain(|c| *c != Capability::SharedMemory);
+        }
+
+        self.pending.insert(connection_key.clone(), vec![]);
+
+        Ok(PendingClient {
+            proxy: ClientProxy::new(
+                client,
+                Box::new(sender),
+                shared_memory_path,
+                permissions.clone(),
+            ),
+            request: CreationRequest {
+                sender: Some(client_sender),
+                key: connection_key.clone(),
+                version: coop_protocol::VERSION,
+                capabilities,
+                decoration_modes: DecorationMode::all(),
+                pixel_formats: PixelFormat::all(),
+            },
+            connection: ClientConnection {
+                key: connection_key,
+                receiver: Box::new(client_receiver),
+                shared_memory,
+                token: Some(token),
+                permissions,
+                recorder: self.recorder.clone(),
+                frames: self.frames.clone(),
+            },
+        })
+    }
+
+    /// Adds a client that has completed the handshake and returns the connection to the client.
+    ///
+    /// Events that are sent to the client during the handshake are sent now.
+    pub async fn add(&mut self, client: OpenedClient) -> ClientConnection {
+        let OpenedClient { proxy, connection } = client;
+
+        for event in self.pending.remove(&connection.key).unwrap_or_default() {
+            proxy.send_event(event).await;
+        }
+
+        self.proxies.insert(connection.key.clone(), proxy);
+
+        connection
+    }
+
+    /// Drops the events to a client that has failed to complete the handshake.
+    pub fn remove_pending(&mut self, key: &str) {
+        self.pending.remove(key);
+    }
+
+    /// Attaches a client that is already connected, e.g. over TCP, and returns the connection to the client.
+    ///
+    /// `name` identifies the connection, e.g. by the address of the client. The client has to authenticate with
+    /// `token` if it is set. Shared memory is not available for attached clients.
+    pub async fn attach(
+        &mut self,
+        name: impl AsRef<str>,
+        sender: Box<dyn MessageSender>,
+        receiver: Box<dyn MessageReceiver>,
+        token: Option<String>,
+        permissions: Vec<Permission>,
+    ) -> ServerResult<ClientConnection> {
+        let connection_key = format!("{}_{}", name.as_ref(), self.connection_count);
+        self.connection_count += 1;
+
+        let mut capabilities = Capability::all();
+        capabilities.retain(|c| *c != Capability::SharedMemory);
+
         sender
-            .send(ClientMessage::Create(client_sender))
+            .send(ClientMessage::Create(CreationRequest {
+                sender: None,
+                key: connection_key.clone(),
+                version: coop_protocol::VERSION,
+                capabilities,
+                decoration_modes: DecorationMode::all(),
+                pixel_formats: PixelFormat::all(),
+            }))
             .await
             .map_err(|_| {
                 ServerError::CannotOpenClient("Cannot establish connection with client".into())
             })?;
 
-        self.proxies
-            .insert(connection_key.clone(), ClientProxy::new(client, sender));
+        self.proxies.insert(
+            connection_key.clone(),
+            ClientProxy::new(None, sender, None, permissions.clone()),
+        );
 
-        Ok((connection_key, client_receiver))
+        Ok(ClientConnection {
+            key: connection_key,
+            receiver,
+            shared_memory: None,
+            token,
+            permissions,
+            recorder: self.recorder.clone(),
+            frames: self.frames.clone(),
+        })
     }
 
-    /// Sends an event to the given client.
-    pub async fn send_event(&self, key: &String, event: ClientEvent) {
+    /// Sends an event to the given client or additional window of a client.
+    pub async fn send_event(&mut self, key: &String, event: ClientEvent) {
+        self.recorder.record_event(key, &event);
+
         if let Some(proxy) = self.proxies.get(key) {
             proxy.send_event(event).await;
+            return;
+        }
+
+        if let Some(events) = self.pending.get_mut(key) {
+            events.push(event);
+            return;
+        }
+
+        if let Some(proxy) = self.proxies.values().find(|p| p.has_window(key)) {
+            proxy.send_window_event(key, event).await;
+        }
+    }
+
+    /// Sends an event to an additional window of the given client.
+    pub async fn send_window_event(&self, client_key: &String, key: &String, event: ClientEvent) {
+        self.recorder.record_event(key, &event);
+
+        if let Some(proxy) = self.proxies.get(client_key) {
+            proxy.send_window_event(key, event).await;
+        }
+    }
+
+    /// Adds an additional window to the given client.
+    pub fn add_window(
+        &mut self,
+        client_key: &String,
+        key: impl Into<String>,
+        parent: Option<String>,
+    ) {
+        if let Some(proxy) = self.proxies.get_mut(client_key) {
+            proxy.add_window(key, parent);
+        }
+    }
+
+    /// Removes an additional window and its child windows from the given client.
+    ///
+    /// Returns the keys of the removed child windows.
+    pub fn remove_window(&mut self, client_key: &String, key: &String) -> Vec<String> {
+        let children = self
+            .proxies
+            .get_mut(client_key)
+            .map(|proxy| proxy.remove_window(key))
+            .unwrap_or_default();
+
+        for key in std::iter::once(key).chain(&children) {
+            self.frames.remove(key);
+        }
+
+        children
+    }
+
+    /// Returns the keys of the additional windows of the given client.
+    pub fn windows(&self, client_key: &String) -> Vec<String> {
+        self.proxies
+            .get(client_key)
+            .map(|proxy| proxy.windows())
+            .unwrap_or_default()
+    }
+
+    /// Sends an event to all clients except the client with the `except` key.
+    pub async fn broadcast_event(&self, event: ClientEvent, except: Option<&String>) {
+        for (key, proxy) in &self.proxies {
+            if Some(key) != except {
+                self.recorder.record_event(key, &event);
+                proxy.send_event(event.clone()).await;
+            }
         }
     }
 
     /// Closes a client.
     pub async fn request_close(&mut self, key: &String) {
-        if let Some(proxy) = self.proxies.get_mut(key) {
-            proxy.request_close().await;
+        if self.proxies.contains_key(key) || self.pending.contains_key(key) {
+            self.send_event(key, ClientEvent::Close).await;
         }
     }
 
     /// End the given client
     pub fn kill_client(&mut self, key: &String) {
-        if let Some(proxy) = self.proxies.get_mut(key) {
+        if let Some(mut proxy) = self.take_client(key) {
             proxy.kill();
         }
+    }
 
-        self.proxies.remove(key);
+    /// Removes the given client from the pool, e.g. to end it off the server loop. The client is killed when the
+    /// returned proxy is dropped.
+    pub fn take_client(&mut self, key: &String) -> Option<ClientProxy> {
+        self.frames.remove_client(key);
+        self.proxies.remove(key)
     }
 
     /// Ends all clients.
@@ -139,8 +713,20 @@ impl ClientProxyPool {
 
     /// Request to close all clients.
     pub async fn request_close_all(&mut self) {
-        for (_, proxy) in &mut self.proxies {
+        for (key, proxy) in &mut self.proxies {
+            self.recorder.record_event(key, &ClientEvent::Close);
             proxy.request_close().await;
         }
     }
 }
+
+/// Generates a random secret that a client uses to authenticate.
+fn generate_token() -> ServerResult<String> {
+    let mut bytes = [0; 16];
+
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| ServerError::CannotOpenClient(format!("Cannot generate token {:?}", e)))?;
+
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
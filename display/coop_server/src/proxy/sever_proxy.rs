@@ -1,18 +1,99 @@
 // SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
 // SPDX-License-Identifier: MIT
 
-use coop_protocol::{ClientEvent, PointerEventButton};
+use std::sync::mpsc;
+
+use coop_protocol::{ClientEvent, KeyEvent, Permission, PointerEventButton};
 
 use tokio::sync::mpsc::UnboundedSender;
 
-/// Used to send messages from `ServerProxy` to  `Server`.
+use super::{ClientCommand, ClientEnv, RestartPolicy};
+use crate::capture::Image;
+use crate::result::*;
+use crate::window_manager::Geometry;
+
+/// Used to send messages from `ServerProxy` and the client loops to `Server`.
+///
+/// It is internal to the server, e.g. a window is only added by the client loop of its client.
 #[derive(Clone, Debug)]
-pub enum ServerProxyMessage {
+pub(crate) enum ServerProxyMessage {
     /// A event that is send to a client.
     SendEvent { key: String, event: ClientEvent },
 
-    /// Opens a client.
-    OpenClient(String),
+    /// A event that is send to the window with the keyboard focus.
+    SendFocusedEvent(ClientEvent),
+
+    /// Moves the keyboard focus to the window with the given key, `None` clears the focus.
+    SetFocus(Option<String>),
+
+    /// Opens a client with the given permissions, it is restarted after a crash as defined by `restart_policy`.
+    ///
+    /// `requester` is the key of the client that has requested it, it gets a `ClientEvent::OpenClientFailed` if
+    /// the client cannot be opened.
+    OpenClient {
+        command: ClientCommand,
+        restart_policy: RestartPolicy,
+        permissions: Vec<Permission>,
+        requester: Option<String>,
+    },
+
+    /// Opens a client that is not started as a child process, the environment to connect it is sent back by `env_sender`.
+    OpenInProcessClient {
+        path: String,
+        permissions: Vec<Permission>,
+        env_sender: mpsc::Sender<ClientEnv>,
+    },
+
+    /// Accepts clients on the given address, the bound address is sent back by `address_sender`.
+    ///
+    /// Clients have to authenticate with `token` if it is set, they are granted the given permissions.
+    Listen {
+        address: String,
+        token: Option<String>,
+        permissions: Vec<Permission>,
+        address_sender: mpsc::Sender<ServerResult<String>>,
+    },
+
+    /// Starts a recording to the file on the given path or stops the running recording if `path` is `None`, the
+    /// result is sent back by `result_sender`.
+    Record {
+        path: Option<String>,
+        result_sender: mpsc::Sender<ServerResult<()>>,
+    },
+
+    /// Captures the latest frame of the window with the given key, the image is sent back by `image_sender`.
+    Capture {
+        key: String,
+        image_sender: tokio::sync::mpsc::Sender<ServerResult<Image>>,
+    },
+
+    /// Composes the latest frames of the given windows from bottom to top into an image of the given size, the
+    /// image is sent back by `image_sender`.
+    CaptureDisplay {
+        width: u32,
+        height: u32,
+        windows: Vec<(String, Geometry)>,
+        image_sender: tokio::sync::mpsc::Sender<ServerResult<Image>>,
+    },
+
+    /// The window with the given key has drawn a frame, it gets a `ClientEvent::FrameDone` on the next frame.
+    FrameDrawn(String),
+
+    /// Adds an additional window to the client with the `client` key.
+    AddWindow {
+        client: String,
+        key: String,
+        parent: Option<String>,
+    },
+
+    /// Removes an additional window from the client with the `client` key.
+    RemoveWindow { client: String, key: String },
+
+    /// Sets the text of the shared clipboard, `source` is the key of the client that has copied the text.
+    SetClipboardText {
+        text: String,
+        source: Option<String>,
+    },
 
     /// Closes a client.
     CloseClient(String),
@@ -29,17 +110,194 @@ pub struct ServerProxy {
 
 impl ServerProxy {
     /// Creates a new `ServerProxy`.
-    pub fn new(sender: UnboundedSender<ServerProxyMessage>) -> Self {
+    pub(crate) fn new(sender: UnboundedSender<ServerProxyMessage>) -> Self {
         Self { sender }
     }
 
-    /// Opens a new client application on the given path.
-    pub fn open_client(&self, path: impl Into<String>) {
+    /// Opens a new client application by the given path or `ClientCommand`, it is granted
+    /// `Permission::defaults()`.
+    pub fn open_client(&self, command: impl Into<ClientCommand>) {
+        self.open_client_with_restart_policy(command, RestartPolicy::Never);
+    }
+
+    /// Opens a new client application that is restarted after a crash as defined by `restart_policy`.
+    pub fn open_client_with_restart_policy(
+        &self,
+        command: impl Into<ClientCommand>,
+        restart_policy: RestartPolicy,
+    ) {
+        self.open_client_with_permissions(command, restart_policy, Permission::defaults());
+    }
+
+    /// Opens a new client application that is only granted the given permissions.
+    ///
+    /// Clients that are opened without explicit permissions are granted `Permission::defaults()`, opening other
+    /// clients by `Permission::SpawnClients` has to be granted explicitly.
+    pub fn open_client_with_permissions(
+        &self,
+        command: impl Into<ClientCommand>,
+        restart_policy: RestartPolicy,
+        permissions: Vec<Permission>,
+    ) {
         self.sender
-            .send(ServerProxyMessage::OpenClient(path.into()))
+            .send(ServerProxyMessage::OpenClient {
+                command: command.into(),
+                restart_policy,
+                permissions,
+                requester: None,
+            })
             .expect("Cannot send open client message to server.");
     }
 
+    /// Opens a client that runs in the current process, e.g. a fake client in a test.
+    ///
+    /// Returns the environment that is needed to connect the client to the server. The client is granted
+    /// `Permission::defaults()`.
+    pub fn open_in_process_client(&self, path: impl Into<String>) -> ServerResult<ClientEnv> {
+        self.open_in_process_client_with_permissions(path, Permission::defaults())
+    }
+
+    /// Opens a client that runs in the current process and is only granted the given permissions.
+    pub fn open_in_process_client_with_permissions(
+        &self,
+        path: impl Into<String>,
+        permissions: Vec<Permission>,
+    ) -> ServerResult<ClientEnv> {
+        let (env_sender, env_receiver) = mpsc::channel();
+
+        self.sender
+            .send(ServerProxyMessage::OpenInProcessClient {
+                path: path.into(),
+                permissions,
+                env_sender,
+            })
+            .expect("Cannot send open client message to server.");
+
+        env_receiver
+            .recv()
+            .map_err(|e| ServerError::CannotOpenClient(format!("{:?}", e)))
+    }
+
+    /// Lets running clients attach to the server, e.g. an app that is started by a debugger or on another machine.
+    ///
+    /// An address that contains a `/` is the path of a Unix socket, otherwise it is a TCP address. Clients connect
+    /// with `coop_client::Window::connect` or the `SERVER_ADDRESS` env variable. Anyone who can reach the address can
+    /// attach, so attached clients are not granted any permission, see `listen_with_token`.
+    ///
+    /// Returns the bound address, e.g. to get the port if the given TCP address uses port `0`.
+    pub fn listen(&self, address: impl Into<String>) -> ServerResult<String> {
+        self.send_listen_message(address.into(), None, vec![])
+    }
+
+    /// Lets running clients attach to the server like `listen`, but only clients that authenticate with the given
+    /// token. They are granted the given permissions.
+    ///
+    /// Clients pass the token by the `CLIENT_TOKEN` env variable, clients with another token are closed.
+    pub fn listen_with_token(
+        &self,
+        address: impl Into<String>,
+        token: impl Into<String>,
+        permissions: Vec<Permission>,
+    ) -> ServerResult<String> {
+        self.send_listen_message(address.into(), Some(token.into()), permissions)
+    }
+
+    fn send_listen_message(
+        &self,
+        address: String,
+        token: Option<String>,
+        permissions: Vec<Permission>,
+    ) -> ServerResult<String> {
+        let (address_sender, address_receiver) = mpsc::channel();
+
+        self.sender
+            .send(ServerProxyMessage::Listen {
+                address,
+                token,
+                permissions,
+                address_sender,
+            })
+            .expect("Cannot send listen message to server.");
+
+        address_receiver
+            .recv()
+            .map_err(|e| ServerError::CannotListen(format!("{:?}", e)))?
+    }
+
+    /// Records the events that are sent to clients and the responses of the clients to the file on the given path.
+    ///
+    /// A running recording is replaced. The recording can be read with `coop_protocol::read_records` and replayed
+    /// with `coop_server::recorder::replay`.
+    pub fn record(&self, path: impl Into<String>) -> ServerResult<()> {
+        self.send_record_message(Some(path.into()))
+    }
+
+    /// Stops the running recording.
+    pub fn stop_recording(&self) -> ServerResult<()> {
+        self.send_record_message(None)
+    }
+
+    fn send_record_message(&self, path: Option<String>) -> ServerResult<()> {
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        self.sender
+            .send(ServerProxyMessage::Record {
+                path,
+                result_sender,
+            })
+            .expect("Cannot send record message to server.");
+
+        result_receiver
+            .recv()
+            .map_err(|e| ServerError::CannotRecord(format!("{:?}", e)))?
+    }
+
+    /// Captures the latest frame of the window with the given key, e.g. to save it with `Image::save_png`.
+    ///
+    /// Returns an error if the window has not drawn a frame yet.
+    pub async fn capture(&self, key: impl Into<String>) -> ServerResult<Image> {
+        let (image_sender, mut image_receiver) = tokio::sync::mpsc::channel(1);
+
+        self.sender
+            .send(ServerProxyMessage::Capture {
+                key: key.into(),
+                image_sender,
+            })
+            .expect("Cannot send capture message to server.");
+
+        image_receiver
+            .recv()
+            .await
+            .ok_or_else(|| ServerError::CannotCapture("Server is closed".into()))?
+    }
+
+    /// Captures the whole display as image of the given size.
+    ///
+    /// `windows` are the keys and geometries of the visible windows from bottom to top, see
+    /// `WindowManager::visible_windows`. Windows that have not drawn a frame yet are left out.
+    pub async fn capture_display(
+        &self,
+        width: u32,
+        height: u32,
+        windows: Vec<(String, Geometry)>,
+    ) -> ServerResult<Image> {
+        let (image_sender, mut image_receiver) = tokio::sync::mpsc::channel(1);
+
+        self.sender
+            .send(ServerProxyMessage::CaptureDisplay {
+                width,
+                height,
+                windows,
+                image_sender,
+            })
+            .expect("Cannot send capture display message to server.");
+
+        image_receiver
+            .recv()
+            .await
+            .ok_or_else(|| ServerError::CannotCapture("Server is closed".into()))?
+    }
+
     /// Sends an event to the given client.
     pub fn send_client_event(&self, key: impl Into<String>, event: ClientEvent) {
         self.sender
@@ -84,6 +342,30 @@ impl ServerProxy {
         );
     }
 
+    /// Sends a pointer moved event to the client of the given key.
+    pub fn send_pointer_moved_event(&self, key: impl Into<String>, x: f32, y: f32) {
+        self.send_client_event(key, ClientEvent::PointerMoved { position: (x, y) });
+    }
+
+    /// Sends a pointer scrolled event to the client of the given key.
+    pub fn send_pointer_scrolled_event(
+        &self,
+        key: impl Into<String>,
+        x: f32,
+        y: f32,
+        delta_x: f32,
+        delta_y: f32,
+    ) {
+        self.send_client_event(
+            key,
+            ClientEvent::PointerScrolled {
+                position: (x, y),
+                delta_x,
+                delta_y,
+            },
+        );
+    }
+
     /// Sends a pointer canceled event to the client of the given key.
     pub fn send_pointer_canceled_event(&self, key: impl Into<String>) {
         self.send_client_event(key, ClientEvent::PointerCanceled);
@@ -94,14 +376,52 @@ impl ServerProxy {
         self.send_client_event(key, ClientEvent::PointerExited);
     }
 
-    /// Sends a key pressed event to the client of the given key.
-    pub fn send_key_pressed_event(&self, key: impl Into<String>, text: impl Into<String>) {
-        self.send_client_event(key, ClientEvent::KeyPressed { text: text.into() });
+    /// Sends an event to the window with the keyboard focus.
+    pub fn send_focused_event(&self, event: ClientEvent) {
+        self.sender
+            .send(ServerProxyMessage::SendFocusedEvent(event))
+            .expect("Cannot send focused event message to server.");
+    }
+
+    /// Sends a key pressed event to the window with the keyboard focus.
+    pub fn send_key_pressed_event(&self, event: KeyEvent) {
+        self.send_focused_event(ClientEvent::KeyPressed(event));
     }
 
-    /// Sends a key released event to the client of the given key.
-    pub fn send_key_released_event(&self, key: impl Into<String>, text: impl Into<String>) {
-        self.send_client_event(key, ClientEvent::KeyReleased { text: text.into() });
+    /// Sends a key released event to the window with the keyboard focus.
+    pub fn send_key_released_event(&self, event: KeyEvent) {
+        self.send_focused_event(ClientEvent::KeyReleased(event));
+    }
+
+    /// Moves the keyboard focus to the window with the given key.
+    ///
+    /// The window that loses the focus gets a `ClientEvent::FocusOut`, the focused window a `ClientEvent::FocusIn`.
+    pub fn focus(&self, key: impl Into<String>) {
+        self.sender
+            .send(ServerProxyMessage::SetFocus(Some(key.into())))
+            .expect("Cannot send focus message to server.");
+    }
+
+    /// Removes the keyboard focus from the focused window.
+    pub fn clear_focus(&self) {
+        self.sender
+            .send(ServerProxyMessage::SetFocus(None))
+            .expect("Cannot send focus message to server.");
+    }
+
+    /// Sends a resize event to the client of the given key.
+    pub fn send_resize_event(&self, key: impl Into<String>, width: f32, height: f32) {
+        self.send_client_event(key, ClientEvent::Resize { width, height });
+    }
+
+    /// Sets the text of the clipboard that is shared with all clients, e.g. to share the host clipboard.
+    pub fn set_clipboard_text(&self, text: impl Into<String>) {
+        self.sender
+            .send(ServerProxyMessage::SetClipboardText {
+                text: text.into(),
+                source: None,
+            })
+            .expect("Cannot send set clipboard text message to server.");
     }
 
     /// Closes the client with the given key.
@@ -1,9 +1,10 @@
 // SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
 // SPDX-License-Identifier: MIT
 
-use super::ServerProxy;
+use super::{ClientCommand, ServerProxy};
+use crate::result::ServerResult;
 
-use slint::private_unstable_api::re_exports::{KeyEvent, PointerEvent};
+use slint::private_unstable_api::re_exports::{KeyEvent, PointerEvent, PointerScrollEvent};
 use slint::SharedString;
 
 /// `SlintProxy` is a wrapper for `ServerProxy` and make it easier to send events from a `Slint` application to the server.
@@ -13,9 +14,34 @@ pub struct SlintProxy {
 }
 
 impl SlintProxy {
-    /// Opens a new client application on the given path.
-    pub fn open_client(&self, path: impl Into<String>) {
-        self.proxy.open_client(path);
+    /// Opens a new client application by the given path or `ClientCommand`.
+    pub fn open_client(&self, command: impl Into<ClientCommand>) {
+        self.proxy.open_client(command);
+    }
+
+    /// Lets running clients attach to the server, see `ServerProxy::listen`.
+    pub fn listen(&self, address: impl Into<String>) -> ServerResult<String> {
+        self.proxy.listen(address)
+    }
+
+    /// Lets running clients that know the given token attach to the server, see `ServerProxy::listen_with_token`.
+    pub fn listen_with_token(
+        &self,
+        address: impl Into<String>,
+        token: impl Into<String>,
+        permissions: Vec<coop_protocol::Permission>,
+    ) -> ServerResult<String> {
+        self.proxy.listen_with_token(address, token, permissions)
+    }
+
+    /// Records the session to the file on the given path, see `ServerProxy::record`.
+    pub fn record(&self, path: impl Into<String>) -> ServerResult<()> {
+        self.proxy.record(path)
+    }
+
+    /// Stops the running recording.
+    pub fn stop_recording(&self) -> ServerResult<()> {
+        self.proxy.stop_recording()
     }
 
     /// Sends a pointer event to the client..
@@ -41,23 +67,43 @@ impl SlintProxy {
                     .send_pointer_released_event(key.clone(), x, y, button);
                 self.proxy.send_pointer_exit_event(key);
             }
+            slint::private_unstable_api::re_exports::PointerEventKind::Move => {
+                self.proxy.send_pointer_moved_event(key, x, y);
+            }
         };
     }
 
-    /// Sends a key pressed event to the client of the given key.
-    pub fn send_key_pressed_event(&self, key: SharedString, event: KeyEvent) {
-        self.proxy.send_key_pressed_event(key, event.text);
+    /// Sends a pointer scrolled event to the client.
+    pub fn send_pointer_scrolled_event(
+        &self,
+        key: SharedString,
+        x: f32,
+        y: f32,
+        event: PointerScrollEvent,
+    ) {
+        self.proxy
+            .send_pointer_scrolled_event(key, x, y, event.delta_x, event.delta_y);
     }
 
-    /// Sends a key released event to the client of the given key.
-    pub fn send_key_released_event(&self, key: SharedString, event: KeyEvent) {
-        self.proxy.send_key_pressed_event(key, event.text);
+    /// Sends a key pressed event to the window with the keyboard focus.
+    pub fn send_key_pressed_event(&self, event: KeyEvent) {
+        self.proxy.send_key_pressed_event(convert_key_event(event));
     }
 
-    /// Sends a resize event.‚
-    // pub fn send_resize_event(&self, key: SharedString, width: f32, height: f32) {
-    //     self.proxy.send_resize_event(key, width, height);
-    // }
+    /// Sends a key released event to the window with the keyboard focus.
+    pub fn send_key_released_event(&self, event: KeyEvent) {
+        self.proxy.send_key_released_event(convert_key_event(event));
+    }
+
+    /// Moves the keyboard focus to the window with the given key.
+    pub fn focus(&self, key: SharedString) {
+        self.proxy.focus(key);
+    }
+
+    /// Sends a resize event to the client of the given key.
+    pub fn send_resize_event(&self, key: SharedString, width: f32, height: f32) {
+        self.proxy.send_resize_event(key, width, height);
+    }
 
     /// Closes the client with the given key.
     pub fn close_client(&self, key: impl Into<String>) {
@@ -75,3 +121,16 @@ impl From<ServerProxy> for SlintProxy {
         Self { proxy }
     }
 }
+
+fn convert_key_event(event: KeyEvent) -> coop_protocol::KeyEvent {
+    coop_protocol::KeyEvent {
+        repeat: event.repeat,
+        modifiers: coop_protocol::KeyboardModifiers {
+            alt: event.modifiers.alt,
+            control: event.modifiers.control,
+            shift: event.modifiers.shift,
+            meta: event.modifiers.meta,
+        },
+        ..coop_protocol::KeyEvent::from_text(event.text.as_str())
+    }
+}
@@ -0,0 +1,287 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coop_protocol::*;
+
+use crate::proxy::ServerProxy;
+
+/// Longest time records are buffered before they are written to the file of a running recording.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records the events that are sent to clients and the responses that are received from them, see
+/// `ServerProxy::record`.
+///
+/// The recorder is shared by the server and all client loops, it does nothing while no recording is started.
+/// Records are buffered and written when the recording is stopped and by `flush`, which the server calls
+/// periodically.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    recording: Arc<Mutex<Option<Recording>>>,
+}
+
+#[derive(Debug)]
+struct Recording {
+    writer: BufWriter<File>,
+    start: Instant,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    /// Creates a new recorder without a running recording.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new recording to the file on the given path, a running recording is stopped.
+    pub fn start(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let recording = Recording {
+            writer: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+            last_flush: Instant::now(),
+        };
+
+        if let Some(mut recording) = self.lock().replace(recording) {
+            recording.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops the running recording.
+    pub fn stop(&self) -> io::Result<()> {
+        match self.lock().take() {
+            Some(mut recording) => recording.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the buffered records of a running recording if they are written last more than `FLUSH_INTERVAL` ago.
+    pub fn flush(&self) {
+        let mut recording = self.lock();
+
+        let result = match recording.as_mut() {
+            Some(running) => running.flush_due(),
+            None => return,
+        };
+
+        // a recording that cannot be written is stopped, it should not break the session.
+        if result.is_err() {
+            *recording = None;
+        }
+    }
+
+    /// Returns `true` if a recording is running.
+    pub fn is_recording(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    /// Records an event that is sent to the window with the given key.
+    pub fn record_event(&self, key: &str, event: &ClientEvent) {
+        self.record(|| RecordMessage::Event {
+            key: key.into(),
+            event: event.clone(),
+        });
+    }
+
+    /// Records a response that is received from a client.
+    ///
+    /// Frame buffers are not recorded to keep recordings small, the buffer of a draw response is empty.
+    pub fn record_response(&self, response: &ClientResponse) {
+        self.record(|| {
+            let message = match &response.message {
+                ClientResponseMessage::Draw(draw) => ClientResponseMessage::Draw(DrawResponse {
+                    buffer: vec![],
+                    damage: draw.damage.clone(),
+                    ..*draw
+                }),
+                message => message.clone(),
+            };
+
+            RecordMessage::Response(ClientResponse {
+                key: response.key.clone(),
+                message,
+            })
+        });
+    }
+
+    fn record(&self, message: impl FnOnce() -> RecordMessage) {
+        let mut recording = self.lock();
+
+        let Some(running) = recording.as_mut() else {
+            return;
+        };
+
+        let record = Record {
+            time: running.start.elapsed().as_millis() as u64,
+            message: message(),
+        };
+
+        // the file is not written on each record, the lock is shared with all client loops.
+        let result = write_record(&mut running.writer, &record).and_then(|_| running.flush_due());
+
+        // a recording that cannot be written is stopped, it should not break the session.
+        if result.is_err() {
+            *recording = None;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Recording>> {
+        // the recording is only a file writer, it is still usable if another thread has panicked.
+        self.recording.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Recording {
+    // writes the buffered records if they are written last more than `FLUSH_INTERVAL` ago.
+    fn flush_due(&mut self) -> io::Result<()> {
+        if self.last_flush.elapsed() < FLUSH_INTERVAL {
+            return Ok(());
+        }
+
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
+
+/// Sends the recorded events of the client with the key `from` and of its windows to the client with the key `to`.
+///
+/// The events are sent with their recorded timing relative to the first record of `from`, the function blocks
+/// until the last event is sent. Frame callbacks and denied permissions are skipped, they are generated by the
+/// server itself.
+pub fn replay(proxy: &ServerProxy, records: &[Record], from: &str, to: &str) {
+    let mut start = None;
+    let replay_start = Instant::now();
+
+    for record in records {
+        let (key, event) = match &record.message {
+            RecordMessage::Event { key, event } => (key, Some(event)),
+            RecordMessage::Response(response) => (&response.key, None),
+        };
+
+        // keys of additional windows start with the key of their client.
+        let Some(window) = key
+            .strip_prefix(from)
+            .filter(|rest| rest.is_empty() || rest.starts_with(':'))
+        else {
+            continue;
+        };
+
+        let start = *start.get_or_insert(record.time);
+
+        let Some(event) = event else {
+            continue;
+        };
+
+        if matches!(
+            event,
+            ClientEvent::FrameDone
+                | ClientEvent::PermissionDenied(_)
+                | ClientEvent::OpenClientFailed(_)
+                | ClientEvent::AuthenticationFailed
+        ) {
+            continue;
+        }
+
+        // records that are not ordered by time are sent without delay.
+        let time = Duration::from_millis(record.time.saturating_sub(start));
+        thread::sleep(time.saturating_sub(replay_start.elapsed()));
+
+        proxy.send_client_event(format!("{to}{window}"), event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::Server;
+
+    #[test]
+    fn test_record_replay() {
+        let mut server = Server::new();
+        let path = std::env::temp_dir().join(format!("coop_record_{}.jsonl", std::process::id()));
+        server.proxy().record(path.to_string_lossy()).unwrap();
+
+        let (env, client) = spawn_client(&server, "fake_client");
+        wait_for(&mut server, |r| {
+            matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+
+        server
+            .proxy()
+            .send_pointer_moved_event(env.client_key.clone(), 1., 0.);
+        server.proxy().close_client(env.client_key.clone());
+        assert_eq!(client.join().unwrap().len(), 1);
+
+        server.proxy().stop_recording().unwrap();
+
+        let records = read_records(io::BufReader::new(File::open(&path).unwrap())).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let messages: Vec<&RecordMessage> = records.iter().map(|r| &r.message).collect();
+        assert!(matches!(
+            messages[..],
+            [
+                RecordMessage::Response(ClientResponse {
+                    message: ClientResponseMessage::Created(_),
+                    ..
+                }),
+                // frame buffers are not recorded.
+                RecordMessage::Response(ClientResponse {
+                    message: ClientResponseMessage::Draw(DrawResponse { ref buffer, .. }),
+                    ..
+                }),
+                RecordMessage::Event {
+                    event: ClientEvent::PointerMoved { .. },
+                    ..
+                },
+                RecordMessage::Event {
+                    event: ClientEvent::Close,
+                    ..
+                },
+            ] if buffer.is_empty()
+        ));
+
+        // the recorded events are replayed into another client.
+        let (replay_env, replay_client) = spawn_client(&server, "fake_client");
+        replay(
+            &server.proxy(),
+            &records,
+            &env.client_key,
+            &replay_env.client_key,
+        );
+
+        let events = replay_client.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            ClientEvent::PointerMoved { position: (x, y) } if x == 1. && y == 0.
+        ));
+    }
+
+    #[test]
+    fn test_flush() {
+        let path = std::env::temp_dir().join(format!("coop_flush_{}.jsonl", std::process::id()));
+        let recorder = Recorder::new();
+        recorder.start(&path).unwrap();
+
+        // the last record is written without a further record.
+        recorder.record_event("client", &ClientEvent::Close);
+        recorder.flush();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        thread::sleep(FLUSH_INTERVAL);
+        recorder.flush();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+
+        recorder.stop().unwrap();
+        std::fs::remove_file(&path).ok();
+    }
+}
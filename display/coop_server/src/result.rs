@@ -7,6 +7,9 @@ pub type ServerResult<T> = Result<T, ServerError>;
 #[derive(Clone, Debug)]
 pub enum ServerError {
     CannotOpenClient(String),
+    CannotListen(String),
+    CannotRecord(String),
+    CannotCapture(String),
     CannotSendEvent(String),
     CannotClose(String),
 }
@@ -0,0 +1,313 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::io;
+use std::thread;
+
+use coop_protocol::*;
+use tokio_unix_ipc::Receiver;
+
+use crate::proxy::ClientEnv;
+use crate::Server;
+
+/// Scripted client that runs in process and speaks the coop protocol.
+///
+/// Use it with `ServerProxy::open_in_process_client` to test a `Server` without spawning client apps.
+pub struct FakeClient {
+    key: String,
+    sender: Box<dyn MessageSender>,
+    receiver: Box<dyn MessageReceiver>,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl FakeClient {
+    /// Connects to the server with the given environment and sends the creation response.
+    pub fn connect(
+        env: &ClientEnv,
+        title: impl Into<String>,
+        width: f32,
+        height: f32,
+    ) -> io::Result<Self> {
+        Self::connect_with_capabilities(env, title, width, height, default_capabilities())
+    }
+
+    /// Connects like `connect` but negotiates the given capabilities as far as the server supports them.
+    pub fn connect_with_capabilities(
+        env: &ClientEnv,
+        title: impl Into<String>,
+        width: f32,
+        height: f32,
+        capabilities: Vec<Capability>,
+    ) -> io::Result<Self> {
+        Self::connect_with_response(
+            env,
+            CreationResponse {
+                title: title.into(),
+                path: env.client_path.clone(),
+                width,
+                height,
+                version: VERSION,
+                capabilities,
+                decoration_mode: DecorationMode::Server,
+            },
+        )
+    }
+
+    /// Connects like `connect` but answers the creation request with the given response, e.g. to fake an other
+    /// protocol version.
+    pub fn connect_with_response(env: &ClientEnv, response: CreationResponse) -> io::Result<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        let receiver = runtime.block_on(Receiver::<ClientMessage>::connect(&env.server_key))?;
+        let mut request = runtime.block_on(read_creation_request(&receiver))?;
+        let sender = request
+            .sender
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing sender"))?;
+        runtime.block_on(sender.send(ClientMessage::Authenticate(env.token.clone())))?;
+
+        Self::create(
+            runtime,
+            Box::new(sender),
+            Box::new(receiver),
+            request,
+            response,
+        )
+    }
+
+    /// Opens an in-process client with the given path on the server and connects to it with a window of 2x1
+    /// pixels.
+    pub fn open(server: &Server, path: &str) -> io::Result<(ClientEnv, Self)> {
+        let env = server
+            .proxy()
+            .open_in_process_client(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, format!("{:?}", e)))?;
+        let client = Self::connect(&env, "fake", 2., 1.)?;
+
+        Ok((env, client))
+    }
+
+    /// Attaches to a server that listens on the given address and sends the creation response.
+    pub fn attach(
+        address: &str,
+        title: impl Into<String>,
+        width: f32,
+        height: f32,
+    ) -> io::Result<Self> {
+        Self::attach_with_token(address, None, title, width, height)
+    }
+
+    /// Attaches like `attach` and authenticates with the given token first, see `ServerProxy::listen_with_token`.
+    pub fn attach_with_token(
+        address: &str,
+        token: Option<&str>,
+        title: impl Into<String>,
+        width: f32,
+        height: f32,
+    ) -> io::Result<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        let (_, sender, receiver) = runtime.block_on(coop_protocol::connect(address))?;
+        let request = runtime.block_on(read_creation_request(receiver.as_ref()))?;
+
+        if let Some(token) = token {
+            runtime.block_on(sender.send(ClientMessage::Authenticate(token.into())))?;
+        }
+
+        Self::create(
+            runtime,
+            sender,
+            receiver,
+            request,
+            CreationResponse {
+                title: title.into(),
+                path: address.into(),
+                width,
+                height,
+                version: VERSION,
+                capabilities: default_capabilities(),
+                decoration_mode: DecorationMode::Server,
+            },
+        )
+    }
+
+    fn create(
+        runtime: tokio::runtime::Runtime,
+        sender: Box<dyn MessageSender>,
+        receiver: Box<dyn MessageReceiver>,
+        request: CreationRequest,
+        response: CreationResponse,
+    ) -> io::Result<Self> {
+        let capabilities = request
+            .capabilities
+            .into_iter()
+            .filter(|c| response.capabilities.contains(c))
+            .collect();
+
+        let decoration_mode = if request.decoration_modes.contains(&response.decoration_mode) {
+            response.decoration_mode
+        } else {
+            request
+                .decoration_modes
+                .first()
+                .copied()
+                .unwrap_or_default()
+        };
+
+        runtime.block_on(sender.send(ClientMessage::Response(ClientResponse {
+            key: request.key.clone(),
+            message: ClientResponseMessage::Created(CreationResponse {
+                capabilities,
+                decoration_mode,
+                ..response
+            }),
+        })))?;
+
+        Ok(Self {
+            key: request.key,
+            sender,
+            receiver,
+            runtime,
+        })
+    }
+
+    /// Gets the connection key of the client.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Sends the given response message to the server.
+    pub fn respond(&self, message: ClientResponseMessage) -> io::Result<()> {
+        self.respond_for(self.key.clone(), message)
+    }
+
+    /// Sends the given response message of the window with the given key to the server.
+    pub fn respond_for(
+        &self,
+        key: impl Into<String>,
+        message: ClientResponseMessage,
+    ) -> io::Result<()> {
+        self.send(ClientMessage::Response(ClientResponse {
+            key: key.into(),
+            message,
+        }))
+    }
+
+    /// Sends a frame buffer with pixels of the format `PixelFormat::Rgba8888` to the server.
+    pub fn draw(&self, buffer: Vec<u8>, width: f32, height: f32) -> io::Result<()> {
+        self.respond(ClientResponseMessage::Draw(DrawResponse {
+            buffer,
+            width,
+            height,
+            damage: vec![],
+            format: PixelFormat::Rgba8888,
+            stride: PixelFormat::Rgba8888.stride(width as u32),
+        }))
+    }
+
+    /// Waits for the next event of the main window. Returns `None` if the connection is lost.
+    ///
+    /// Events of additional windows are skipped.
+    pub fn next_event(&self) -> Option<ClientEvent> {
+        loop {
+            match self.next_window_event()? {
+                (key, event) if key == self.key => return Some(event),
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for the next event of any window with the key of the window. Returns `None` if the connection is lost.
+    pub fn next_window_event(&self) -> Option<(String, ClientEvent)> {
+        match self.runtime.block_on(self.receiver.recv()) {
+            Ok(ClientMessage::Event(event)) => Some((self.key.clone(), event)),
+            Ok(ClientMessage::WindowEvent { key, event }) => Some((key, event)),
+            _ => None,
+        }
+    }
+
+    /// Reads events until the server requests to close the client, then closes it.
+    ///
+    /// Returns all events that are received before the close request.
+    pub fn run_until_close(&self) -> io::Result<Vec<ClientEvent>> {
+        let mut events = vec![];
+
+        while let Some(event) = self.next_event() {
+            if let ClientEvent::Close = event {
+                self.close()?;
+                break;
+            }
+
+            events.push(event);
+        }
+
+        Ok(events)
+    }
+
+    /// Tells the server that the client is closed.
+    pub fn close(&self) -> io::Result<()> {
+        self.send(ClientMessage::Closed(self.key.clone()))
+    }
+
+    fn send(&self, message: ClientMessage) -> io::Result<()> {
+        self.runtime.block_on(self.sender.send(message))
+    }
+}
+
+// the fake client sends its frames with the draw message and does not wait for frame callbacks by default.
+fn default_capabilities() -> Vec<Capability> {
+    Capability::all()
+        .into_iter()
+        .filter(|c| !matches!(c, Capability::SharedMemory | Capability::FrameCallbacks))
+        .collect()
+}
+
+async fn read_creation_request(receiver: &dyn MessageReceiver) -> io::Result<CreationRequest> {
+    match receiver.recv().await? {
+        ClientMessage::Create(request) => Ok(request),
+        message => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected message {:?}", message),
+        )),
+    }
+}
+
+/// Opens a fake client like `FakeClient::open` on its own thread, it draws a white frame and runs until the server
+/// closes it.
+///
+/// The thread returns the events that the client has received before it was closed.
+pub fn spawn_client(
+    server: &Server,
+    path: &str,
+) -> (ClientEnv, thread::JoinHandle<Vec<ClientEvent>>) {
+    let env = server.proxy().open_in_process_client(path).unwrap();
+
+    let client = thread::spawn({
+        let env = env.clone();
+        move || {
+            let client = FakeClient::connect(&env, "fake", 2., 1.).unwrap();
+            client.draw(vec![255; 8], 2., 1.).unwrap();
+            client.run_until_close().unwrap()
+        }
+    });
+
+    (env, client)
+}
+
+/// Reads the client responses of the server until `matches` returns `true` and returns that response.
+///
+/// Panics if the server is gone before.
+pub fn wait_for(
+    server: &mut Server,
+    mut matches: impl FnMut(&ClientResponse) -> bool,
+) -> ClientResponse {
+    loop {
+        let response = server
+            .client_response()
+            .expect("Server is gone before the expected response.");
+
+        if matches(&response) {
+            return response;
+        }
+    }
+}
@@ -0,0 +1,680 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use coop_protocol::*;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+use crate::capture::{FrameStore, Image};
+use crate::compositor::{Compositor, Decoration};
+use crate::proxy::ServerProxy;
+use crate::window_manager::{Geometry, Hit, LayoutPolicy, WindowManager};
+use crate::Server;
+
+pub mod rfb;
+
+use rfb::{RfbMessage, RfbPixelFormat};
+
+/// Name of the desktop that is shown by the viewers.
+pub const DESKTOP_NAME: &str = "coop";
+
+/// Distance in pixels of one step of the scroll wheel.
+pub const SCROLL_STEP: f32 = 20.;
+
+// buttons of the RFB button mask.
+const POINTER_BUTTONS: [(u8, PointerEventButton); 3] = [
+    (1, PointerEventButton::Left),
+    (2, PointerEventButton::Middle),
+    (4, PointerEventButton::Right),
+];
+
+// mask of all buttons of `POINTER_BUTTONS`.
+const POINTER_BUTTON_MASK: u8 = 7;
+
+// scroll wheel directions of the RFB button mask: up, down, left and right.
+const WHEEL_BUTTONS: [(u8, f32, f32); 4] = [
+    (8, 0., SCROLL_STEP),
+    (16, 0., -SCROLL_STEP),
+    (32, SCROLL_STEP, 0.),
+    (64, -SCROLL_STEP, 0.),
+];
+
+/// Shows the windows of a `Server` to VNC viewers and forwards the pointer and key input of the viewers to the
+/// clients, e.g. to run the server on a headless machine or a kiosk.
+///
+/// The VNC server is its own window manager: feed it with the responses of `Server::client_response` or let
+/// `run` do it. The windows are composed with decorations by a `Compositor` into a display of a fixed size, all
+/// viewers share this display. Windows are moved by their title bar and resized by their border, windows with
+/// `DecorationMode::Client` request it with `ClientResponseMessage::StartMove` and
+/// `ClientResponseMessage::StartResize`. Viewers connect without authentication, bind it to a trusted network
+/// only.
+pub struct VncServer {
+    shared: Arc<Shared>,
+    local_address: SocketAddr,
+
+    // the viewers are disconnected when the runtime is dropped.
+    _runtime: tokio::runtime::Runtime,
+}
+
+// state that is shared by the VNC server and all viewer connections.
+#[derive(Debug)]
+struct Shared {
+    display: Mutex<Display>,
+    proxy: ServerProxy,
+
+    // notifies the viewers that the display has been composed.
+    updates: watch::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Display {
+    window_manager: WindowManager,
+    frames: FrameStore,
+    compositor: Compositor,
+
+    // key of the top level window that gets the key events.
+    focused: Option<String>,
+
+    // display position of the pointer of a viewer while one of its buttons is pressed.
+    pointer: Option<(f32, f32)>,
+}
+
+impl VncServer {
+    /// Listens for VNC viewers on the given TCP address and shows them a display of the given size.
+    ///
+    /// Use port `0` to let the system choose a free port, see `local_address`.
+    pub fn bind(
+        address: impl ToSocketAddrs,
+        width: u16,
+        height: u16,
+        proxy: ServerProxy,
+    ) -> io::Result<Self> {
+        let listener = StdTcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_address = listener.local_addr()?;
+
+        let compositor = Compositor::new(width as u32, height as u32);
+        let mut window_manager = WindowManager::new();
+        window_manager.set_area(Geometry::new(0., 0., width as f32, height as f32));
+        window_manager.set_frame_insets(compositor.decoration().insets());
+
+        let mut display = Display {
+            window_manager,
+            frames: FrameStore::new(),
+            compositor,
+            focused: None,
+            pointer: None,
+        };
+        display.compose();
+
+        let shared = Arc::new(Shared {
+            display: Mutex::new(display),
+            proxy,
+            updates: watch::channel(()).0,
+        });
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let listener = {
+            let _guard = runtime.enter();
+            TcpListener::from_std(listener)?
+        };
+        runtime.spawn(accept_viewers(listener, shared.clone()));
+
+        Ok(Self {
+            shared,
+            local_address,
+            _runtime: runtime,
+        })
+    }
+
+    /// Returns the address the VNC server listens on.
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Sets the appearance of the window decorations, the windows are arranged with their decorations.
+    pub fn set_decoration(&self, decoration: Decoration) {
+        let mut display = self.shared.lock();
+        display.compositor.set_decoration(decoration);
+        display.window_manager.set_frame_insets(decoration.insets());
+        self.shared.update(&mut display);
+    }
+
+    /// Sets the policy that places and arranges the top level windows, e.g. `MasterStack` for a kiosk.
+    pub fn set_layout(&self, layout: impl LayoutPolicy + 'static) {
+        let mut display = self.shared.lock();
+        display.window_manager.set_layout(layout);
+        self.shared.update(&mut display);
+    }
+
+    /// Updates the display from the given client response.
+    ///
+    /// New top level windows get the keyboard focus, sizes that are assigned by the layout are sent to the clients.
+    pub fn handle_response(&self, response: &ClientResponse) {
+        let mut display = self.shared.lock();
+        let key = &response.key;
+        display.window_manager.handle_response(response);
+
+        match &response.message {
+            ClientResponseMessage::Created(_)
+            | ClientResponseMessage::WindowCreated(WindowCreationResponse {
+                kind: WindowKind::TopLevel,
+                ..
+            }) => {
+                self.shared.focus(&mut display, Some(key.clone()));
+            }
+            ClientResponseMessage::Draw(draw) => display.frames.update(key, draw),
+            ClientResponseMessage::Closed | ClientResponseMessage::Crashed { .. } => {
+                display.frames.remove_client(key)
+            }
+            ClientResponseMessage::WindowClosed => display.frames.remove(key),
+            // the window follows the pointer until its buttons are released.
+            ClientResponseMessage::StartMove => {
+                if let Some((x, y)) = display.pointer {
+                    display.window_manager.start_move(key, x, y);
+                }
+            }
+            ClientResponseMessage::StartResize(edge) => {
+                if let Some((x, y)) = display.pointer {
+                    display.window_manager.start_resize(key, *edge, x, y);
+                }
+            }
+            _ => {}
+        }
+
+        // the top most top level window gets the focus if the focused window is gone.
+        if display
+            .focused
+            .as_ref()
+            .is_some_and(|focused| display.window_manager.window(focused).is_none())
+        {
+            let top = display
+                .window_manager
+                .windows()
+                .iter()
+                .rev()
+                .find(|w| w.parent.is_none())
+                .map(|w| w.key.clone());
+            self.shared.focus(&mut display, top);
+        }
+
+        self.shared.update(&mut display);
+    }
+
+    /// Handles the responses of the server until all clients are closed.
+    pub fn run(&self, server: &mut Server) {
+        while let Some(response) = server.client_response() {
+            if let ClientResponseMessage::AllClosed = response.message {
+                break;
+            }
+
+            self.handle_response(&response);
+        }
+    }
+
+    /// Returns the current content of the display.
+    pub fn output(&self) -> Image {
+        self.shared.lock().compositor.output().clone()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Display> {
+        // the display is still usable if a viewer task has panicked while holding the lock.
+        self.display.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn focus(&self, display: &mut Display, key: Option<String>) {
+        if display.focused == key {
+            return;
+        }
+
+        match &key {
+            Some(key) => self.proxy.focus(key.clone()),
+            None => self.proxy.clear_focus(),
+        }
+
+        display.focused = key;
+    }
+
+    // sends the sizes that are assigned by the layout, composes the display and notifies the viewers.
+    fn update(&self, display: &mut Display) {
+        for (key, width, height) in display.window_manager.take_resize_requests() {
+            self.proxy.send_resize_event(key, width, height);
+        }
+
+        display.compose();
+        self.updates.send_replace(());
+    }
+}
+
+impl Display {
+    fn compose(&mut self) {
+        self.compositor.compose_windows(
+            &self.frames,
+            &self.window_manager,
+            self.focused.as_deref(),
+        );
+    }
+
+    // key of the top level window that has opened the window with the given key.
+    fn top_level(&self, key: &str) -> String {
+        let mut keys = vec![key.to_string()];
+
+        // parents are followed only once, windows of a broken client could form a cycle.
+        while let Some(parent) = keys
+            .last()
+            .and_then(|key| self.window_manager.window(key))
+            .and_then(|w| w.parent.clone())
+            .filter(|parent| !keys.contains(parent))
+        {
+            keys.push(parent);
+        }
+
+        keys.pop().unwrap_or_default()
+    }
+}
+
+async fn accept_viewers(listener: TcpListener, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let shared = shared.clone();
+
+        // a viewer that sends invalid messages or disconnects ends its own connection, its pressed buttons and
+        // keys are released by `serve_viewer`.
+        tokio::spawn(async move {
+            let _ = serve_viewer(stream, shared).await;
+        });
+    }
+}
+
+// input state of one viewer.
+#[derive(Debug, Default)]
+struct Input {
+    buttons: u8,
+
+    // window that gets all pointer events while a button is pressed.
+    grab: Option<String>,
+    hovered: Option<String>,
+
+    modifiers: KeyboardModifiers,
+    pressed_keys: HashSet<u32>,
+}
+
+async fn serve_viewer(stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = stream.into_split();
+
+    let (width, height) = {
+        let display = shared.lock();
+        let output = display.compositor.output();
+        (output.width as u16, output.height as u16)
+    };
+
+    rfb::handshake(&mut reader, &mut writer, width, height, DESKTOP_NAME).await?;
+
+    // reads the messages in their own task, reading is not cancel safe.
+    let (message_sender, mut messages) = mpsc::unbounded_channel();
+    let read_task = tokio::spawn(async move {
+        while let Ok(message) = rfb::read_message(&mut reader).await {
+            if message_sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut updates = shared.updates.subscribe();
+    let mut format = RfbPixelFormat::default();
+    let mut input = Input::default();
+
+    // pending update request, `true` if only the changes since the last update are requested.
+    let mut request: Option<bool> = None;
+    let mut last: Option<Image> = None;
+
+    let result = loop {
+        tokio::select! {
+            message = messages.recv() => {
+                let Some(message) = message else {
+                    break Ok(());
+                };
+
+                match message {
+                    RfbMessage::SetPixelFormat(pixel_format) => {
+                        if !pixel_format.is_supported() {
+                            break Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Unsupported pixel format",
+                            ));
+                        }
+
+                        format = pixel_format;
+                        last = None;
+                    }
+                    // only the raw encoding is used, every viewer supports it.
+                    RfbMessage::SetEncodings(_) => {}
+                    RfbMessage::FramebufferUpdateRequest { incremental, .. } => {
+                        request = Some(request.unwrap_or(true) && incremental);
+                    }
+                    RfbMessage::KeyEvent { down, keysym } => {
+                        handle_key(&shared, &mut input, down, keysym)
+                    }
+                    RfbMessage::PointerEvent { buttons, x, y } => {
+                        handle_pointer(&shared, &mut input, buttons, x as f32, y as f32)
+                    }
+                    RfbMessage::ClientCutText(text) => shared.proxy.set_clipboard_text(text),
+                }
+            }
+            _ = updates.changed() => {}
+        }
+
+        let Some(incremental) = request else {
+            continue;
+        };
+
+        let image = shared.lock().compositor.output().clone();
+
+        let previous = if incremental { last.as_ref() } else { None };
+
+        // an incremental request without changes is answered by the next update.
+        if let Some(rect) = rfb::changed_rect(previous, &image) {
+            if let Err(e) = rfb::write_framebuffer_update(&mut writer, &image, rect, &format).await
+            {
+                break Err(e);
+            }
+
+            last = Some(image);
+            request = None;
+        }
+    };
+
+    read_task.abort();
+
+    // the windows and the other viewers do not keep the input state of a closed connection.
+    if input.buttons != 0 {
+        let pointer = shared.lock().pointer;
+
+        if let Some((x, y)) = pointer {
+            handle_pointer(&shared, &mut input, 0, x, y);
+        }
+    }
+
+    for keysym in input.pressed_keys.clone() {
+        handle_key(&shared, &mut input, false, keysym);
+    }
+
+    if let Some(key) = input.hovered {
+        shared.proxy.send_pointer_exit_event(key);
+    }
+
+    result
+}
+
+fn handle_pointer(shared: &Shared, input: &mut Input, buttons: u8, x: f32, y: f32) {
+    let proxy = &shared.proxy;
+    let mut display = shared.lock();
+
+    let pressed = buttons & !input.buttons;
+    let released = input.buttons & !buttons;
+    input.buttons = buttons;
+    display.pointer = (buttons & POINTER_BUTTON_MASK != 0).then_some((x, y));
+
+    // a running interactive move or resize gets the pointer until its buttons are released.
+    if display.window_manager.interaction().is_some() {
+        if buttons & POINTER_BUTTON_MASK != 0 {
+            if display.window_manager.update_interaction(x, y) {
+                shared.update(&mut display);
+            }
+
+            return;
+        }
+
+        display.window_manager.end_interaction();
+    }
+
+    let hit = match &input.grab {
+        Some(key) => display.window_manager.window(key).map(|w| Hit {
+            key: key.clone(),
+            x: x - w.geometry.x,
+            y: y - w.geometry.y,
+        }),
+        None => display.window_manager.frame_at(x, y),
+    };
+
+    // the window under the pointer gets the pointer events, its decorations are handled by the VNC server.
+    let content = hit.clone().filter(|hit| {
+        input.grab.is_some()
+            || display.window_manager.window(&hit.key).is_some_and(|w| {
+                (0. ..w.geometry.width).contains(&hit.x)
+                    && (0. ..w.geometry.height).contains(&hit.y)
+            })
+    });
+
+    let key = content.as_ref().map(|h| h.key.clone());
+
+    if input.hovered != key {
+        if let Some(hovered) = input.hovered.take() {
+            proxy.send_pointer_exit_event(hovered);
+        }
+
+        input.hovered = key;
+    }
+
+    // a press raises the window and moves the focus to it.
+    if let Some(hit) = hit.as_ref().filter(|_| pressed & POINTER_BUTTON_MASK != 0) {
+        let top_level = display.top_level(&hit.key);
+        display.window_manager.raise(&top_level);
+        shared.focus(&mut display, Some(top_level));
+        shared.update(&mut display);
+    }
+
+    let Some(Hit { key, x, y }) = content else {
+        // the title bar moves the window, the border resizes it.
+        if let Some(hit) = hit.filter(|_| pressed & 1 != 0) {
+            let decoration = display.compositor.decoration();
+            let edge = display.window_manager.window(&hit.key).and_then(|w| {
+                decoration_edge(&decoration, &hit, w.geometry.width, w.geometry.height)
+            });
+
+            match edge {
+                Some(edge) => display.window_manager.start_resize(&hit.key, edge, x, y),
+                None => display.window_manager.start_move(&hit.key, x, y),
+            };
+        }
+
+        input.grab = None;
+        return;
+    };
+
+    proxy.send_pointer_moved_event(key.clone(), x, y);
+
+    for (mask, button) in POINTER_BUTTONS {
+        if pressed & mask != 0 {
+            proxy.send_pointer_pressed_event(key.clone(), x, y, button.clone());
+        }
+
+        if released & mask != 0 {
+            proxy.send_pointer_released_event(key.clone(), x, y, button);
+        }
+    }
+
+    for (mask, delta_x, delta_y) in WHEEL_BUTTONS {
+        if pressed & mask != 0 {
+            proxy.send_pointer_scrolled_event(key.clone(), x, y, delta_x, delta_y);
+        }
+    }
+
+    input.grab = (buttons & POINTER_BUTTON_MASK != 0).then_some(key);
+}
+
+// edge of the border at the given position relative to a window, `None` on the title bar.
+fn decoration_edge(
+    decoration: &Decoration,
+    hit: &Hit,
+    width: f32,
+    height: f32,
+) -> Option<ResizeEdge> {
+    let top = hit.y < -(decoration.title_bar_height as f32);
+    let bottom = hit.y >= height;
+    let left = hit.x < 0.;
+    let right = hit.x >= width;
+
+    let edge = match (top, bottom, left, right) {
+        (true, _, true, _) => ResizeEdge::TopLeft,
+        (true, _, _, true) => ResizeEdge::TopRight,
+        (_, true, true, _) => ResizeEdge::BottomLeft,
+        (_, true, _, true) => ResizeEdge::BottomRight,
+        (true, ..) => ResizeEdge::Top,
+        (_, true, ..) => ResizeEdge::Bottom,
+        (_, _, true, _) => ResizeEdge::Left,
+        (_, _, _, true) => ResizeEdge::Right,
+        _ => return None,
+    };
+
+    Some(edge)
+}
+
+fn handle_key(shared: &Shared, input: &mut Input, down: bool, keysym: u32) {
+    let Some(key) = rfb::keysym_to_key(keysym) else {
+        return;
+    };
+
+    let repeat = if down {
+        !input.pressed_keys.insert(keysym)
+    } else {
+        input.pressed_keys.remove(&keysym);
+        false
+    };
+
+    match key {
+        Key::Shift | Key::ShiftR => input.modifiers.shift = down,
+        Key::Control | Key::ControlR => input.modifiers.control = down,
+        Key::Alt | Key::AltGr => input.modifiers.alt = down,
+        Key::Meta | Key::MetaR => input.modifiers.meta = down,
+        _ => {}
+    }
+
+    let event = KeyEvent {
+        modifiers: input.modifiers,
+        repeat,
+        ..KeyEvent::from_text(key.text())
+    };
+
+    if down {
+        shared.proxy.send_key_pressed_event(event);
+    } else {
+        shared.proxy.send_key_released_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_vnc() {
+        let mut server = Server::new();
+        let vnc = VncServer::bind("127.0.0.1:0", 20, 20, server.proxy()).unwrap();
+        vnc.set_decoration(Decoration {
+            title_bar_height: 2,
+            ..Decoration::default()
+        });
+
+        let env = server
+            .proxy()
+            .open_in_process_client("fake_client")
+            .unwrap();
+        let client = thread::spawn(move || {
+            let client = FakeClient::connect(&env, "fake", 2., 1.).unwrap();
+            client.draw(vec![255; 8], 2., 1.).unwrap();
+
+            // the client closes itself after the key is released.
+            let mut events = vec![];
+            while let Some(event) = client.next_event() {
+                let released = matches!(event, ClientEvent::KeyReleased(_));
+                events.push(event);
+
+                if released {
+                    break;
+                }
+            }
+
+            client.close().unwrap();
+            events
+        });
+
+        wait_for(&mut server, |r| {
+            vnc.handle_response(r);
+            matches!(r.message, ClientResponseMessage::Draw(_))
+        });
+
+        let mut viewer = TcpStream::connect(vnc.local_address()).unwrap();
+        let mut reader = viewer.try_clone().unwrap();
+        let mut read = |len: usize| {
+            let mut buffer = vec![0; len];
+            reader.read_exact(&mut buffer).unwrap();
+            buffer
+        };
+
+        assert_eq!(read(12), b"RFB 003.008\n");
+        viewer.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(read(2), vec![1, 1]);
+        viewer.write_all(&[1]).unwrap();
+        assert_eq!(read(4), vec![0; 4]);
+        viewer.write_all(&[1]).unwrap();
+
+        // server init with the display size, the pixel format and the name.
+        let init = read(24);
+        assert_eq!(&init[..4], &[0, 20, 0, 20]);
+        let name_len = u32::from_be_bytes([init[20], init[21], init[22], init[23]]);
+        assert_eq!(read(name_len as usize), DESKTOP_NAME.as_bytes());
+
+        viewer.write_all(&[3, 0, 0, 0, 0, 0, 0, 20, 0, 20]).unwrap();
+        assert_eq!(&read(4), &[0, 0, 0, 1]);
+        assert_eq!(&read(12), &[0, 0, 0, 0, 0, 20, 0, 20, 0, 0, 0, 0]);
+        let pixels = read(20 * 20 * 4);
+
+        // the window is placed inside of the decorations, the pixels are sent as blue, green, red and padding.
+        let pixel = |x: usize, y: usize| &pixels[(y * 20 + x) * 4..(y * 20 + x) * 4 + 4];
+        assert_eq!(pixel(11, 13), &[255, 255, 255, 0]);
+        assert_eq!(pixel(12, 13), &[255, 255, 255, 0]);
+        assert_eq!(pixel(15, 13), &[0, 0, 0, 0]);
+
+        // left click on the second pixel of the window and the key `a`.
+        viewer.write_all(&[5, 1, 0, 12, 0, 13]).unwrap();
+        viewer.write_all(&[5, 0, 0, 12, 0, 13]).unwrap();
+        viewer.write_all(&[4, 1, 0, 0, 0, 0, 0, 0x61]).unwrap();
+        viewer.write_all(&[4, 0, 0, 0, 0, 0, 0, 0x61]).unwrap();
+
+        wait_for(&mut server, |r| {
+            vnc.handle_response(r);
+            matches!(r.message, ClientResponseMessage::Closed)
+        });
+
+        let events = client.join().unwrap();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ClientEvent::PointerPressed {
+                position: (x, y),
+                button: PointerEventButton::Left
+            } if (*x, *y) == (1., 0.)
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ClientEvent::KeyPressed(KeyEvent {
+                key: Key::Character(text),
+                ..
+            }) if text == "a"
+        )));
+
+        // the window is removed from the display.
+        assert_eq!(vnc.output().pixel(11, 13), Some([0, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,648 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+//! Server side of the remote frame buffer protocol (RFB) that is spoken by VNC viewers, see RFC 6143.
+
+use std::io;
+
+use coop_protocol::Key;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::capture::Image;
+
+/// Protocol version that is offered to the viewers.
+const VERSION: &[u8; 12] = b"RFB 003.008\n";
+
+/// Security type without authentication.
+const SECURITY_NONE: u8 = 1;
+
+/// Maximum length of a cut text that is accepted from a viewer.
+const MAX_CUT_TEXT: usize = 1024 * 1024;
+
+/// Raw encoding of frame buffer rectangles.
+const ENCODING_RAW: i32 = 0;
+
+/// Layout of the pixels that are sent to a viewer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RfbPixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub true_colour: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl Default for RfbPixelFormat {
+    // 32 bits true colour that is sent as blue, green, red and padding.
+    fn default() -> Self {
+        Self {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian: false,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        }
+    }
+}
+
+impl RfbPixelFormat {
+    /// Reads the pixel format from its wire representation.
+    pub fn from_bytes(bytes: &[u8; 16]) -> Self {
+        Self {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            true_colour: bytes[3] != 0,
+            red_max: u16::from_be_bytes([bytes[4], bytes[5]]),
+            green_max: u16::from_be_bytes([bytes[6], bytes[7]]),
+            blue_max: u16::from_be_bytes([bytes[8], bytes[9]]),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        }
+    }
+
+    /// Returns the wire representation of the pixel format.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let [red_max_0, red_max_1] = self.red_max.to_be_bytes();
+        let [green_max_0, green_max_1] = self.green_max.to_be_bytes();
+        let [blue_max_0, blue_max_1] = self.blue_max.to_be_bytes();
+
+        [
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            self.true_colour as u8,
+            red_max_0,
+            red_max_1,
+            green_max_0,
+            green_max_1,
+            blue_max_0,
+            blue_max_1,
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    /// Returns `true` if pixels can be encoded in this format. Colour maps are not supported and each channel must
+    /// fit into the bits of a pixel.
+    pub fn is_supported(&self) -> bool {
+        let bits = self.bits_per_pixel as u32;
+
+        // the shifted maximum is computed with 64 bits, a 32 bits pixel has no room for an overflow.
+        let fits =
+            |max: u16, shift: u8| (shift as u32) < bits && (max as u64) << shift < 1u64 << bits;
+
+        self.true_colour
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && fits(self.red_max, self.red_shift)
+            && fits(self.green_max, self.green_shift)
+            && fits(self.blue_max, self.blue_shift)
+    }
+
+    /// Appends the given pixels of the format `PixelFormat::Rgba8888` in this format to `out`.
+    ///
+    /// Channels of an unsupported format that do not fit into a pixel are cut off, see `is_supported`.
+    pub fn encode(&self, rgba: &[u8], out: &mut Vec<u8>) {
+        let bytes = (self.bits_per_pixel as usize / 8).clamp(1, 4);
+
+        for pixel in rgba.chunks_exact(4) {
+            let value = shifted(channel(pixel[0], self.red_max), self.red_shift)
+                | shifted(channel(pixel[1], self.green_max), self.green_shift)
+                | shifted(channel(pixel[2], self.blue_max), self.blue_shift);
+
+            if self.big_endian {
+                out.extend_from_slice(&value.to_be_bytes()[4 - bytes..]);
+            } else {
+                out.extend_from_slice(&value.to_le_bytes()[..bytes]);
+            }
+        }
+    }
+}
+
+// scales an 8 bit channel to the given maximum.
+fn channel(value: u8, max: u16) -> u32 {
+    (value as u32 * max as u32 + 127) / 255
+}
+
+// shifts a channel to its position in a pixel, shifts beyond the pixel value drop the channel.
+fn shifted(value: u32, shift: u8) -> u32 {
+    value.checked_shl(shift as u32).unwrap_or_default()
+}
+
+/// Message from a viewer to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RfbMessage {
+    SetPixelFormat(RfbPixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest {
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    KeyEvent {
+        down: bool,
+        keysym: u32,
+    },
+    PointerEvent {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+    ClientCutText(String),
+}
+
+/// Rectangle of the frame buffer in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Runs the handshake with a viewer without authentication up to the server init message.
+pub async fn handshake(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    width: u16,
+    height: u16,
+    name: &str,
+) -> io::Result<()> {
+    writer.write_all(VERSION).await?;
+
+    let mut version = [0; 12];
+    reader.read_exact(&mut version).await?;
+
+    if !version.starts_with(b"RFB 003.") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported RFB version",
+        ));
+    }
+
+    // version 3.3 lets the server decide the security type, later versions let the viewer choose.
+    if &version[8..11] == b"003" {
+        writer.write_u32(SECURITY_NONE as u32).await?;
+    } else {
+        writer.write_all(&[1, SECURITY_NONE]).await?;
+
+        if reader.read_u8().await? != SECURITY_NONE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported security type",
+            ));
+        }
+
+        // the security result of the none type is only sent since version 3.8.
+        if &version[8..11] != b"007" {
+            writer.write_u32(0).await?;
+        }
+    }
+
+    // the shared flag is ignored, all viewers share the display.
+    reader.read_u8().await?;
+
+    writer.write_u16(width).await?;
+    writer.write_u16(height).await?;
+    writer
+        .write_all(&RfbPixelFormat::default().to_bytes())
+        .await?;
+    writer.write_u32(name.len() as u32).await?;
+    writer.write_all(name.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Reads the next message of a viewer.
+pub async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<RfbMessage> {
+    let message = match reader.read_u8().await? {
+        0 => {
+            let mut bytes = [0; 19];
+            reader.read_exact(&mut bytes).await?;
+
+            let mut format = [0; 16];
+            format.copy_from_slice(&bytes[3..]);
+            RfbMessage::SetPixelFormat(RfbPixelFormat::from_bytes(&format))
+        }
+        2 => {
+            reader.read_u8().await?;
+            let count = reader.read_u16().await?;
+            let mut encodings = Vec::with_capacity(count as usize);
+
+            for _ in 0..count {
+                encodings.push(reader.read_i32().await?);
+            }
+
+            RfbMessage::SetEncodings(encodings)
+        }
+        3 => RfbMessage::FramebufferUpdateRequest {
+            incremental: reader.read_u8().await? != 0,
+            x: reader.read_u16().await?,
+            y: reader.read_u16().await?,
+            width: reader.read_u16().await?,
+            height: reader.read_u16().await?,
+        },
+        4 => {
+            let down = reader.read_u8().await? != 0;
+            reader.read_u16().await?;
+
+            RfbMessage::KeyEvent {
+                down,
+                keysym: reader.read_u32().await?,
+            }
+        }
+        5 => RfbMessage::PointerEvent {
+            buttons: reader.read_u8().await?,
+            x: reader.read_u16().await?,
+            y: reader.read_u16().await?,
+        },
+        6 => {
+            let mut padding = [0; 3];
+            reader.read_exact(&mut padding).await?;
+            let len = reader.read_u32().await? as usize;
+
+            if len > MAX_CUT_TEXT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Cut text is too long",
+                ));
+            }
+
+            let mut text = vec![0; len];
+            reader.read_exact(&mut text).await?;
+
+            // cut text is Latin-1 encoded.
+            RfbMessage::ClientCutText(text.into_iter().map(char::from).collect())
+        }
+        message => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown RFB message {message}"),
+            ))
+        }
+    };
+
+    Ok(message)
+}
+
+/// Sends the given rectangle of the image with raw encoding in the given pixel format.
+pub async fn write_framebuffer_update(
+    writer: &mut (impl AsyncWrite + Unpin),
+    image: &Image,
+    rect: Rect,
+    format: &RfbPixelFormat,
+) -> io::Result<()> {
+    let mut message = vec![0, 0];
+    message.extend_from_slice(&1_u16.to_be_bytes());
+
+    for value in [rect.x, rect.y, rect.width, rect.height] {
+        message.extend_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    message.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+
+    for row in rect.y..rect.y + rect.height {
+        let start = (row * image.width + rect.x) as usize * 4;
+        format.encode(
+            &image.buffer[start..start + rect.width as usize * 4],
+            &mut message,
+        );
+    }
+
+    writer.write_all(&message).await?;
+    writer.flush().await
+}
+
+/// Returns the bounding rectangle of the pixels that differ between the images or the whole image if there is no
+/// previous image of the same size. Returns `None` if nothing has changed.
+pub fn changed_rect(previous: Option<&Image>, image: &Image) -> Option<Rect> {
+    let full = Rect {
+        x: 0,
+        y: 0,
+        width: image.width,
+        height: image.height,
+    };
+
+    let Some(previous) = previous.filter(|p| p.width == image.width && p.height == image.height)
+    else {
+        return Some(full);
+    };
+
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    let row_len = image.width as usize * 4;
+
+    for (y, (row, previous_row)) in image
+        .buffer
+        .chunks_exact(row_len.max(1))
+        .zip(previous.buffer.chunks_exact(row_len.max(1)))
+        .enumerate()
+    {
+        if row == previous_row {
+            continue;
+        }
+
+        for (x, (pixel, previous_pixel)) in row
+            .chunks_exact(4)
+            .zip(previous_row.chunks_exact(4))
+            .enumerate()
+        {
+            if pixel != previous_pixel {
+                left = left.min(x as u32);
+                right = right.max(x as u32 + 1);
+            }
+        }
+
+        top = top.min(y as u32);
+        bottom = y as u32 + 1;
+    }
+
+    (left < right).then(|| Rect {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+/// Converts an X11 keysym that is sent by a viewer into a key.
+pub fn keysym_to_key(keysym: u32) -> Option<Key> {
+    let key = match keysym {
+        0x20..=0x7e | 0xa0..=0xff => Key::from_text(&char::from(keysym as u8).to_string()),
+        0x0100_0000..=0x0110_ffff => {
+            Key::from_text(&char::from_u32(keysym - 0x0100_0000)?.to_string())
+        }
+        0xffb0..=0xffb9 => Key::Character(char::from(b'0' + (keysym - 0xffb0) as u8).to_string()),
+        0xffbe..=0xffd5 => Key::from_text(&char::from_u32(0xf704 + keysym - 0xffbe)?.to_string()),
+        0xff08 => Key::Backspace,
+        0xff09 => Key::Tab,
+        0xfe20 => Key::Backtab,
+        0xff0d | 0xff8d => Key::Return,
+        0xff1b => Key::Escape,
+        0xffff | 0xff9f => Key::Delete,
+        0xff50 => Key::Home,
+        0xff51 => Key::LeftArrow,
+        0xff52 => Key::UpArrow,
+        0xff53 => Key::RightArrow,
+        0xff54 => Key::DownArrow,
+        0xff55 => Key::PageUp,
+        0xff56 => Key::PageDown,
+        0xff57 => Key::End,
+        0xff63 => Key::Insert,
+        0xff13 => Key::Pause,
+        0xff14 => Key::ScrollLock,
+        0xff15 => Key::SysReq,
+        0xff67 => Key::Menu,
+        0xff69 => Key::Stop,
+        0xffe1 => Key::Shift,
+        0xffe2 => Key::ShiftR,
+        0xffe3 => Key::Control,
+        0xffe4 => Key::ControlR,
+        0xffe5 => Key::CapsLock,
+        0xffe7 | 0xffeb => Key::Meta,
+        0xffe8 | 0xffec => Key::MetaR,
+        0xffe9 => Key::Alt,
+        0xffea | 0xfe03 => Key::AltGr,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let format = RfbPixelFormat::default();
+        assert_eq!(RfbPixelFormat::from_bytes(&format.to_bytes()), format);
+
+        let mut out = vec![];
+        format.encode(&[1, 2, 3, 255], &mut out);
+        assert_eq!(out, vec![3, 2, 1, 0]);
+
+        // 16 bits big endian rgb565.
+        let format = RfbPixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..RfbPixelFormat::default()
+        };
+
+        let mut out = vec![];
+        format.encode(&[255, 0, 0, 255, 0, 0, 255, 255], &mut out);
+        assert_eq!(out, vec![0xf8, 0x00, 0x00, 0x1f]);
+    }
+
+    #[test]
+    fn test_hostile_pixel_format() {
+        let format = RfbPixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 5,
+            green_shift: 2,
+            blue_shift: 0,
+            ..RfbPixelFormat::default()
+        };
+        assert!(format.is_supported());
+
+        let hostile = [
+            // shift beyond the width of a pixel.
+            RfbPixelFormat {
+                red_shift: 200,
+                ..RfbPixelFormat::default()
+            },
+            RfbPixelFormat {
+                blue_shift: 8,
+                ..format
+            },
+            // maximum that does not fit into the pixel at its shift.
+            RfbPixelFormat {
+                red_max: u16::MAX,
+                ..format
+            },
+            RfbPixelFormat {
+                green_max: u16::MAX,
+                green_shift: 31,
+                ..RfbPixelFormat::default()
+            },
+            RfbPixelFormat {
+                bits_per_pixel: 0,
+                ..RfbPixelFormat::default()
+            },
+        ];
+
+        for format in hostile {
+            assert!(!format.is_supported(), "{format:?}");
+
+            // an unsupported format never panics while encoding.
+            format.encode(&[255; 8], &mut vec![]);
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut written = vec![];
+        let read: &[u8] = b"RFB 003.008\n\x01\x01";
+
+        block_on(handshake(&mut { read }, &mut written, 2, 1, "coop")).unwrap();
+
+        assert_eq!(&written[..12], VERSION);
+        assert_eq!(&written[12..14], &[1, SECURITY_NONE]);
+        assert_eq!(&written[14..18], &[0; 4]);
+        assert_eq!(&written[18..22], &[0, 2, 0, 1]);
+        assert_eq!(&written[38..42], &[0, 0, 0, 4]);
+        assert_eq!(&written[42..], b"coop");
+
+        // version 3.3 gets the security type from the server.
+        let mut written = vec![];
+        let read: &[u8] = b"RFB 003.003\n\x01";
+
+        block_on(handshake(&mut { read }, &mut written, 2, 1, "coop")).unwrap();
+        assert_eq!(&written[12..16], &[0, 0, 0, SECURITY_NONE]);
+    }
+
+    #[test]
+    fn test_read_message() {
+        let read: &[u8] = &[
+            3, 1, 0, 1, 0, 2, 0, 3, 0, 4, // framebuffer update request
+            4, 1, 0, 0, 0, 0, 0, 0x61, // key event
+            5, 1, 0, 10, 0, 20, // pointer event
+            6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i', // cut text
+            2, 0, 0, 1, 0, 0, 0, 0, // set encodings
+        ];
+        let mut read = read;
+
+        assert_eq!(
+            block_on(read_message(&mut read)).unwrap(),
+            RfbMessage::FramebufferUpdateRequest {
+                incremental: true,
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4
+            }
+        );
+        assert_eq!(
+            block_on(read_message(&mut read)).unwrap(),
+            RfbMessage::KeyEvent {
+                down: true,
+                keysym: 0x61
+            }
+        );
+        assert_eq!(
+            block_on(read_message(&mut read)).unwrap(),
+            RfbMessage::PointerEvent {
+                buttons: 1,
+                x: 10,
+                y: 20
+            }
+        );
+        assert_eq!(
+            block_on(read_message(&mut read)).unwrap(),
+            RfbMessage::ClientCutText("hi".into())
+        );
+        assert_eq!(
+            block_on(read_message(&mut read)).unwrap(),
+            RfbMessage::SetEncodings(vec![0])
+        );
+        assert!(block_on(read_message(&mut read)).is_err());
+    }
+
+    #[test]
+    fn test_write_framebuffer_update() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            buffer: vec![1, 2, 3, 255, 4, 5, 6, 255],
+        };
+        let rect = Rect {
+            x: 1,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+
+        let mut written = vec![];
+        block_on(write_framebuffer_update(
+            &mut written,
+            &image,
+            rect,
+            &RfbPixelFormat::default(),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            written,
+            vec![0, 0, 0, 1, 0, 1, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 6, 5, 4, 0]
+        );
+    }
+
+    #[test]
+    fn test_changed_rect() {
+        let previous = Image::new(3, 3);
+        let mut image = previous.clone();
+
+        assert_eq!(changed_rect(Some(&previous), &image), None);
+        assert_eq!(
+            changed_rect(None, &image),
+            Some(Rect {
+                x: 0,
+                y: 0,
+                width: 3,
+                height: 3
+            })
+        );
+
+        image.fill_rect(1, 1, 1, 2, [255; 4]);
+        assert_eq!(
+            changed_rect(Some(&previous), &image),
+            Some(Rect {
+                x: 1,
+                y: 1,
+                width: 1,
+                height: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_keysym_to_key() {
+        assert_eq!(keysym_to_key(0x61), Some(Key::Character("a".into())));
+        assert_eq!(keysym_to_key(0x20), Some(Key::Space));
+        assert_eq!(keysym_to_key(0x0100_20ac), Some(Key::Character("€".into())));
+        assert_eq!(keysym_to_key(0xff0d), Some(Key::Return));
+        assert_eq!(keysym_to_key(0xffbf), Some(Key::F2));
+        assert_eq!(keysym_to_key(0xffe1), Some(Key::Shift));
+        assert_eq!(keysym_to_key(0x1234), None);
+    }
+}
@@ -0,0 +1,1102 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use coop_protocol::*;
+
+mod layout;
+
+pub use layout::*;
+
+/// Position of new top level windows on the display.
+pub const DEFAULT_POSITION: (f32, f32) = (10., 10.);
+
+/// Position and size of a window on the display.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Geometry {
+    /// X position on the display.
+    pub x: f32,
+
+    /// Y position on the display.
+    pub y: f32,
+
+    /// Width of the window.
+    pub width: f32,
+
+    /// Height of the window.
+    pub height: f32,
+}
+
+impl Geometry {
+    /// Creates a new geometry.
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Returns `true` if the given display position is inside of the geometry.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    /// Returns the geometry grown by the given insets on each side.
+    pub fn outset(&self, insets: Insets) -> Self {
+        Self::new(
+            self.x - insets.left,
+            self.y - insets.top,
+            self.width + insets.left + insets.right,
+            self.height + insets.top + insets.bottom,
+        )
+    }
+
+    /// Returns the geometry shrunk by the given insets on each side.
+    pub fn inset(&self, insets: Insets) -> Self {
+        Self::new(
+            self.x + insets.left,
+            self.y + insets.top,
+            (self.width - insets.left - insets.right).max(0.),
+            (self.height - insets.top - insets.bottom).max(0.),
+        )
+    }
+}
+
+/// Space on each side of a window, e.g. for the decorations that are drawn around it by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Insets {
+    /// Space above the window, e.g. for the title bar.
+    pub top: f32,
+
+    /// Space below the window.
+    pub bottom: f32,
+
+    /// Space left of the window.
+    pub left: f32,
+
+    /// Space right of the window.
+    pub right: f32,
+}
+
+/// A window that is managed by the `WindowManager`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManagedWindow {
+    /// Key of the window, it is the client key for the main window of a client.
+    pub key: String,
+
+    /// Title of the window.
+    pub title: String,
+
+    /// Position and size of the window.
+    pub geometry: Geometry,
+
+    /// Key of the parent window of a popup.
+    pub parent: Option<String>,
+
+    /// Current state of the window.
+    pub state: WindowState,
+
+    /// `true` if the window can be resized.
+    pub resizable: bool,
+
+    /// Decides who draws the decorations of a top level window.
+    pub decoration_mode: DecorationMode,
+}
+
+impl ManagedWindow {
+    /// Creates a new top level window.
+    pub fn new(key: impl Into<String>, geometry: Geometry) -> Self {
+        Self {
+            key: key.into(),
+            title: String::default(),
+            geometry,
+            parent: None,
+            state: WindowState::Normal,
+            resizable: true,
+            decoration_mode: DecorationMode::Server,
+        }
+    }
+
+    /// Returns `true` if the server draws decorations around the window, popups are never decorated.
+    pub fn is_decorated(&self) -> bool {
+        self.parent.is_none() && self.decoration_mode == DecorationMode::Server
+    }
+}
+
+/// Result of a hit test, see `WindowManager::window_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit {
+    /// Key of the window under the position.
+    pub key: String,
+
+    /// X position relative to the window.
+    pub x: f32,
+
+    /// Y position relative to the window.
+    pub y: f32,
+}
+
+/// Interactive move or resize of a window with the pointer, see `WindowManager::start_move`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interaction {
+    /// Key of the window.
+    pub key: String,
+
+    /// Edge that is dragged by a resize, `None` for a move.
+    pub edge: Option<ResizeEdge>,
+
+    // display position of the pointer and geometry of the window when the interaction has started.
+    start: (f32, f32),
+    geometry: Geometry,
+}
+
+/// Keeps track of geometry and stacking order of the windows of all clients.
+///
+/// Feed it with the responses of `Server::client_response` to add and remove windows. Top level windows are
+/// placed and arranged by a `LayoutPolicy`, popups are moved with their parent.
+///
+/// The geometry of a window is the geometry of its frame buffer. Decorated windows are placed and arranged with
+/// their frame, that is the geometry grown by the frame insets, see `set_frame_insets`.
+#[derive(Debug)]
+pub struct WindowManager {
+    // windows from bottom to top.
+    windows: Vec<ManagedWindow>,
+
+    // keys of the top level windows from the oldest to the newest.
+    order: Vec<String>,
+
+    layout: Box<dyn LayoutPolicy>,
+    area: Geometry,
+
+    // sizes that are assigned by the layout and not yet sent to the clients.
+    resize_requests: Vec<(String, f32, f32)>,
+
+    frame_insets: Insets,
+    interaction: Option<Interaction>,
+}
+
+impl Default for WindowManager {
+    fn default() -> Self {
+        Self {
+            windows: vec![],
+            order: vec![],
+            layout: Box::new(Floating),
+            area: Geometry::default(),
+            resize_requests: vec![],
+            frame_insets: Insets::default(),
+            interaction: None,
+        }
+    }
+}
+
+impl WindowManager {
+    /// Creates a new window manager without windows and the `Floating` layout policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the layout policy and arranges the windows with it.
+    pub fn set_layout(&mut self, layout: impl LayoutPolicy + 'static) {
+        self.layout = Box::new(layout);
+        self.arrange();
+    }
+
+    /// Sets the area of the display that is used to arrange the windows.
+    pub fn set_area(&mut self, area: Geometry) {
+        self.area = area;
+        self.arrange();
+    }
+
+    /// Gets the area of the display that is used to arrange the windows.
+    pub fn area(&self) -> Geometry {
+        self.area
+    }
+
+    /// Sets the space of the decorations around decorated windows and arranges the windows again.
+    pub fn set_frame_insets(&mut self, insets: Insets) {
+        self.frame_insets = insets;
+        self.arrange();
+    }
+
+    /// Gets the space of the decorations around decorated windows.
+    pub fn frame_insets(&self) -> Insets {
+        self.frame_insets
+    }
+
+    /// Returns the geometry of the window with the given key including its decorations.
+    pub fn frame(&self, key: &str) -> Option<Geometry> {
+        self.window(key)
+            .map(|w| w.geometry.outset(self.insets_of(w)))
+    }
+
+    /// Returns the windows with the sizes that the layout has assigned to them since the last call.
+    ///
+    /// Send them to the clients as resize events.
+    pub fn take_resize_requests(&mut self) -> Vec<(String, f32, f32)> {
+        std::mem::take(&mut self.resize_requests)
+    }
+
+    /// Updates the windows from the given client response.
+    ///
+    /// Returns `true` if the windows have changed.
+    pub fn handle_response(&mut self, response: &ClientResponse) -> bool {
+        let key = &response.key;
+
+        match &response.message {
+            ClientResponseMessage::Created(c) => {
+                self.add_window(ManagedWindow {
+                    title: c.title.clone(),
+                    resizable: c.capabilities.contains(&Capability::Resize),
+                    decoration_mode: c.decoration_mode,
+                    ..ManagedWindow::new(key, self.place(c.width, c.height, c.decoration_mode))
+                });
+            }
+            ClientResponseMessage::WindowCreated(c) => {
+                let (geometry, parent) = match &c.kind {
+                    WindowKind::TopLevel => {
+                        (self.place(c.width, c.height, c.decoration_mode), None)
+                    }
+                    // popups are placed relative to their parent.
+                    WindowKind::Popup { parent, x, y } => {
+                        let (parent_x, parent_y) = self
+                            .window(parent)
+                            .map(|p| (p.geometry.x, p.geometry.y))
+                            .unwrap_or(DEFAULT_POSITION);
+
+                        (
+                            Geometry::new(parent_x + x, parent_y + y, c.width, c.height),
+                            Some(parent.clone()),
+                        )
+                    }
+                };
+
+                self.add_window(ManagedWindow {
+                    title: c.title.clone(),
+                    resizable: parent.is_none(),
+                    decoration_mode: c.decoration_mode,
+                    parent,
+                    ..ManagedWindow::new(key, geometry)
+                });
+            }
+            ClientResponseMessage::Draw(d) => return self.resize(key, d.width, d.height),
+            ClientResponseMessage::SetTitle(title) => match self.window_mut(key) {
+                Some(window) => window.title = title.clone(),
+                None => return false,
+            },
+            ClientResponseMessage::SetState(state) => return self.set_state(key, *state),
+            // all windows of a closed client are gone, also the popups of its main window.
+            ClientResponseMessage::Closed | ClientResponseMessage::Crashed { .. } => {
+                return self.remove_client(key);
+            }
+            ClientResponseMessage::WindowClosed => return self.remove_window(key).is_some(),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Adds a window on top of all other windows. An existing window with the same key is replaced.
+    pub fn add_window(&mut self, window: ManagedWindow) {
+        self.remove_window(&window.key);
+
+        if window.parent.is_none() {
+            self.order.push(window.key.clone());
+        }
+
+        self.windows.push(window);
+        self.arrange();
+    }
+
+    /// Removes the window with the given key.
+    pub fn remove_window(&mut self, key: &str) -> Option<ManagedWindow> {
+        let index = self.index(key)?;
+        let window = self.windows.remove(index);
+        self.order.retain(|k| k != key);
+        self.resize_requests.retain(|(k, ..)| k != key);
+
+        if self.interaction.as_ref().is_some_and(|i| i.key == key) {
+            self.interaction = None;
+        }
+
+        self.arrange();
+
+        Some(window)
+    }
+
+    /// Removes the main window and all additional windows of the client with the given key.
+    ///
+    /// Returns `true` if a window is removed.
+    pub fn remove_client(&mut self, client_key: &str) -> bool {
+        let keys: Vec<String> = self
+            .windows
+            .iter()
+            .filter(|w| crate::is_window_of(client_key, &w.key))
+            .map(|w| w.key.clone())
+            .collect();
+
+        for key in &keys {
+            self.remove_window(key);
+        }
+
+        !keys.is_empty()
+    }
+
+    /// Gets the window with the given key.
+    pub fn window(&self, key: &str) -> Option<&ManagedWindow> {
+        self.windows.iter().find(|w| w.key == key)
+    }
+
+    /// Returns all windows from bottom to top.
+    pub fn windows(&self) -> &[ManagedWindow] {
+        &self.windows
+    }
+
+    /// Returns the keys and geometries of the windows that are not minimized from bottom to top, e.g. to capture
+    /// the display with `ServerProxy::capture_display`. Popups of minimized windows are hidden with them.
+    pub fn visible_windows(&self) -> Vec<(String, Geometry)> {
+        self.windows
+            .iter()
+            .filter(|w| self.is_visible(w))
+            .map(|w| (w.key.clone(), w.geometry))
+            .collect()
+    }
+
+    /// Gets the window on top of all other windows.
+    pub fn top(&self) -> Option<&ManagedWindow> {
+        self.windows.last()
+    }
+
+    /// Moves the window with the given key and its popups on top of all other windows.
+    pub fn raise(&mut self, key: &str) -> bool {
+        let stack = self.take_stack(key);
+        let changed = !stack.is_empty();
+        self.windows.extend(stack);
+
+        changed
+    }
+
+    /// Moves the window with the given key and its popups below all other windows.
+    pub fn lower(&mut self, key: &str) -> bool {
+        let stack = self.take_stack(key);
+        let changed = !stack.is_empty();
+        self.windows.splice(0..0, stack);
+
+        changed
+    }
+
+    /// Moves the window with the given key to the given display position. Its popups are moved with it.
+    ///
+    /// The layout policy decides about the final geometry of a top level window.
+    pub fn move_to(&mut self, key: &str, x: f32, y: f32) -> bool {
+        let Some(window) = self.window(key) else {
+            return false;
+        };
+
+        let old = window.geometry;
+        let insets = self.insets_of(window);
+        let new = match window.parent {
+            Some(_) => Geometry { x, y, ..old },
+            // the layout moves the frame of the window.
+            None => self
+                .layout
+                .move_to(
+                    self.area,
+                    old.outset(insets),
+                    x - insets.left,
+                    y - insets.top,
+                )
+                .inset(insets),
+        };
+
+        if let Some(window) = self.window_mut(key) {
+            window.geometry = new;
+        }
+
+        self.geometry_changed(key, old, new);
+
+        true
+    }
+
+    /// Resizes the window with the given key, e.g. to the size of the frame that its client has drawn.
+    ///
+    /// The layout policy decides about the final size of a top level window. If it keeps another size, e.g. in a
+    /// tiled layout, that size is requested from the client again by `take_resize_requests`.
+    pub fn resize(&mut self, key: &str, width: f32, height: f32) -> bool {
+        let Some(old) = self.window(key).map(|w| w.geometry) else {
+            return false;
+        };
+
+        // the client already knows its own size.
+        self.resize_to(
+            key,
+            Geometry {
+                width,
+                height,
+                ..old
+            },
+            (width, height),
+        );
+
+        true
+    }
+
+    /// Sets the state of the window with the given key. Only windows in normal state are arranged by the layout.
+    pub fn set_state(&mut self, key: &str, state: WindowState) -> bool {
+        match self.window_mut(key) {
+            Some(window) => window.state = state,
+            None => return false,
+        }
+
+        self.arrange();
+
+        true
+    }
+
+    /// Arranges the top level windows in normal state with the layout policy.
+    pub fn arrange(&mut self) {
+        let before: Vec<(String, Geometry)> = self
+            .windows
+            .iter()
+            .filter(|w| w.parent.is_none())
+            .map(|w| (w.key.clone(), w.geometry))
+            .collect();
+
+        let frame_insets = self.frame_insets;
+        let mut windows: Vec<&mut ManagedWindow> = self
+            .windows
+            .iter_mut()
+            .filter(|w| w.parent.is_none() && w.state == WindowState::Normal)
+            .collect();
+        windows.sort_by_key(|w| self.order.iter().position(|k| *k == w.key));
+
+        // the layout arranges the frames of the windows.
+        for window in windows.iter_mut().filter(|w| w.is_decorated()) {
+            window.geometry = window.geometry.outset(frame_insets);
+        }
+
+        self.layout.arrange(self.area, &mut windows);
+
+        for window in windows.iter_mut().filter(|w| w.is_decorated()) {
+            window.geometry = window.geometry.inset(frame_insets);
+        }
+
+        for (key, old) in before {
+            if let Some(new) = self.window(&key).map(|w| w.geometry) {
+                self.geometry_changed(&key, old, new);
+            }
+        }
+    }
+
+    /// Returns the top most visible window at the given display position with the position relative to the window.
+    pub fn window_at(&self, x: f32, y: f32) -> Option<Hit> {
+        self.windows
+            .iter()
+            .rev()
+            .filter(|w| self.is_visible(w))
+            .find(|w| w.geometry.contains(x, y))
+            .map(|w| Hit {
+                key: w.key.clone(),
+                x: x - w.geometry.x,
+                y: y - w.geometry.y,
+            })
+    }
+
+    /// Returns the top most visible window whose frame contains the given display position with the position
+    /// relative to the window, e.g. it is negative on the title bar.
+    pub fn frame_at(&self, x: f32, y: f32) -> Option<Hit> {
+        self.windows
+            .iter()
+            .rev()
+            .filter(|w| self.is_visible(w))
+            .find(|w| w.geometry.outset(self.insets_of(w)).contains(x, y))
+            .map(|w| Hit {
+                key: w.key.clone(),
+                x: x - w.geometry.x,
+                y: y - w.geometry.y,
+            })
+    }
+
+    /// Starts to move the top level window with the given key by the pointer at the given display position.
+    ///
+    /// Returns `false` if there is no such window in normal state.
+    pub fn start_move(&mut self, key: &str, x: f32, y: f32) -> bool {
+        self.start_interaction(key, None, x, y)
+    }
+
+    /// Starts to resize the top level window with the given key at the given edge by the pointer at the given
+    /// display position.
+    ///
+    /// Returns `false` if there is no such window in normal state or if it cannot be resized.
+    pub fn start_resize(&mut self, key: &str, edge: ResizeEdge, x: f32, y: f32) -> bool {
+        self.window(key).is_some_and(|w| w.resizable)
+            && self.start_interaction(key, Some(edge), x, y)
+    }
+
+    /// Gets the running interactive move or resize.
+    pub fn interaction(&self) -> Option<&Interaction> {
+        self.interaction.as_ref()
+    }
+
+    /// Moves or resizes the window of the running interaction to follow the pointer to the given display position.
+    ///
+    /// The layout policy decides about the final geometry like for `resize`. Returns `true` if the geometry of the
+    /// window has changed. New sizes are returned by `take_resize_requests`.
+    pub fn update_interaction(&mut self, x: f32, y: f32) -> bool {
+        let Some(Interaction {
+            key,
+            edge,
+            start,
+            geometry,
+        }) = self.interaction.clone()
+        else {
+            return false;
+        };
+
+        let (delta_x, delta_y) = (x - start.0, y - start.1);
+
+        let Some(edge) = edge else {
+            let old = self.window(&key).map(|w| w.geometry);
+            self.move_to(&key, geometry.x + delta_x, geometry.y + delta_y);
+
+            return old != self.window(&key).map(|w| w.geometry);
+        };
+
+        let mut new = geometry;
+
+        // the opposite edge keeps its position, windows have at least one pixel.
+        if edge.is_left() {
+            new.width = (geometry.width - delta_x).max(1.);
+            new.x = geometry.x + geometry.width - new.width;
+        } else if edge.is_right() {
+            new.width = (geometry.width + delta_x).max(1.);
+        }
+
+        if edge.is_top() {
+            new.height = (geometry.height - delta_y).max(1.);
+            new.y = geometry.y + geometry.height - new.height;
+        } else if edge.is_bottom() {
+            new.height = (geometry.height + delta_y).max(1.);
+        }
+
+        let Some(size) = self
+            .window(&key)
+            .map(|w| (w.geometry.width, w.geometry.height))
+        else {
+            return false;
+        };
+
+        self.resize_to(&key, new, size)
+            .is_some_and(|(old, new)| old != new)
+    }
+
+    /// Ends the running interactive move or resize, e.g. if the pointer is released.
+    pub fn end_interaction(&mut self) -> Option<Interaction> {
+        self.interaction.take()
+    }
+
+    fn start_interaction(&mut self, key: &str, edge: Option<ResizeEdge>, x: f32, y: f32) -> bool {
+        let Some(window) = self
+            .window(key)
+            .filter(|w| w.parent.is_none() && w.state == WindowState::Normal)
+        else {
+            return false;
+        };
+
+        self.interaction = Some(Interaction {
+            key: key.into(),
+            edge,
+            start: (x, y),
+            geometry: window.geometry,
+        });
+
+        true
+    }
+
+    // geometry of a new top level window, the layout places its frame.
+    fn place(&self, width: f32, height: f32, decoration_mode: DecorationMode) -> Geometry {
+        let insets = match decoration_mode {
+            DecorationMode::Server => self.frame_insets,
+            DecorationMode::Client => Insets::default(),
+        };
+
+        self.layout
+            .place(
+                self.area,
+                width + insets.left + insets.right,
+                height + insets.top + insets.bottom,
+            )
+            .inset(insets)
+    }
+
+    // a popup is hidden with its minimized parent.
+    fn is_visible(&self, window: &ManagedWindow) -> bool {
+        let mut window = window;
+
+        // parents are followed only once, windows of a broken client could form a cycle.
+        let mut visited = vec![];
+
+        loop {
+            if window.state == WindowState::Minimized {
+                return false;
+            }
+
+            visited.push(window.key.as_str());
+
+            match window
+                .parent
+                .as_deref()
+                .filter(|parent| !visited.contains(parent))
+                .and_then(|parent| self.window(parent))
+            {
+                Some(parent) => window = parent,
+                None => return true,
+            }
+        }
+    }
+
+    // resizes the window with the given key to `new` as far as the layout allows and requests the final size if
+    // the client has another `size`. Returns the old and the final geometry.
+    fn resize_to(
+        &mut self,
+        key: &str,
+        new: Geometry,
+        size: (f32, f32),
+    ) -> Option<(Geometry, Geometry)> {
+        let window = self.window(key)?;
+        let old = window.geometry;
+        let new = self.layout_resize(window, new);
+
+        if let Some(window) = self.window_mut(key) {
+            window.geometry = new;
+        }
+
+        self.geometry_changed(
+            key,
+            Geometry {
+                width: size.0,
+                height: size.1,
+                ..old
+            },
+            new,
+        );
+
+        Some((old, new))
+    }
+
+    // geometry of a window that is resized to `new`, the layout resizes the frame of a top level window in normal
+    // state.
+    fn layout_resize(&self, window: &ManagedWindow, new: Geometry) -> Geometry {
+        if window.parent.is_some() || window.state != WindowState::Normal {
+            return new;
+        }
+
+        let insets = self.insets_of(window);
+
+        self.layout
+            .resize(
+                self.area,
+                window.geometry.outset(insets),
+                new.outset(insets),
+            )
+            .inset(insets)
+    }
+
+    fn insets_of(&self, window: &ManagedWindow) -> Insets {
+        if window.is_decorated() {
+            self.frame_insets
+        } else {
+            Insets::default()
+        }
+    }
+
+    fn index(&self, key: &str) -> Option<usize> {
+        self.windows.iter().position(|w| w.key == key)
+    }
+
+    fn window_mut(&mut self, key: &str) -> Option<&mut ManagedWindow> {
+        self.windows.iter_mut().find(|w| w.key == key)
+    }
+
+    // requests a resize if the size of the window has changed and moves its popups with it.
+    fn geometry_changed(&mut self, key: &str, old: Geometry, new: Geometry) {
+        if (old.width, old.height) != (new.width, new.height) {
+            self.resize_requests.retain(|(k, ..)| k != key);
+            self.resize_requests
+                .push((key.to_string(), new.width, new.height));
+        }
+
+        let (delta_x, delta_y) = (new.x - old.x, new.y - old.y);
+
+        if delta_x == 0. && delta_y == 0. {
+            return;
+        }
+
+        let popups = self.stack_keys(key).split_off(1);
+
+        for window in self.windows.iter_mut().filter(|w| popups.contains(&w.key)) {
+            window.geometry.x += delta_x;
+            window.geometry.y += delta_y;
+        }
+    }
+
+    // keys of the window and of all popups that are opened by it or by one of its popups.
+    fn stack_keys(&self, key: &str) -> Vec<String> {
+        let mut keys = vec![key.to_string()];
+        let mut i = 0;
+
+        while i < keys.len() {
+            for window in &self.windows {
+                if window.parent.as_ref() == Some(&keys[i]) && !keys.contains(&window.key) {
+                    keys.push(window.key.clone());
+                }
+            }
+
+            i += 1;
+        }
+
+        keys
+    }
+
+    // removes the window and its popups, keeps their stacking order.
+    fn take_stack(&mut self, key: &str) -> Vec<ManagedWindow> {
+        if self.index(key).is_none() {
+            return vec![];
+        }
+
+        let keys = self.stack_keys(key);
+        let (stack, rest) = self.windows.drain(..).partition(|w| keys.contains(&w.key));
+        self.windows = rest;
+
+        stack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window_manager() -> WindowManager {
+        let mut window_manager = WindowManager::new();
+        window_manager.add_window(ManagedWindow::new("one", Geometry::new(0., 0., 10., 10.)));
+        window_manager.add_window(ManagedWindow::new("two", Geometry::new(5., 5., 10., 10.)));
+        window_manager.add_window(ManagedWindow {
+            parent: Some("one".into()),
+            ..ManagedWindow::new("one:0", Geometry::new(2., 2., 2., 2.))
+        });
+
+        window_manager
+    }
+
+    fn keys(window_manager: &WindowManager) -> Vec<&str> {
+        window_manager
+            .windows()
+            .iter()
+            .map(|w| w.key.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_raise_lower() {
+        let mut window_manager = window_manager();
+
+        assert!(window_manager.raise("two"));
+        assert_eq!(keys(&window_manager), vec!["one", "one:0", "two"]);
+
+        assert!(window_manager.raise("one"));
+        assert_eq!(keys(&window_manager), vec!["two", "one", "one:0"]);
+
+        assert!(window_manager.lower("one"));
+        assert_eq!(keys(&window_manager), vec!["one", "one:0", "two"]);
+
+        assert!(!window_manager.raise("three"));
+    }
+
+    #[test]
+    fn test_move_to() {
+        let mut window_manager = window_manager();
+
+        assert!(window_manager.move_to("one", 20., 10.));
+        assert_eq!(
+            window_manager.window("one").unwrap().geometry,
+            Geometry::new(20., 10., 10., 10.)
+        );
+        assert_eq!(
+            window_manager.window("one:0").unwrap().geometry,
+            Geometry::new(22., 12., 2., 2.)
+        );
+        assert_eq!(
+            window_manager.window("two").unwrap().geometry,
+            Geometry::new(5., 5., 10., 10.)
+        );
+    }
+
+    #[test]
+    fn test_window_at() {
+        let mut window_manager = window_manager();
+
+        assert_eq!(
+            window_manager.window_at(3., 3.),
+            Some(Hit {
+                key: "one:0".into(),
+                x: 1.,
+                y: 1.
+            })
+        );
+        assert_eq!(window_manager.window_at(6., 6.).unwrap().key, "two");
+        assert_eq!(window_manager.window_at(1., 1.).unwrap().key, "one");
+        assert_eq!(window_manager.window_at(30., 30.), None);
+
+        window_manager.set_state("two", WindowState::Minimized);
+        assert_eq!(window_manager.window_at(6., 6.).unwrap().key, "one");
+
+        // popups are hidden with their minimized parent.
+        window_manager.set_state("one", WindowState::Minimized);
+        assert_eq!(window_manager.window_at(3., 3.), None);
+        assert_eq!(window_manager.frame_at(3., 3.), None);
+    }
+
+    #[test]
+    fn test_visible_windows() {
+        let mut window_manager = window_manager();
+        window_manager.set_state("one", WindowState::Minimized);
+
+        assert_eq!(
+            window_manager.visible_windows(),
+            vec![("two".to_string(), Geometry::new(5., 5., 10., 10.))]
+        );
+    }
+
+    #[test]
+    fn test_parent_cycle() {
+        let mut window_manager = WindowManager::new();
+
+        // a popup that is its own parent and two popups that are the parent of each other.
+        for (key, parent) in [("one", "one"), ("two", "three"), ("three", "two")] {
+            window_manager.add_window(ManagedWindow {
+                parent: Some(parent.into()),
+                ..ManagedWindow::new(key, Geometry::new(0., 0., 10., 10.))
+            });
+        }
+
+        assert_eq!(window_manager.visible_windows().len(), 3);
+        assert_eq!(window_manager.window_at(5., 5.).unwrap().key, "three");
+        assert_eq!(window_manager.frame_at(5., 5.).unwrap().key, "three");
+
+        window_manager.set_state("two", WindowState::Minimized);
+        assert_eq!(
+            window_manager.visible_windows(),
+            vec![("one".to_string(), Geometry::new(0., 0., 10., 10.))]
+        );
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut window_manager = window_manager();
+        window_manager.set_area(Geometry::new(0., 0., 40., 10.));
+        window_manager.set_layout(Grid);
+
+        assert_eq!(
+            window_manager.window("one").unwrap().geometry,
+            Geometry::new(0., 0., 20., 10.)
+        );
+        assert_eq!(
+            window_manager.window("two").unwrap().geometry,
+            Geometry::new(20., 0., 20., 10.)
+        );
+        // popups are not arranged but moved with their parent.
+        assert_eq!(
+            window_manager.window("one:0").unwrap().geometry,
+            Geometry::new(2., 2., 2., 2.)
+        );
+        assert_eq!(
+            window_manager.take_resize_requests(),
+            vec![("one".into(), 20., 10.), ("two".into(), 20., 10.)]
+        );
+
+        window_manager.set_state("two", WindowState::Minimized);
+        assert_eq!(
+            window_manager.window("one").unwrap().geometry,
+            Geometry::new(0., 0., 40., 10.)
+        );
+        assert_eq!(
+            window_manager.take_resize_requests(),
+            vec![("one".into(), 40., 10.)]
+        );
+        assert!(window_manager.take_resize_requests().is_empty());
+
+        // the layout keeps its geometry if the client draws another size or the window is moved.
+        assert!(window_manager.handle_response(&ClientResponse {
+            key: "one".into(),
+            message: ClientResponseMessage::Draw(DrawResponse {
+                buffer: vec![],
+                width: 10.,
+                height: 10.,
+                damage: vec![],
+                format: PixelFormat::Rgba8888,
+                stride: PixelFormat::Rgba8888.stride(10),
+            }),
+        }));
+        assert!(window_manager.move_to("one", 5., 5.));
+        assert_eq!(
+            window_manager.window("one").unwrap().geometry,
+            Geometry::new(0., 0., 40., 10.)
+        );
+        assert_eq!(
+            window_manager.take_resize_requests(),
+            vec![("one".into(), 40., 10.)]
+        );
+
+        // the client size is kept if the layout does not arrange the window.
+        assert!(window_manager.resize("two", 3., 4.));
+        assert_eq!(
+            window_manager.window("two").unwrap().geometry,
+            Geometry::new(20., 0., 3., 4.)
+        );
+        assert!(window_manager.take_resize_requests().is_empty());
+    }
+
+    #[test]
+    fn test_handle_response() {
+        let mut window_manager = WindowManager::new();
+
+        assert!(window_manager.handle_response(&ClientResponse {
+            key: "client".into(),
+            message: ClientResponseMessage::Created(CreationResponse {
+                title: "client".into(),
+                path: "client".into(),
+                width: 20.,
+                height: 10.,
+                version: VERSION,
+                capabilities: vec![],
+                decoration_mode: DecorationMode::Server,
+            }),
+        }));
+        assert!(window_manager.handle_response(&ClientResponse {
+            key: "client:0".into(),
+            message: ClientResponseMessage::WindowCreated(WindowCreationResponse {
+                title: "popup".into(),
+                width: 5.,
+                height: 5.,
+                kind: WindowKind::Popup {
+                    parent: "client".into(),
+                    x: 2.,
+                    y: 3.,
+                },
+                decoration_mode: DecorationMode::Server,
+            }),
+        }));
+
+        let popup = window_manager.window("client:0").unwrap();
+        assert_eq!(popup.geometry, Geometry::new(12., 13., 5., 5.));
+        assert_eq!(popup.parent.as_deref(), Some("client"));
+        assert!(!window_manager.window("client").unwrap().resizable);
+
+        assert!(window_manager.handle_response(&ClientResponse {
+            key: "client".into(),
+            message: ClientResponseMessage::Closed,
+        }));
+        assert!(window_manager.windows().is_empty());
+        assert!(!window_manager.handle_response(&ClientResponse {
+            key: "client".into(),
+            message: ClientResponseMessage::Crashed {
+                exit_code: None,
+                signal: None,
+            },
+        }));
+    }
+
+    #[test]
+    fn test_frame_insets() {
+        let mut window_manager = WindowManager::new();
+        window_manager.set_area(Geometry::new(0., 0., 100., 50.));
+        window_manager.set_frame_insets(Insets {
+            top: 10.,
+            bottom: 1.,
+            left: 1.,
+            right: 1.,
+        });
+        window_manager.set_layout(MasterStack { ratio: 0.5 });
+
+        window_manager.add_window(ManagedWindow::new("server", Geometry::default()));
+        window_manager.add_window(ManagedWindow {
+            decoration_mode: DecorationMode::Client,
+            ..ManagedWindow::new("client", Geometry::default())
+        });
+
+        // the layout arranges the frames, windows with client decorations have no frame.
+        assert_eq!(
+            window_manager.window("server").unwrap().geometry,
+            Geometry::new(1., 10., 48., 39.)
+        );
+        assert_eq!(
+            window_manager.frame("server"),
+            Some(Geometry::new(0., 0., 50., 50.))
+        );
+        assert_eq!(
+            window_manager.window("client").unwrap().geometry,
+            Geometry::new(50., 0., 50., 50.)
+        );
+
+        let hit = window_manager.frame_at(10., 5.).unwrap();
+        assert_eq!((hit.key.as_str(), hit.x, hit.y), ("server", 9., -5.));
+        assert!(window_manager.window_at(10., 5.).is_none());
+    }
+
+    #[test]
+    fn test_interaction() {
+        let mut window_manager = window_manager();
+        window_manager.take_resize_requests();
+
+        // popups cannot be moved interactively.
+        assert!(!window_manager.start_move("one:0", 3., 3.));
+        assert!(window_manager.interaction().is_none());
+
+        assert!(window_manager.start_move("one", 1., 1.));
+        assert!(window_manager.update_interaction(4., 6.));
+        assert_eq!(
+            window_manager.window("one").unwrap().geometry,
+            Geometry::new(3., 5., 10., 10.)
+        );
+        assert_eq!(
+            window_manager.window("one:0").unwrap().geometry,
+            Geometry::new(5., 7., 2., 2.)
+        );
+        assert_eq!(window_manager.end_interaction().unwrap().key, "one");
+        assert!(!window_manager.update_interaction(10., 10.));
+
+        // the right edge keeps its position if the window is resized at its left edge.
+        assert!(window_manager.start_resize("two", ResizeEdge::BottomLeft, 5., 15.));
+        assert!(window_manager.update_interaction(7., 20.));
+        assert_eq!(
+            window_manager.window("two").unwrap().geometry,
+            Geometry::new(7., 5., 8., 15.)
+        );
+        assert!(window_manager.update_interaction(30., 0.));
+        assert_eq!(
+            window_manager.window("two").unwrap().geometry,
+            Geometry::new(14., 5., 1., 1.)
+        );
+        assert_eq!(
+            window_manager.take_resize_requests(),
+            vec![("two".into(), 1., 1.)]
+        );
+
+        // the interaction ends if its window is removed.
+        window_manager.remove_window("two");
+        assert!(window_manager.interaction().is_none());
+
+        // a tiled layout keeps the geometry of its windows.
+        window_manager.set_layout(Grid);
+        window_manager.take_resize_requests();
+        let geometry = window_manager.window("one").unwrap().geometry;
+
+        assert!(window_manager.start_resize("one", ResizeEdge::BottomRight, 5., 5.));
+        assert!(!window_manager.update_interaction(20., 20.));
+        assert!(window_manager.end_interaction().is_some());
+        assert!(window_manager.start_move("one", 5., 5.));
+        assert!(!window_manager.update_interaction(20., 20.));
+        assert_eq!(window_manager.window("one").unwrap().geometry, geometry);
+        assert!(window_manager.take_resize_requests().is_empty());
+    }
+}
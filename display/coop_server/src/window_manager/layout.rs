@@ -0,0 +1,265 @@
+// SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
+// SPDX-License-Identifier: MIT
+
+use std::fmt::Debug;
+
+use super::{Geometry, ManagedWindow, DEFAULT_POSITION};
+
+/// Defines how the `WindowManager` places and arranges top level windows.
+pub trait LayoutPolicy: Debug + Send {
+    /// Returns the geometry of a new top level window with the given size inside of `area`.
+    fn place(&self, area: Geometry, width: f32, height: f32) -> Geometry {
+        Geometry::new(
+            area.x + DEFAULT_POSITION.0,
+            area.y + DEFAULT_POSITION.1,
+            width,
+            height,
+        )
+    }
+
+    /// Arranges the given top level windows inside of `area`. The windows are ordered from the oldest to the newest.
+    ///
+    /// It is called each time a window is added or removed and if the area or the policy changes.
+    fn arrange(&self, _area: Geometry, _windows: &mut [&mut ManagedWindow]) {}
+
+    /// Returns the geometry of a window that is moved by the user to the given position.
+    fn move_to(&self, _area: Geometry, geometry: Geometry, x: f32, y: f32) -> Geometry {
+        Geometry { x, y, ..geometry }
+    }
+
+    /// Returns the geometry of a window with the geometry `geometry` that is resized to `new` by the user or by its
+    /// client.
+    fn resize(&self, _area: Geometry, _geometry: Geometry, new: Geometry) -> Geometry {
+        new
+    }
+}
+
+/// Windows are placed at a fixed position and can be moved freely.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Floating;
+
+impl LayoutPolicy for Floating {}
+
+/// The oldest window fills the left part of the area, the other windows are stacked on the right.
+#[derive(Clone, Copy, Debug)]
+pub struct MasterStack {
+    /// Part of the area width that is used by the master window.
+    pub ratio: f32,
+}
+
+impl Default for MasterStack {
+    fn default() -> Self {
+        Self { ratio: 0.6 }
+    }
+}
+
+impl LayoutPolicy for MasterStack {
+    fn arrange(&self, area: Geometry, windows: &mut [&mut ManagedWindow]) {
+        let Some((master, stack)) = windows.split_first_mut() else {
+            return;
+        };
+
+        if stack.is_empty() {
+            master.geometry = area;
+            return;
+        }
+
+        let master_width = area.width * self.ratio.clamp(0., 1.);
+        master.geometry = Geometry::new(area.x, area.y, master_width, area.height);
+
+        let height = area.height / stack.len() as f32;
+
+        for (i, window) in stack.iter_mut().enumerate() {
+            window.geometry = Geometry::new(
+                area.x + master_width,
+                area.y + i as f32 * height,
+                area.width - master_width,
+                height,
+            );
+        }
+    }
+
+    // the layout owns the geometry of the windows.
+    fn move_to(&self, _area: Geometry, geometry: Geometry, _x: f32, _y: f32) -> Geometry {
+        geometry
+    }
+
+    fn resize(&self, _area: Geometry, geometry: Geometry, _new: Geometry) -> Geometry {
+        geometry
+    }
+}
+
+/// Windows are arranged in a grid with cells of the same size, filled row by row.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Grid;
+
+impl LayoutPolicy for Grid {
+    fn arrange(&self, area: Geometry, windows: &mut [&mut ManagedWindow]) {
+        if windows.is_empty() {
+            return;
+        }
+
+        let columns = (windows.len() as f32).sqrt().ceil() as usize;
+        let rows = windows.len().div_ceil(columns);
+        let (width, height) = (area.width / columns as f32, area.height / rows as f32);
+
+        for (i, window) in windows.iter_mut().enumerate() {
+            window.geometry = Geometry::new(
+                area.x + (i % columns) as f32 * width,
+                area.y + (i / columns) as f32 * height,
+                width,
+                height,
+            );
+        }
+    }
+
+    // the layout owns the geometry of the windows.
+    fn move_to(&self, _area: Geometry, geometry: Geometry, _x: f32, _y: f32) -> Geometry {
+        geometry
+    }
+
+    fn resize(&self, _area: Geometry, geometry: Geometry, _new: Geometry) -> Geometry {
+        geometry
+    }
+}
+
+/// Floating windows that snap to a half of the area if they are moved to an edge and to a quarter at a corner.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapping {
+    /// Distance to an edge of the area at which a window snaps.
+    pub threshold: f32,
+}
+
+impl Default for Snapping {
+    fn default() -> Self {
+        Self { threshold: 16. }
+    }
+}
+
+impl LayoutPolicy for Snapping {
+    fn move_to(&self, area: Geometry, geometry: Geometry, x: f32, y: f32) -> Geometry {
+        let left = x - area.x <= self.threshold;
+        let top = y - area.y <= self.threshold;
+        let right = area.x + area.width - (x + geometry.width) <= self.threshold;
+        let bottom = area.y + area.height - (y + geometry.height) <= self.threshold;
+
+        let (half_width, half_height) = (area.width / 2., area.height / 2.);
+
+        // a window at two opposite edges fills the whole axis and snaps only at the other axis.
+        let horizontal = match (left, right) {
+            (true, false) => Some((area.x, half_width)),
+            (false, true) => Some((area.x + half_width, half_width)),
+            _ => None,
+        };
+        let vertical = match (top, bottom) {
+            (true, false) => Some((area.y, half_height)),
+            (false, true) => Some((area.y + half_height, half_height)),
+            _ => None,
+        };
+
+        match (horizontal, vertical) {
+            (None, None) => Geometry { x, y, ..geometry },
+            (horizontal, vertical) => {
+                let (x, width) = horizontal.unwrap_or((area.x, area.width));
+                let (y, height) = vertical.unwrap_or((area.y, area.height));
+
+                Geometry::new(x, y, width, height)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Geometry = Geometry {
+        x: 0.,
+        y: 0.,
+        width: 100.,
+        height: 60.,
+    };
+
+    fn arrange(policy: &dyn LayoutPolicy, count: usize) -> Vec<Geometry> {
+        let mut windows: Vec<ManagedWindow> = (0..count)
+            .map(|i| ManagedWindow::new(i.to_string(), Geometry::new(0., 0., 10., 10.)))
+            .collect();
+
+        policy.arrange(AREA, &mut windows.iter_mut().collect::<Vec<_>>());
+
+        windows.iter().map(|w| w.geometry).collect()
+    }
+
+    #[test]
+    fn test_floating() {
+        assert_eq!(
+            Floating.place(AREA, 20., 10.),
+            Geometry::new(10., 10., 20., 10.)
+        );
+        assert_eq!(
+            arrange(&Floating, 2),
+            vec![Geometry::new(0., 0., 10., 10.); 2]
+        );
+    }
+
+    #[test]
+    fn test_master_stack() {
+        let policy = MasterStack { ratio: 0.5 };
+
+        assert_eq!(arrange(&policy, 1), vec![AREA]);
+        assert_eq!(
+            arrange(&policy, 3),
+            vec![
+                Geometry::new(0., 0., 50., 60.),
+                Geometry::new(50., 0., 50., 30.),
+                Geometry::new(50., 30., 50., 30.)
+            ]
+        );
+
+        // windows can neither be moved nor resized.
+        let geometry = Geometry::new(50., 0., 50., 30.);
+        assert_eq!(policy.move_to(AREA, geometry, 10., 10.), geometry);
+        assert_eq!(
+            policy.resize(AREA, geometry, Geometry::new(40., 0., 60., 20.)),
+            geometry
+        );
+    }
+
+    #[test]
+    fn test_grid() {
+        assert_eq!(
+            arrange(&Grid, 3),
+            vec![
+                Geometry::new(0., 0., 50., 30.),
+                Geometry::new(50., 0., 50., 30.),
+                Geometry::new(0., 30., 50., 30.)
+            ]
+        );
+
+        let geometry = Geometry::new(0., 30., 50., 30.);
+        assert_eq!(Grid.move_to(AREA, geometry, 10., 10.), geometry);
+        assert_eq!(
+            Grid.resize(AREA, geometry, Geometry::new(0., 30., 20., 10.)),
+            geometry
+        );
+    }
+
+    #[test]
+    fn test_snapping() {
+        let policy = Snapping { threshold: 5. };
+        let geometry = Geometry::new(30., 30., 20., 10.);
+
+        assert_eq!(
+            policy.move_to(AREA, geometry, 40., 20.),
+            Geometry::new(40., 20., 20., 10.)
+        );
+        assert_eq!(
+            policy.move_to(AREA, geometry, 2., 20.),
+            Geometry::new(0., 0., 50., 60.)
+        );
+        assert_eq!(
+            policy.move_to(AREA, geometry, 78., 49.),
+            Geometry::new(50., 30., 50., 30.)
+        );
+    }
+}
@@ -3,12 +3,17 @@
 
 use crate::*;
 
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use slint::{Model, SharedString, VecModel, Weak};
+use slint::private_unstable_api::re_exports::PointerEventKind;
+use slint::{Model, Rgba8Pixel, SharedPixelBuffer, SharedString, VecModel, Weak};
 
 use coop_protocol::*;
+use coop_server::proxy::SlintProxy;
+use coop_server::window_manager::*;
 
 pub struct DisplayViewController {
     display_loop: thread::JoinHandle<()>,
@@ -22,6 +27,15 @@ impl DisplayViewController {
             .global::<DisplayViewAdapter>()
             .set_window_models(window_models.into());
 
+        let launcher_entries = Rc::new(VecModel::from(vec![LauncherEntry {
+            path: "target/debug/widgets".into(),
+            icon: slint::Image::default(),
+        }]));
+
+        desktop
+            .global::<DisplayViewAdapter>()
+            .set_launcher_entries(launcher_entries.into());
+
         let display_loop = thread::spawn({
             let desktop = desktop.as_weak();
 
@@ -42,9 +56,33 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
     let mut server = coop_server::Server::new();
     let proxy = server.slint_proxy();
 
+    // lets running apps attach to the desktop, e.g. an app that is started by a debugger. Only apps that know the
+    // token of `COOP_TOKEN` are granted permissions.
+    if let Ok(address) = std::env::var("COOP_LISTEN") {
+        match std::env::var("COOP_TOKEN") {
+            Ok(token) => proxy.listen_with_token(address, token, Permission::defaults()),
+            Err(_) => proxy.listen(address),
+        }
+        .expect("Cannot listen for clients.");
+    }
+
+    // records the session to reproduce input bugs with `coop_replay`.
+    if let Ok(path) = std::env::var("COOP_RECORD") {
+        proxy.record(path).expect("Cannot record session.");
+    }
+
+    // geometry and stacking order of the windows, the window models are ordered like its windows.
+    let window_manager = Arc::new(Mutex::new(WindowManager::new()));
+
+    // display position of the pointer while it is pressed, it starts the moves and resizes that are requested by
+    // windows with client side decorations.
+    let pointer = Arc::new(Mutex::new(None));
+
     desktop
         .upgrade_in_event_loop({
             let proxy = proxy.clone();
+            let window_manager = window_manager.clone();
+            let pointer = pointer.clone();
 
             move |desktop| {
                 desktop.window().on_close_requested({
@@ -57,9 +95,11 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                 desktop.global::<DisplayViewAdapter>().on_open({
                     let proxy = proxy.clone();
                     let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
 
                     move |path| {
                         let proxy = proxy.clone();
+                        let window_manager = window_manager.clone();
                         desktop
                             .upgrade_in_event_loop(move |desktop| {
                                 let models =
@@ -68,12 +108,13 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                                 for i in 0..models.row_count() {
                                     let model = models.row_data(i).unwrap();
                                     if model.path.eq(&path) {
-                                        bring_to_front(desktop, model.key);
+                                        proxy.focus(model.key.clone());
+                                        bring_to_front(desktop, window_manager, model.key);
                                         return;
                                     }
                                 }
 
-                                proxy.open_client(path);
+                                proxy.open_client(path.as_str());
                             })
                             .expect("Cannot update in event loop");
                     }
@@ -88,18 +129,57 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                 });
 
                 desktop.global::<DisplayViewAdapter>().on_pointer_event({
+                    let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
                     let proxy = proxy.clone();
                     move |key, x, y, event| {
+                        let mut window_manager = window_manager.lock().unwrap();
+                        let position = window_manager
+                            .window(&key)
+                            .map(|w| (w.geometry.x + x, w.geometry.y + y));
+
+                        match event.kind {
+                            PointerEventKind::Down => *pointer.lock().unwrap() = position,
+                            PointerEventKind::Up => *pointer.lock().unwrap() = None,
+                            _ => {}
+                        }
+
+                        // the window follows the pointer until it is released, the client gets the release.
+                        if window_manager.interaction().is_some() {
+                            match (event.kind, position) {
+                                (PointerEventKind::Move, Some((x, y))) => {
+                                    if window_manager.update_interaction(x, y) {
+                                        if let Some(desktop) = desktop.upgrade() {
+                                            apply_layout(&desktop, &mut window_manager, &proxy);
+                                        }
+                                    }
+
+                                    return;
+                                }
+                                (PointerEventKind::Up, _) => {
+                                    window_manager.end_interaction();
+                                }
+                                _ => {}
+                            }
+                        }
+
                         proxy.send_pointer_event(key, x, y, event);
                     }
                 });
 
+                desktop.global::<DisplayViewAdapter>().on_pointer_scrolled({
+                    let proxy = proxy.clone();
+                    move |key, x, y, event| {
+                        proxy.send_pointer_scrolled_event(key, x, y, event);
+                    }
+                });
+
                 desktop
                     .global::<DisplayViewAdapter>()
                     .on_key_pressed_event({
                         let proxy = proxy.clone();
-                        move |key, event| {
-                            proxy.send_key_pressed_event(key, event);
+                        move |event| {
+                            proxy.send_key_pressed_event(event);
                         }
                     });
 
@@ -108,11 +188,25 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                     .global::<DisplayViewAdapter>()
                     .on_key_released_event({
                         let proxy = proxy.clone();
-                        move |key, event| {
-                            proxy.send_key_released_event(key, event);
+                        move |event| {
+                            proxy.send_key_released_event(event);
                         }
                     });
 
+                desktop.global::<DisplayViewAdapter>().on_focus_window({
+                    let proxy = proxy.clone();
+                    move |key| {
+                        proxy.focus(key);
+                    }
+                });
+
+                desktop.global::<DisplayViewAdapter>().on_resize({
+                    let proxy = proxy.clone();
+                    move |key, width, height| {
+                        proxy.send_resize_event(key, width, height);
+                    }
+                });
+
                 let proxy = proxy.clone();
                 desktop.global::<DisplayViewAdapter>().on_close({
                     let proxy = proxy.clone();
@@ -123,30 +217,120 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
 
                 desktop.global::<DisplayViewAdapter>().on_bring_to_front({
                     let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
                     move |key| {
-                        let desktop = desktop.clone();
-                        bring_to_front(desktop, key);
+                        bring_to_front(desktop.clone(), window_manager.clone(), key);
+                    }
+                });
+
+                desktop.global::<DisplayViewAdapter>().on_moved({
+                    let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
+                    let proxy = proxy.clone();
+                    move |key, x, y| {
+                        let mut window_manager = window_manager.lock().unwrap();
+                        window_manager.move_to(&key, x, y);
+
+                        // popups are moved with their parent.
+                        if let Some(desktop) = desktop.upgrade() {
+                            apply_layout(&desktop, &mut window_manager, &proxy);
+                        }
+                    }
+                });
+
+                desktop.global::<DisplayViewAdapter>().on_area_changed({
+                    let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
+                    let proxy = proxy.clone();
+                    move |width, height| {
+                        let mut window_manager = window_manager.lock().unwrap();
+                        window_manager.set_area(Geometry::new(0., 0., width, height));
+
+                        if let Some(desktop) = desktop.upgrade() {
+                            apply_layout(&desktop, &mut window_manager, &proxy);
+                        }
+                    }
+                });
+
+                desktop.global::<DisplayViewAdapter>().on_set_layout({
+                    let desktop = desktop.as_weak();
+                    let window_manager = window_manager.clone();
+                    let proxy = proxy.clone();
+                    move |index| {
+                        let mut window_manager = window_manager.lock().unwrap();
+
+                        match index {
+                            1 => window_manager.set_layout(MasterStack::default()),
+                            2 => window_manager.set_layout(Grid),
+                            3 => window_manager.set_layout(Snapping::default()),
+                            _ => window_manager.set_layout(Floating),
+                        }
+
+                        if let Some(desktop) = desktop.upgrade() {
+                            apply_layout(&desktop, &mut window_manager, &proxy);
+                        }
                     }
                 });
             }
         })
         .expect("Cannot upgrade in desktop event loop");
 
+    // last frame buffer of each client, used to apply damaged regions.
+    let mut frame_buffers: HashMap<String, SharedPixelBuffer<Rgba8Pixel>> = HashMap::new();
+
     'server: loop {
         if let Some(response) = server.client_response() {
+            let geometry = {
+                let mut manager = window_manager.lock().unwrap();
+
+                // other windows can be rearranged if a window is added or removed.
+                let changed = manager.handle_response(&response);
+
+                // the layout requests its sizes again if a client draws another size.
+                for (key, width, height) in manager.take_resize_requests() {
+                    proxy.send_resize_event(key.into(), width, height);
+                }
+
+                if changed {
+                    desktop
+                        .upgrade_in_event_loop({
+                            let window_manager = window_manager.clone();
+                            move |desktop| {
+                                update_positions(&desktop, &window_manager.lock().unwrap());
+                            }
+                        })
+                        .expect("Cannot update window positions.");
+                }
+
+                manager
+                    .window(&response.key)
+                    .map(|w| w.geometry)
+                    .unwrap_or_default()
+            };
+
             match response.message {
                 ClientResponseMessage::Created(c) => {
+                    // new windows get the keyboard focus.
+                    proxy.focus(response.key.as_str().into());
+
                     desktop
                         .upgrade_in_event_loop(move |desktop| {
                             let client = WindowModel {
                                 key: response.key.into(),
-                                x: 10.0.into(),
-                                y: 10.0.into(),
+                                x: geometry.x,
+                                y: geometry.y,
                                 width: c.width.into(),
                                 height: c.height.into(),
                                 title: c.title.into(),
                                 buffer: slint::Image::default(),
                                 path: c.path.into(),
+                                resizable: c.capabilities.contains(&Capability::Resize),
+                                decorated: c.decoration_mode == DecorationMode::Server,
+                                icon: slint::Image::default(),
+                                minimized: false,
+                                maximized: false,
+                                restore_width: c.width,
+                                restore_height: c.height,
                             };
 
                             if let Some(clients) = desktop
@@ -160,7 +344,76 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                         })
                         .expect("Cannot add client to list of window clients");
                 }
+                ClientResponseMessage::WindowCreated(c) => {
+                    proxy.focus(response.key.as_str().into());
+
+                    desktop
+                        .upgrade_in_event_loop(move |desktop| {
+                            let window = WindowModel {
+                                key: response.key.into(),
+                                x: geometry.x,
+                                y: geometry.y,
+                                width: c.width,
+                                height: c.height,
+                                title: c.title.into(),
+                                buffer: slint::Image::default(),
+                                path: SharedString::default(),
+                                resizable: matches!(c.kind, WindowKind::TopLevel),
+                                // popups are always decorated by the desktop.
+                                decorated: !matches!(c.kind, WindowKind::TopLevel)
+                                    || c.decoration_mode == DecorationMode::Server,
+                                icon: slint::Image::default(),
+                                minimized: false,
+                                maximized: false,
+                                restore_width: c.width,
+                                restore_height: c.height,
+                            };
+
+                            if let Some(clients) = desktop
+                                .global::<DisplayViewAdapter>()
+                                .get_window_models()
+                                .as_any()
+                                .downcast_ref::<VecModel<WindowModel>>()
+                            {
+                                clients.push(window);
+                            }
+                        })
+                        .expect("Cannot add window to list of window clients");
+                }
                 ClientResponseMessage::Draw(d) => {
+                    let (width, height) = (d.width as u32, d.height as u32);
+                    let stride = PixelFormat::Rgba8888.stride(width);
+
+                    // the frame buffers of the desktop are Rgba8888, the height of a whole frame buffer is taken
+                    // from the buffer itself, so a buffer that does not match the window size is never misread.
+                    let frame_buffer = if d.damage.is_empty() {
+                        let buffer = convert_to_rgba8(&d.buffer, width, d.stride, d.format);
+                        SharedPixelBuffer::<Rgba8Pixel>::clone_from_slice(
+                            &buffer,
+                            width,
+                            buffer_height(buffer.len(), stride),
+                        )
+                    } else {
+                        match frame_buffers.remove(&response.key) {
+                            Some(mut frame_buffer)
+                                if frame_buffer.width() == width
+                                    && frame_buffer.height() == height =>
+                            {
+                                unpack_regions(
+                                    frame_buffer.make_mut_bytes(),
+                                    stride,
+                                    PixelFormat::Rgba8888,
+                                    &d.damage,
+                                    &d.format.to_rgba8(&d.buffer),
+                                );
+                                frame_buffer
+                            }
+                            _ => continue 'server,
+                        }
+                    };
+
+                    frame_buffers.insert(response.key.clone(), frame_buffer.clone());
+
                     desktop
                         .upgrade_in_event_loop(move |desktop| {
                             let clients =
@@ -169,38 +422,139 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
                             for row in 0..clients.row_count() {
                                 if let Some(mut c) = clients.row_data(row) {
                                     if c.key.to_string().eq(&response.key) {
-                                        let spb = slint::SharedPixelBuffer::<
-                                                            slint::Rgba8Pixel,
-                                                        >::clone_from_slice(
-                                                            &d.buffer, d.width as u32, 400
-                                                        );
-
-                                        c.buffer = slint::Image::from_rgba8(spb);
+                                        c.buffer = slint::Image::from_rgba8(frame_buffer);
+                                        c.width = d.width;
+                                        c.height = d.height;
                                         clients.set_row_data(row, c);
+                                        return;
                                     }
                                 }
                             }
                         })
                         .expect("Cannot draw.");
                 }
-                ClientResponseMessage::Closed => {
+                ClientResponseMessage::SetTitle(title) => {
+                    update_window(desktop.clone(), response.key, move |_, window| {
+                        window.title = title.into();
+                    });
+                }
+                ClientResponseMessage::SetIcon(icon) => {
+                    // an icon whose buffer does not match its size cannot be shown.
+                    if !icon.is_valid() {
+                        continue 'server;
+                    }
+
+                    let icon = SharedPixelBuffer::<Rgba8Pixel>::clone_from_slice(
+                        &icon.buffer,
+                        icon.width,
+                        icon.height,
+                    );
+
+                    update_window(desktop.clone(), response.key, move |desktop, window| {
+                        window.icon = slint::Image::from_rgba8(icon);
+
+                        // shows the icon of the running app also in the launcher.
+                        let entries = desktop
+                            .global::<DisplayViewAdapter>()
+                            .get_launcher_entries();
+                        for row in 0..entries.row_count() {
+                            if let Some(mut entry) = entries.row_data(row) {
+                                if entry.path == window.path {
+                                    entry.icon = window.icon.clone();
+                                    entries.set_row_data(row, entry);
+                                }
+                            }
+                        }
+                    });
+                }
+                ClientResponseMessage::SetState(state) => {
+                    let proxy = proxy.clone();
+                    let key = response.key.clone();
+
+                    update_window(desktop.clone(), response.key, move |_, window| {
+                        match state {
+                            WindowState::Minimized => window.minimized = true,
+                            // the desktop has no fullscreen mode, fullscreen windows are maximized.
+                            WindowState::Maximized | WindowState::Fullscreen => {
+                                if !window.maximized {
+                                    window.restore_width = window.width;
+                                    window.restore_height = window.height;
+                                }
+
+                                window.minimized = false;
+                                window.maximized = true;
+                            }
+                            WindowState::Normal => {
+                                if window.maximized {
+                                    proxy.send_resize_event(
+                                        key.into(),
+                                        window.restore_width,
+                                        window.restore_height,
+                                    );
+                                }
+
+                                window.minimized = false;
+                                window.maximized = false;
+                            }
+                        }
+                    });
+                }
+                ClientResponseMessage::StartMove => {
+                    if let Some((x, y)) = *pointer.lock().unwrap() {
+                        window_manager
+                            .lock()
+                            .unwrap()
+                            .start_move(&response.key, x, y);
+                    }
+                }
+                ClientResponseMessage::StartResize(edge) => {
+                    if let Some((x, y)) = *pointer.lock().unwrap() {
+                        window_manager
+                            .lock()
+                            .unwrap()
+                            .start_resize(&response.key, edge, x, y);
+                    }
+                }
+                // the server converts ready frames to draw responses.
+                ClientResponseMessage::FrameReady(_) => {}
+                // the server already shares the text with the other clients.
+                ClientResponseMessage::SetClipboardText(_)
+                | ClientResponseMessage::OpenClient(_) => {}
+                ClientResponseMessage::OpenClientFailed { path, error } => {
+                    eprintln!("Cannot open client {}: {}", path, error);
+                }
+                ClientResponseMessage::Closed
+                | ClientResponseMessage::Crashed { .. }
+                | ClientResponseMessage::WindowClosed => {
+                    if let ClientResponseMessage::Crashed { exit_code, signal } = response.message {
+                        eprintln!(
+                            "Client {} crashed (exit code: {:?}, signal: {:?}).",
+                            response.key, exit_code, signal
+                        );
+                    }
+
+                    // a closed client takes its additional windows with it.
+                    let is_removed = move |key: &str| match response.message {
+                        ClientResponseMessage::WindowClosed => key == response.key,
+                        _ => key
+                            .strip_prefix(response.key.as_str())
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with(':')),
+                    };
+
+                    frame_buffers.retain(|key, _| !is_removed(key));
+
                     desktop
                         .upgrade_in_event_loop(move |desktop| {
-                            let clients =
-                                desktop.global::<DisplayViewAdapter>().get_window_models();
+                            let models = desktop.global::<DisplayViewAdapter>().get_window_models();
+                            let Some(clients) =
+                                models.as_any().downcast_ref::<VecModel<WindowModel>>()
+                            else {
+                                return;
+                            };
 
-                            for row in 0..clients.row_count() {
-                                if let Some(c) = clients.row_data(row) {
-                                    if c.key.to_string().eq(&response.key) {
-                                        if let Some(clients) = desktop
-                                            .global::<DisplayViewAdapter>()
-                                            .get_window_models()
-                                            .as_any()
-                                            .downcast_ref::<VecModel<WindowModel>>()
-                                        {
-                                            clients.remove(row);
-                                        }
-                                    }
+                            for row in (0..clients.row_count()).rev() {
+                                if clients.row_data(row).is_some_and(|c| is_removed(&c.key)) {
+                                    clients.remove(row);
                                 }
                             }
                         })
@@ -216,43 +570,95 @@ fn display_loop(desktop: Weak<Desktop>) -> tokio::io::Result<()> {
     Ok(())
 }
 
-fn bring_to_front(desktop: Weak<Desktop>, key: SharedString) {
+/// Updates the window model with the given key in the event loop of the desktop.
+fn update_window(
+    desktop: Weak<Desktop>,
+    key: String,
+    update: impl FnOnce(&Desktop, &mut WindowModel) + Send + 'static,
+) {
     desktop
         .upgrade_in_event_loop(move |desktop| {
-            for i in 0..desktop
-                .global::<DisplayViewAdapter>()
-                .get_window_models()
-                .row_count()
+            let clients = desktop.global::<DisplayViewAdapter>().get_window_models();
+
+            for row in 0..clients.row_count() {
+                if let Some(mut c) = clients.row_data(row) {
+                    if c.key.to_string().eq(&key) {
+                        update(&desktop, &mut c);
+                        clients.set_row_data(row, c);
+                        return;
+                    }
+                }
+            }
+        })
+        .expect("Cannot update window.");
+}
+
+/// Updates the positions of the window models from the window manager.
+fn update_positions(desktop: &Desktop, window_manager: &WindowManager) {
+    let clients = desktop.global::<DisplayViewAdapter>().get_window_models();
+
+    for row in 0..clients.row_count() {
+        if let Some(mut c) = clients.row_data(row) {
+            if let Some(window) = window_manager.window(&c.key) {
+                if (c.x, c.y) != (window.geometry.x, window.geometry.y) {
+                    c.x = window.geometry.x;
+                    c.y = window.geometry.y;
+                    clients.set_row_data(row, c);
+                }
+            }
+        }
+    }
+}
+
+/// Updates the window models from the window manager and sends the sizes that are assigned by the layout to the clients.
+fn apply_layout(desktop: &Desktop, window_manager: &mut WindowManager, proxy: &SlintProxy) {
+    update_positions(desktop, window_manager);
+
+    for (key, width, height) in window_manager.take_resize_requests() {
+        proxy.send_resize_event(key.into(), width, height);
+    }
+}
+
+/// Raises the window with the given key and its popups and moves their models to the end of the list.
+fn bring_to_front(
+    desktop: Weak<Desktop>,
+    window_manager: Arc<Mutex<WindowManager>>,
+    key: SharedString,
+) {
+    desktop
+        .upgrade_in_event_loop(move |desktop| {
+            let mut window_manager = window_manager.lock().unwrap();
+
+            if window_manager
+                .window(&key)
+                .is_some_and(|w| w.state == WindowState::Minimized)
             {
-                if desktop
-                    .global::<DisplayViewAdapter>()
-                    .get_window_models()
-                    .row_data(i)
-                    .unwrap()
-                    .key
-                    .eq(&key)
-                {
-                    let window = desktop
-                        .global::<DisplayViewAdapter>()
-                        .get_window_models()
-                        .row_data(i)
-                        .unwrap();
+                window_manager.set_state(&key, WindowState::Normal);
+            }
 
-                    desktop
-                        .global::<DisplayViewAdapter>()
-                        .get_window_models()
-                        .as_any()
-                        .downcast_ref::<VecModel<WindowModel>>()
-                        .unwrap()
-                        .remove(i);
+            if !window_manager.raise(&key) {
+                return;
+            }
 
-                    desktop
-                        .global::<DisplayViewAdapter>()
-                        .get_window_models()
-                        .as_any()
-                        .downcast_ref::<VecModel<WindowModel>>()
-                        .unwrap()
-                        .push(window);
+            let models = desktop.global::<DisplayViewAdapter>().get_window_models();
+            let Some(clients) = models.as_any().downcast_ref::<VecModel<WindowModel>>() else {
+                return;
+            };
+
+            let windows = window_manager.windows();
+            let raised = windows
+                .iter()
+                .position(|w| w.key == key.as_str())
+                .unwrap_or(windows.len());
+
+            for window in &windows[raised..] {
+                if let Some(row) = clients.iter().position(|c| c.key == window.key.as_str()) {
+                    let mut client = clients.remove(row);
+                    if client.key == key {
+                        client.minimized = false;
+                    }
+
+                    clients.push(client);
                 }
             }
         })
@@ -65,8 +65,11 @@ fn app() -> App {
 #[cfg(not(feature = "mcu-board-support"))]
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
 pub fn main() {
-    #[cfg(feature = "coop_client")]
-    coop_client::init_config(600., 400., "widgets");
+    // falls back to the default backend if the app is not started by a coop server.
+    #[cfg(feature = "slint_coop")]
+    if let Err(error) = slint_coop::try_init_config(600., 400., "widgets") {
+        eprintln!("Cannot connect to coop server: {:?}", error);
+    }
 
     // This provides better error messages in debug mode.
     // It's disabled in release mode so it doesn't bloat up the file size.
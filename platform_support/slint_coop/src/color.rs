@@ -4,28 +4,36 @@
 use slint::platform::software_renderer::*;
 
 /// Color definition internally used to work with Slint and coop_client.
+///
+/// The bytes of the color in memory are in the order of `coop_protocol::PixelFormat::Rgba8888` on little and big
+/// endian targets, so a buffer of colors is sent without conversion.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
 pub struct Color(pub u32);
 
 impl Color {
-    /// Gets the read value of the color.
+    /// Creates a color from its red, green, blue and alpha channel.
+    pub fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self(u32::from_ne_bytes([r, g, b, a]))
+    }
+
+    /// Gets the red value of the color.
     pub fn r(&self) -> u8 {
-        (self.0 & 0x000000FF) as u8
+        self.0.to_ne_bytes()[0]
     }
 
     /// Gets the green value of the color.
     pub fn g(&self) -> u8 {
-        ((self.0 & 0x0000FF00) >> 8) as u8
+        self.0.to_ne_bytes()[1]
     }
 
     /// Gets the blue value of the color.
     pub fn b(&self) -> u8 {
-        ((self.0 & 0x00FF0000) >> 16) as u8
+        self.0.to_ne_bytes()[2]
     }
 
     /// Gets the alpha channel of the color.
     pub fn a(&self) -> u8 {
-        ((self.0 & 0x00FF0000) >> 24) as u8
+        self.0.to_ne_bytes()[3]
     }
 }
 
@@ -40,6 +48,54 @@ impl TargetPixel for Color {
     }
 
     fn from_rgb(r: u8, g: u8, b: u8) -> Self {
-        Self(0xFF000000 | ((b as u32) << 16) | ((g as u32) << 8) | (r as u32))
+        Self::from_rgba(r, g, b, u8::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channels() {
+        let color = Color::from_rgba(0x10, 0x20, 0x30, 0x80);
+
+        assert_eq!(color.r(), 0x10);
+        assert_eq!(color.g(), 0x20);
+        assert_eq!(color.b(), 0x30);
+        assert_eq!(color.a(), 0x80);
+    }
+
+    #[test]
+    fn test_from_rgb() {
+        let color = Color::from_rgb(1, 2, 3);
+
+        assert_eq!((color.r(), color.g(), color.b(), color.a()), (1, 2, 3, 255));
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut color = Color::from_rgb(200, 100, 0);
+        color.blend(PremultipliedRgbaColor {
+            red: 50,
+            green: 0,
+            blue: 50,
+            alpha: 128,
+        });
+
+        assert_eq!(
+            (color.r(), color.g(), color.b(), color.a()),
+            (149, 49, 50, 255)
+        );
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let color = Color::from_rgb(1, 2, 3);
+
+        assert_eq!(
+            coop_protocol::PixelFormat::Rgba8888.to_rgba(&color.0.to_ne_bytes()),
+            [1, 2, 3, 255]
+        );
     }
 }
@@ -1,11 +1,14 @@
 // SPDX-FileCopyrightText: 2022 Florian Blasius <co_sl@tutanota.com>
 // SPDX-License-Identifier: MIT
 
+use std::time::Duration;
+
+use coop_client::result::ClientResult;
 use coop_protocol::{ClientEvent, PointerEventButton};
 use slint::platform;
 use slint::LogicalPosition;
 
-/// Represents `WindowEvent`, Resize or Quit.
+/// Represents `WindowEvent`, Resize, FrameDone or Quit.
 #[derive(Debug, PartialEq)]
 pub enum Event {
     /// Slint `WindowEvent`.
@@ -14,6 +17,9 @@ pub enum Event {
     /// Resize event.
     Resize(slint::LogicalSize),
 
+    /// The server is ready for the next frame of the window.
+    FrameDone,
+
     /// Window quit event.
     Quit,
 }
@@ -25,13 +31,40 @@ impl From<slint::platform::WindowEvent> for Event {
 }
 
 /// Reads all events from the given `Window` and converts them into a vec of `Event`.
-pub fn read(coop_window: &coop_client::Window) -> Vec<Event> {
-    coop_window.events().iter().map(|e| convert(e)).collect()
+pub fn read(coop_window: &coop_client::Window) -> ClientResult<Vec<Event>> {
+    Ok(coop_window.events()?.iter().filter_map(convert).collect())
+}
+
+/// Reads all events of all windows from the given `Window` and converts them into a vec of `Event` with the key
+/// of the window the event belongs to.
+pub fn read_windows(coop_window: &coop_client::Window) -> ClientResult<Vec<(String, Event)>> {
+    Ok(coop_window
+        .window_events()?
+        .iter()
+        .filter_map(|(key, event)| convert(event).map(|event| (key.clone(), event)))
+        .collect())
 }
 
-pub fn convert(in_event: &coop_protocol::ClientEvent) -> Event {
-    match in_event {
+/// Waits until events of the given `Window` are received or the timeout has elapsed and converts them like
+/// `read_windows`. Returns an empty vec if the timeout has elapsed.
+pub fn wait_windows(
+    coop_window: &coop_client::Window,
+    timeout: Option<Duration>,
+) -> ClientResult<Vec<(String, Event)>> {
+    Ok(coop_window
+        .wait_window_events(timeout)?
+        .iter()
+        .filter_map(|(key, event)| convert(event).map(|event| (key.clone(), event)))
+        .collect())
+}
+
+/// Converts a `ClientEvent` into an `Event`. Returns `None` for events that are handled by the `Window` itself.
+pub fn convert(in_event: &coop_protocol::ClientEvent) -> Option<Event> {
+    let event = match in_event {
         ClientEvent::Close => Event::Quit,
+        ClientEvent::Resize { width, height } => {
+            Event::Resize(slint::LogicalSize::new(*width, *height))
+        }
         ClientEvent::PointerPressed { position, button } => platform::WindowEvent::PointerPressed {
             position: LogicalPosition::new(position.0, position.1),
             button: convert_button(button),
@@ -44,19 +77,46 @@ pub fn convert(in_event: &coop_protocol::ClientEvent) -> Event {
             }
             .into()
         }
-        // todo: what to do with pointer canceled?
-        ClientEvent::PointerCanceled => todo!(),
-        ClientEvent::PointerExited => platform::WindowEvent::PointerExited.into(),
-        // FIXME: use SharedString after it is updated by Slint
-        ClientEvent::KeyPressed { text } => platform::WindowEvent::KeyPressed {
-            text: text.chars().into_iter().next().unwrap().into(),
+        ClientEvent::PointerMoved { position } => platform::WindowEvent::PointerMoved {
+            position: LogicalPosition::new(position.0, position.1),
         }
         .into(),
-        ClientEvent::KeyReleased { text } => platform::WindowEvent::KeyReleased {
-            text: text.chars().into_iter().next().unwrap().into(),
+        ClientEvent::PointerScrolled {
+            position,
+            delta_x,
+            delta_y,
+        } => platform::WindowEvent::PointerScrolled {
+            position: LogicalPosition::new(position.0, position.1),
+            delta_x: *delta_x,
+            delta_y: *delta_y,
         }
         .into(),
-    }
+        // Slint has no cancel event, leaving the window releases the pointer grab of the pressed item as well.
+        ClientEvent::PointerCanceled | ClientEvent::PointerExited => {
+            platform::WindowEvent::PointerExited.into()
+        }
+        ClientEvent::KeyPressed(event) if event.repeat => platform::WindowEvent::KeyPressRepeated {
+            text: event.dispatch_text().into(),
+        }
+        .into(),
+        ClientEvent::KeyPressed(event) => platform::WindowEvent::KeyPressed {
+            text: event.dispatch_text().into(),
+        }
+        .into(),
+        ClientEvent::KeyReleased(event) => platform::WindowEvent::KeyReleased {
+            text: event.dispatch_text().into(),
+        }
+        .into(),
+        ClientEvent::FocusIn => platform::WindowEvent::WindowActiveChanged(true).into(),
+        ClientEvent::FocusOut => platform::WindowEvent::WindowActiveChanged(false).into(),
+        ClientEvent::FrameDone => Event::FrameDone,
+        ClientEvent::ClipboardChanged(_)
+        | ClientEvent::PermissionDenied(_)
+        | ClientEvent::OpenClientFailed(_)
+        | ClientEvent::AuthenticationFailed => return None,
+    };
+
+    Some(event)
 }
 
 fn convert_button(button: &coop_protocol::PointerEventButton) -> platform::PointerEventButton {
@@ -68,4 +128,46 @@ fn convert_button(button: &coop_protocol::PointerEventButton) -> platform::Point
     }
 }
 
-// FIXME tests for convert convertbutton and read
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_pointer() {
+        assert_eq!(
+            convert(&ClientEvent::PointerPressed {
+                position: (1., 2.),
+                button: PointerEventButton::Left
+            }),
+            Some(Event::WindowEvent(platform::WindowEvent::PointerPressed {
+                position: LogicalPosition::new(1., 2.),
+                button: platform::PointerEventButton::Left
+            }))
+        );
+        assert_eq!(
+            convert(&ClientEvent::PointerRelease {
+                position: (1., 2.),
+                button: PointerEventButton::None
+            }),
+            Some(Event::WindowEvent(platform::WindowEvent::PointerReleased {
+                position: LogicalPosition::new(1., 2.),
+                button: platform::PointerEventButton::Other
+            }))
+        );
+    }
+
+    #[test]
+    fn test_convert_pointer_canceled() {
+        assert_eq!(
+            convert(&ClientEvent::PointerCanceled),
+            Some(Event::WindowEvent(platform::WindowEvent::PointerExited))
+        );
+    }
+
+    #[test]
+    fn test_convert_handled_by_window() {
+        assert_eq!(convert(&ClientEvent::ClipboardChanged("text".into())), None);
+        assert_eq!(convert(&ClientEvent::FrameDone), Some(Event::FrameDone));
+        assert_eq!(convert(&ClientEvent::Close), Some(Event::Quit));
+    }
+}
@@ -8,3 +8,5 @@ mod platform;
 pub use color::*;
 pub use event_reader::*;
 pub use platform::*;
+
+pub use coop_protocol::{DecorationMode, ResizeEdge};
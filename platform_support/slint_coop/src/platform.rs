@@ -3,13 +3,30 @@
 
 use std::cell::*;
 use std::rc::*;
+use std::time::Duration;
 
 use slint::platform::software_renderer as renderer;
 use slint::platform::software_renderer::RepaintBufferType;
 use slint::PlatformError;
 
+use coop_client::result::{ClientError, ClientResult};
+use coop_protocol::{
+    Capability, DamageRegion, DecorationMode, PixelFormat, ResizeEdge, WindowKind,
+};
+
 use crate::Color;
 
+// frame interval of animations if the client does not wait for a frame callback of the server.
+const FALLBACK_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+thread_local! {
+    // interactive move or resize of the main window that is requested by the app, it is sent by the event loop.
+    static INTERACTION_REQUEST: Cell<Option<Option<ResizeEdge>>> = const { Cell::new(None) };
+
+    // windows that are opened as popups with their position, the windows are identified by their address.
+    static POPUP_REQUESTS: RefCell<Vec<(usize, f32, f32)>> = const { RefCell::new(vec![]) };
+}
+
 /// Initializes the platform.
 pub fn init() {
     slint::platform::set_platform(Box::new(ThemePlatform::default())).unwrap();
@@ -21,21 +38,258 @@ pub fn init_config(width: f32, height: f32, title: impl Into<String>) {
         .unwrap();
 }
 
+/// Initializes the platform with a configuration.
+///
+/// Returns an error if the app is not started by a `coop_server`, so the app can fall back to another backend.
+pub fn try_init_config(width: f32, height: f32, title: impl Into<String>) -> ClientResult<()> {
+    slint::platform::set_platform(Box::new(ThemePlatform::try_new(
+        width,
+        height,
+        title.into(),
+    )?))
+    .unwrap();
+
+    Ok(())
+}
+
+/// Initializes the platform with a configuration and the preferred decoration mode of the windows.
+///
+/// Use `DecorationMode::Client` for an app that draws its own header bar, check the mode that is negotiated with
+/// the server with `decoration_mode`.
+pub fn try_init_config_with_decoration_mode(
+    width: f32,
+    height: f32,
+    title: impl Into<String>,
+    decoration_mode: DecorationMode,
+) -> ClientResult<()> {
+    slint::platform::set_platform(Box::new(ThemePlatform::try_new_with_decoration_mode(
+        width,
+        height,
+        title.into(),
+        decoration_mode,
+    )?))
+    .unwrap();
+
+    Ok(())
+}
+
+/// Requests the server to move the main window with the pressed pointer, call it on a pointer press on a header
+/// bar that is drawn by the app.
+pub fn start_move() {
+    INTERACTION_REQUEST.with(|request| request.set(Some(None)));
+}
+
+/// Requests the server to resize the main window at the given edge with the pressed pointer.
+pub fn start_resize(edge: ResizeEdge) {
+    INTERACTION_REQUEST.with(|request| request.set(Some(Some(edge))));
+}
+
+/// Opens the given window as popup of the main window at the given position relative to the main window, e.g. a
+/// context menu. Call it before the window is shown, other additional windows are opened as top level windows.
+pub fn set_popup(window: &slint::Window, x: f32, y: f32) {
+    POPUP_REQUESTS.with(|requests| {
+        requests
+            .borrow_mut()
+            .push((window as *const slint::Window as usize, x, y))
+    });
+}
+
 /// Slint platform implementation based on `coop_client`.
 pub struct ThemePlatform {
     slint_window: RefCell<Rc<renderer::MinimalSoftwareWindow>>,
     coop_window: RefCell<coop_client::Window>,
+    main_window_created: Cell<bool>,
+    child_windows: RefCell<Vec<Rc<ChildWindow>>>,
+}
+
+// Additional window that is opened as window of the `coop_client::Window` while it is visible.
+//
+// It is its own window adapter to get the title and the preferred size of the Slint window.
+struct ChildWindow {
+    window: slint::Window,
+    renderer: renderer::SoftwareRenderer,
+    needs_redraw: Cell<bool>,
+    title: RefCell<String>,
+    preferred_size: Cell<(f32, f32)>,
+
+    // position relative to the main window if the window is opened as popup, see `set_popup`.
+    popup: Cell<Option<(f32, f32)>>,
+    key: RefCell<Option<String>>,
+    size: Cell<(f32, f32)>,
+    work_buffer: RefCell<Vec<Color>>,
+    frame_ready: Cell<bool>,
+}
+
+impl ChildWindow {
+    fn new() -> Rc<Self> {
+        Rc::new_cyclic(|adapter: &Weak<Self>| Self {
+            window: slint::Window::new(adapter.clone()),
+            renderer: renderer::SoftwareRenderer::new_with_repaint_buffer_type(
+                RepaintBufferType::ReusedBuffer,
+            ),
+            needs_redraw: Cell::new(false),
+            title: RefCell::new(String::default()),
+            preferred_size: Cell::new((0., 0.)),
+            popup: Cell::new(None),
+            key: RefCell::new(None),
+            size: Cell::new((0., 0.)),
+            work_buffer: RefCell::new(vec![]),
+            frame_ready: Cell::new(true),
+        })
+    }
+
+    fn resize(&self, width: f32, height: f32) {
+        self.size.set((width, height));
+        self.window
+            .dispatch_event(slint::platform::WindowEvent::Resized {
+                size: slint::LogicalSize::new(width, height),
+            });
+        *self.work_buffer.borrow_mut() = vec![Color(0); width as usize * height as usize];
+        self.needs_redraw.set(true);
+    }
+
+    // calls `render` with the renderer if the window has changed since the last call.
+    fn draw_if_needed(&self, render: impl FnOnce(&renderer::SoftwareRenderer)) {
+        if self.needs_redraw.replace(false) {
+            render(&self.renderer);
+        }
+    }
+}
+
+impl slint::platform::WindowAdapter for ChildWindow {
+    fn window(&self) -> &slint::Window {
+        &self.window
+    }
+
+    // the size is defined by the server, see `resize`.
+    fn size(&self) -> slint::PhysicalSize {
+        let (width, height) = self.size.get();
+        slint::PhysicalSize::new(width as u32, height as u32)
+    }
+
+    fn renderer(&self) -> &dyn slint::platform::Renderer {
+        &self.renderer
+    }
+
+    fn request_redraw(&self) {
+        self.needs_redraw.set(true);
+    }
+
+    fn update_window_properties(&self, properties: slint::platform::WindowProperties<'_>) {
+        *self.title.borrow_mut() = properties.title().into();
+
+        let preferred = properties.layout_constraints().preferred;
+        self.preferred_size.set((preferred.width, preferred.height));
+    }
 }
 
 impl ThemePlatform {
     /// Returns a new platform object.
     pub fn new(width: f32, height: f32, title: String) -> Self {
-        Self {
+        Self::try_new(width, height, title).expect("Cannot establish connection to backend.")
+    }
+
+    /// Returns a new platform object or an error if the connection to the server cannot be established.
+    pub fn try_new(width: f32, height: f32, title: String) -> ClientResult<Self> {
+        Self::try_new_with_decoration_mode(width, height, title, DecorationMode::Server)
+    }
+
+    /// Returns a new platform object with the preferred decoration mode of the windows or an error if the
+    /// connection to the server cannot be established.
+    pub fn try_new_with_decoration_mode(
+        width: f32,
+        height: f32,
+        title: String,
+        decoration_mode: DecorationMode,
+    ) -> ClientResult<Self> {
+        let coop_window = coop_client::Window::try_new_with_decoration_mode(
+            width,
+            height,
+            title,
+            true,
+            decoration_mode,
+        )?;
+
+        // the work buffer is sent as it is, the server has to support the memory layout of `Color`.
+        coop_window.set_pixel_format(PixelFormat::Rgba8888)?;
+
+        Ok(Self {
             slint_window: RefCell::new(renderer::MinimalSoftwareWindow::new(
-                RepaintBufferType::default(),
+                RepaintBufferType::ReusedBuffer,
             )),
-            coop_window: RefCell::new(coop_client::Window::new(width, height, title, true)),
+            coop_window: RefCell::new(coop_window),
+            main_window_created: Cell::new(false),
+            child_windows: RefCell::new(vec![]),
+        })
+    }
+
+    /// Returns the decoration mode that is negotiated with the server.
+    pub fn decoration_mode(&self) -> DecorationMode {
+        self.coop_window.borrow().decoration_mode()
+    }
+
+    // opens, draws or closes the coop window of the given child window depending on the visibility of the Slint window.
+    fn update_child_window(&self, child: &ChildWindow) -> ClientResult<()> {
+        let coop_window = self.coop_window.borrow();
+        let key = child.key.borrow().clone();
+
+        match (child.window.is_visible(), key) {
+            (true, None) => {
+                // a window without a layout gets the size of the main window.
+                let (width, height) = match child.preferred_size.get() {
+                    (width, height) if width > 0. && height > 0. => (width, height),
+                    _ => coop_window.size(),
+                };
+
+                let kind = match child.popup.get() {
+                    Some((x, y)) => WindowKind::Popup {
+                        parent: coop_window.key().into(),
+                        x,
+                        y,
+                    },
+                    None => WindowKind::TopLevel,
+                };
+
+                let title = child.title.borrow().clone();
+                let key = coop_window.open_window(title, width, height, kind)?;
+
+                *child.key.borrow_mut() = Some(key);
+                child.resize(width, height);
+            }
+            (true, Some(key)) if child.frame_ready.get() => {
+                let (width, height) = child.size.get();
+
+                let mut draw_result = Ok(false);
+                child.draw_if_needed(|renderer| {
+                    draw_result = render(
+                        renderer,
+                        &mut child.work_buffer.borrow_mut(),
+                        width,
+                        height,
+                        |buffer, damage| {
+                            coop_window.draw_window(&key, buffer, width, height, damage)
+                        },
+                    );
+                });
+
+                if draw_result? && coop_window.has_capability(Capability::FrameCallbacks) {
+                    child.frame_ready.set(false);
+                }
+            }
+            // the window waits for the frame callback of its last frame.
+            (true, Some(_)) => {}
+            (false, Some(key)) => {
+                *child.key.borrow_mut() = None;
+
+                // the window is already gone if it is closed by the server.
+                if coop_window.windows().contains(&key) {
+                    coop_window.close_window(&key)?;
+                }
+            }
+            (false, None) => {}
         }
+
+        Ok(())
     }
 }
 
@@ -51,11 +305,39 @@ impl slint::platform::Platform for ThemePlatform {
     fn create_window_adapter(
         &self,
     ) -> Result<Rc<dyn slint::platform::WindowAdapter>, PlatformError> {
-        Ok(self.slint_window.borrow().clone())
+        // the first window is the main window of the client, all others are opened as additional windows.
+        if !self.main_window_created.replace(true)
+            || !self
+                .coop_window
+                .borrow()
+                .has_capability(Capability::Windows)
+        {
+            return Ok(self.slint_window.borrow().clone());
+        }
+
+        let child = ChildWindow::new();
+        self.child_windows.borrow_mut().push(child.clone());
+
+        Ok(child)
+    }
+
+    fn set_clipboard_text(&self, text: &str, clipboard: slint::platform::Clipboard) {
+        if clipboard == slint::platform::Clipboard::DefaultClipboard {
+            // the clipboard is lost if the server is gone, there is nothing left to share it with.
+            self.coop_window.borrow().set_clipboard_text(text).ok();
+        }
+    }
+
+    fn clipboard_text(&self, clipboard: slint::platform::Clipboard) -> Option<String> {
+        if clipboard != slint::platform::Clipboard::DefaultClipboard {
+            return None;
+        }
+
+        self.coop_window.borrow().clipboard_text()
     }
 
     fn run_event_loop(&self) -> std::result::Result<(), slint::PlatformError> {
-        let (width, height) = self.coop_window.borrow().size();
+        let (mut width, mut height) = self.coop_window.borrow().size();
 
         self.slint_window
             .borrow()
@@ -64,28 +346,113 @@ impl slint::platform::Platform for ThemePlatform {
 
         let mut work_buffer = vec![Color(0); width as usize * height as usize];
 
+        let frame_callbacks = self
+            .coop_window
+            .borrow()
+            .has_capability(Capability::FrameCallbacks);
+
+        // `false` while the main window waits for the frame callback of its last frame.
+        let mut frame_ready = true;
+
         'events: loop {
             slint::platform::update_timers_and_animations();
-            self.slint_window.borrow().draw_if_needed(|renderer| {
-                renderer.render(
-                    &mut work_buffer,
-                    self.slint_window.borrow().size().width as usize,
-                );
 
-                let len = work_buffer.len() * std::mem::size_of::<Color>();
+            if frame_ready {
+                let mut draw_result = Ok(false);
+                self.slint_window.borrow().draw_if_needed(|renderer| {
+                    draw_result = render(
+                        renderer,
+                        &mut work_buffer,
+                        width,
+                        height,
+                        |buffer, damage| {
+                            self.coop_window
+                                .borrow()
+                                .draw_damaged(buffer, width, height, damage)
+                        },
+                    );
+                });
+
+                if draw_result.map_err(platform_error)? && frame_callbacks {
+                    frame_ready = false;
+                }
+            }
 
-                let buffer =
-                    unsafe { std::slice::from_raw_parts(work_buffer.as_ptr() as *mut u8, len) };
+            // the request is sent while the pointer that pressed on the header bar of the app is still pressed.
+            match INTERACTION_REQUEST.with(Cell::take) {
+                Some(None) => self.coop_window.borrow().start_move(),
+                Some(Some(edge)) => self.coop_window.borrow().start_resize(edge),
+                None => Ok(()),
+            }
+            .map_err(platform_error)?;
 
-                self.coop_window.borrow().draw(buffer, width, height);
-            });
+            // child windows can be created while events are dispatched, so the list is not borrowed in the loop.
+            let child_windows = self.child_windows.borrow().clone();
+
+            for (window, x, y) in POPUP_REQUESTS.with(|requests| requests.take()) {
+                if let Some(child) = child_windows
+                    .iter()
+                    .find(|c| &c.window as *const slint::Window as usize == window)
+                {
+                    child.popup.set(Some((x, y)));
+                }
+            }
+
+            for child in &child_windows {
+                self.update_child_window(child).map_err(platform_error)?;
+            }
+
+            // the loop is woken up by the frame callback of a pending frame, running animations without a pending
+            // frame are paced by the fallback frame interval.
+            let waits_for_frame =
+                !frame_ready || child_windows.iter().any(|c| !c.frame_ready.get());
+            let has_animations = self.slint_window.borrow().has_active_animations()
+                || child_windows
+                    .iter()
+                    .any(|c| c.window.has_active_animations());
+
+            let timeout = match slint::platform::duration_until_next_timer_update() {
+                Some(timer) if has_animations && !waits_for_frame => {
+                    Some(timer.min(FALLBACK_FRAME_INTERVAL))
+                }
+                None if has_animations && !waits_for_frame => Some(FALLBACK_FRAME_INTERVAL),
+                timer => timer,
+            };
+
+            let main_key = self.coop_window.borrow().key().to_string();
+
+            for (key, event) in
+                crate::wait_windows(&self.coop_window.borrow(), timeout).map_err(platform_error)?
+            {
+                if key != main_key {
+                    if let Some(child) = child_windows
+                        .iter()
+                        .find(|c| c.key.borrow().as_ref() == Some(&key))
+                    {
+                        dispatch_child_event(child, event);
+                    }
+
+                    continue;
+                }
 
-            for event in crate::read(&self.coop_window.borrow()) {
                 match event {
                     crate::Event::WindowEvent(e) => self.slint_window.borrow().dispatch_event(e),
-                    crate::Event::Resize(_) => todo!(),
+                    crate::Event::Resize(size) => {
+                        width = size.width;
+                        height = size.height;
+
+                        self.slint_window
+                            .borrow()
+                            .as_ref()
+                            .set_size(slint::PhysicalSize::new(width as u32, height as u32));
+
+                        work_buffer = vec![Color(0); width as usize * height as usize];
+                        self.slint_window.borrow().request_redraw();
+                    }
+                    crate::Event::FrameDone => frame_ready = true,
                     crate::Event::Quit => {
-                        self.coop_window.borrow().close();
+                        // the server could be already gone on quit.
+                        self.coop_window.borrow().close().ok();
                         break 'events;
                     }
                 }
@@ -95,3 +462,63 @@ impl slint::platform::Platform for ThemePlatform {
         Ok(())
     }
 }
+
+fn dispatch_child_event(child: &ChildWindow, event: crate::Event) {
+    match event {
+        crate::Event::WindowEvent(e) => child.window.dispatch_event(e),
+        crate::Event::Resize(size) => child.resize(size.width, size.height),
+        crate::Event::FrameDone => child.frame_ready.set(true),
+        crate::Event::Quit => {
+            // the server has closed the window, it is hidden by the close request.
+            *child.key.borrow_mut() = None;
+            child
+                .window
+                .dispatch_event(slint::platform::WindowEvent::CloseRequested);
+        }
+    }
+}
+
+// renders the window into the work buffer and sends the changed part of it with `draw`. Returns `true` if a frame
+// was sent.
+fn render(
+    renderer: &renderer::SoftwareRenderer,
+    work_buffer: &mut [Color],
+    width: f32,
+    height: f32,
+    draw: impl FnOnce(&[u8], &[DamageRegion]) -> ClientResult<()>,
+) -> ClientResult<bool> {
+    let region = renderer.render(work_buffer, width as usize);
+
+    let origin = region.bounding_box_origin();
+    let size = region.bounding_box_size();
+
+    if size.width == 0 || size.height == 0 {
+        return Ok(false);
+    }
+
+    let len = std::mem::size_of_val(work_buffer);
+
+    let buffer = unsafe { std::slice::from_raw_parts(work_buffer.as_ptr() as *mut u8, len) };
+
+    // only the changed part of the buffer is sent if not the whole window is repainted.
+    if origin.x == 0 && origin.y == 0 && size.width >= width as u32 && size.height >= height as u32
+    {
+        draw(buffer, &[])?;
+    } else {
+        draw(
+            buffer,
+            &[DamageRegion::new(
+                origin.x as u32,
+                origin.y as u32,
+                size.width,
+                size.height,
+            )],
+        )?;
+    }
+
+    Ok(true)
+}
+
+fn platform_error(error: ClientError) -> PlatformError {
+    PlatformError::Other(format!("coop client error: {:?}", error))
+}